@@ -0,0 +1,31 @@
+// The proxy's own hashing module, so the numbers match what CONTENT_HASH_ALGO selects
+#[allow(dead_code)]
+#[path = "../src/hash/mod.rs"]
+mod hash;
+
+use hash::HashAlgorithm;
+use std::{hint::black_box, time::Instant};
+
+fn main() {
+    // Usage: cargo run --release --example hash_bench -- [rounds]
+    let rounds: u32 = std::env::args().nth(1).map(|rounds| rounds.parse().expect("rounds must be a number")).unwrap_or(20);
+
+    // Bodies are hashed whole: roughly a thumbnail, a master image and a large original
+    for size in [64 << 10, 1 << 20, 16 << 20] {
+        let data: Vec<u8> = (0..size).map(|i| (i * 31 % 251) as u8).collect();
+        println!("{} KiB:", size >> 10);
+
+        for name in ["blake3", "sha256", "xxh3"] {
+            let algorithm: HashAlgorithm = name.parse().unwrap();
+            black_box(algorithm.digest(&data));
+
+            let started = Instant::now();
+            for _ in 0..rounds {
+                black_box(algorithm.digest(black_box(&data)));
+            }
+            let elapsed = started.elapsed() / rounds;
+            let throughput = size as f64 / elapsed.as_secs_f64() / (1 << 20) as f64;
+            println!("  {:<7} {:>10.1?} per hash, {:>8.0} MiB/s", name, elapsed, throughput);
+        }
+    }
+}
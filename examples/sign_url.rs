@@ -0,0 +1,17 @@
+// The proxy's own signing module, so minted URLs always match what it verifies
+#[allow(dead_code)]
+#[path = "../src/signing/mod.rs"]
+mod signing;
+
+fn main() {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    // Usage: URL_SIGNING_SECRET=... cargo run --example sign_url -- <path> [ttl_secs]
+    let mut args = std::env::args().skip(1);
+    let path = args.next().expect("usage: sign_url <path> [ttl_secs]");
+    let ttl: u64 = args.next().map(|ttl| ttl.parse().expect("ttl_secs must be a number")).unwrap_or(3600);
+    let secret = std::env::var("URL_SIGNING_SECRET").expect("URL_SIGNING_SECRET must be set");
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("clock before 1970").as_secs();
+    println!("{}", signing::signed_url(&secret, &path, now + ttl));
+}
@@ -0,0 +1,192 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{HeaderMap, StatusCode, header},
+    response::Response,
+    Json,
+};
+use bytes::Bytes;
+use futures::{Stream, StreamExt, TryStreamExt, stream};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use super::{AdminError, require_admin};
+use crate::{
+    proxy::ProxyState,
+    storage::{ObjectSummary, S3Storage},
+};
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub copied: u64,
+    pub skipped: u64,
+    pub failed: u64,
+    pub invalid_lines: u64,
+}
+
+enum ImportOutcome {
+    Copied,
+    Skipped,
+    Failed,
+    Invalid,
+}
+
+/// Stream every stored object key with its listing metadata as newline-delimited JSON.
+pub async fn export_manifest_handler(
+    headers: HeaderMap,
+    State(state): State<ProxyState>,
+) -> Result<Response<Body>, AdminError> {
+    require_admin(&headers, &state)?;
+
+    let storage = state.storage.clone();
+    // Each listing page becomes one chunk of the response; state is (continuation token, finished)
+    let pages = stream::try_unfold((None::<String>, false), move |(continuation, finished)| {
+        let storage = storage.clone();
+        async move {
+            if finished {
+                return Ok(None);
+            }
+
+            let (objects, next) = storage.list_objects_page(continuation.as_deref()).await?;
+            let mut chunk = Vec::new();
+            for object in &objects {
+                serde_json::to_writer(&mut chunk, object)?;
+                chunk.push(b'\n');
+            }
+
+            let finished = next.is_none();
+            Ok::<_, anyhow::Error>(Some((Bytes::from(chunk), (next, finished))))
+        }
+    })
+    .inspect_err(|e| warn!("Manifest export aborted: {}", e));
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::from_stream(pages))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Copy the objects listed in an uploaded manifest from the configured source bucket.
+/// Objects already present locally are skipped, so an interrupted import can simply be re-run.
+pub async fn import_manifest_handler(
+    headers: HeaderMap,
+    State(state): State<ProxyState>,
+    body: Body,
+) -> Result<Json<ImportReport>, AdminError> {
+    require_admin(&headers, &state)?;
+
+    let Some(source_config) = state.config.admin.manifest_source.as_ref() else {
+        return Err((StatusCode::NOT_FOUND, "Manifest import is not configured".to_string()));
+    };
+    let source = state.storage.for_source(source_config)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    info!("Importing manifest from bucket '{}'", source_config.bucket);
+
+    let report = body_lines(body)
+        .filter(|line| std::future::ready(!matches!(line, Ok(line) if line.is_empty())))
+        .map(|line| {
+            let source = source.clone();
+            let storage = state.storage.clone();
+            async move {
+                match line {
+                    Ok(line) => import_object(&source, &storage, &line).await,
+                    Err(e) => {
+                        warn!("Failed to read manifest body: {}", e);
+                        ImportOutcome::Failed
+                    }
+                }
+            }
+        })
+        .buffer_unordered(state.config.admin.manifest_import_concurrency)
+        .fold(ImportReport::default(), |mut report, outcome| async move {
+            match outcome {
+                ImportOutcome::Copied => report.copied += 1,
+                ImportOutcome::Skipped => report.skipped += 1,
+                ImportOutcome::Failed => report.failed += 1,
+                ImportOutcome::Invalid => report.invalid_lines += 1,
+            }
+            report
+        })
+        .await;
+
+    info!(
+        "Manifest import finished: {} copied, {} skipped, {} failed, {} invalid lines",
+        report.copied, report.skipped, report.failed, report.invalid_lines
+    );
+
+    Ok(Json(report))
+}
+
+// Objects are copied exactly as stored, crypto header and all
+async fn import_object(source: &S3Storage, storage: &S3Storage, line: &str) -> ImportOutcome {
+    let Ok(object) = serde_json::from_str::<ObjectSummary>(line) else {
+        return ImportOutcome::Invalid;
+    };
+
+    match storage.head_object(&object.key).await {
+        Ok(true) => return ImportOutcome::Skipped,
+        Ok(false) => {},
+        Err(e) => {
+            warn!("Failed to check {} before import: {}", object.key, e);
+            return ImportOutcome::Failed;
+        }
+    }
+
+    let data = match source.get_raw_object(&object.key).await {
+        Ok(Some(data)) => data,
+        Ok(None) => {
+            warn!("Manifest object {} is missing from the source bucket", object.key);
+            return ImportOutcome::Failed;
+        },
+        Err(e) => {
+            warn!("Failed to read {} from the source bucket: {}", object.key, e);
+            return ImportOutcome::Failed;
+        }
+    };
+
+    match storage.put_raw_object(&object.key, data, None, None).await {
+        Ok(()) => ImportOutcome::Copied,
+        Err(e) => {
+            warn!("Failed to import {}: {}", object.key, e);
+            ImportOutcome::Failed
+        }
+    }
+}
+
+// Split a streamed request body into trimmed lines without buffering the whole manifest.
+// State is (body stream, partial line, end of body reached).
+fn body_lines(body: Body) -> impl Stream<Item = Result<String, axum::Error>> {
+    stream::unfold(
+        (body.into_data_stream(), Vec::new(), false),
+        |(mut data, mut buffer, mut eof)| async move {
+            loop {
+                if let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=end).collect();
+                    let line = String::from_utf8_lossy(&line).trim().to_string();
+                    return Some((Ok(line), (data, buffer, eof)));
+                }
+
+                if eof {
+                    if buffer.is_empty() {
+                        return None;
+                    }
+                    let line = String::from_utf8_lossy(&buffer).trim().to_string();
+                    buffer.clear();
+                    return Some((Ok(line), (data, buffer, eof)));
+                }
+
+                match data.next().await {
+                    Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                    Some(Err(e)) => {
+                        // Give up on the rest of the body, including any partial line
+                        buffer.clear();
+                        return Some((Err(e), (data, buffer, true)));
+                    },
+                    None => eof = true,
+                }
+            }
+        },
+    )
+}
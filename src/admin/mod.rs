@@ -0,0 +1,444 @@
+mod manifest;
+
+pub use manifest::{export_manifest_handler, import_manifest_handler};
+
+use axum::{
+    extract::{Path, Query, RawQuery, State},
+    http::{HeaderMap, StatusCode, Uri, header},
+    Json,
+};
+use bytes::Bytes;
+use futures::{StreamExt, stream};
+use image::ImageReader;
+use serde::{Deserialize, Serialize};
+use std::{io::Cursor, time::Instant};
+use subtle::ConstantTimeEq;
+use tracing::{info, warn};
+
+use crate::{
+    health::unix_now,
+    proxy::{self, ProxyState, Warmed, validate_image},
+    signing,
+    stats::{HotPathsReport, KeySpaceStats},
+    storage::CorruptObject,
+    transform,
+};
+
+const BENCH_KEY: &str = "__bench/payload";
+
+type AdminError = (StatusCode, String);
+
+/// Reject the request unless it carries the configured admin token as a bearer token.
+/// The admin API is hidden entirely (404) when no token is configured.
+pub fn require_admin(headers: &HeaderMap, state: &ProxyState) -> Result<(), AdminError> {
+    let Some(expected) = state.config.admin.token.as_deref() else {
+        return Err((StatusCode::NOT_FOUND, "Not found".to_string()));
+    };
+
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if bool::from(provided.as_bytes().ct_eq(expected.as_bytes())) {
+        Ok(())
+    } else {
+        warn!("Rejected admin request with invalid token");
+        Err((StatusCode::UNAUTHORIZED, "Invalid admin token".to_string()))
+    }
+}
+
+/// Latest background sample of stored objects and negative-cache entries.
+pub async fn stats_handler(
+    headers: HeaderMap,
+    State(state): State<ProxyState>,
+) -> Result<Json<KeySpaceStats>, AdminError> {
+    require_admin(&headers, &state)?;
+    Ok(Json(state.stats.snapshot().await))
+}
+
+/// Most requested paths and their approximate counts.
+pub async fn hot_paths_handler(
+    headers: HeaderMap,
+    State(state): State<ProxyState>,
+) -> Result<Json<HotPathsReport>, AdminError> {
+    require_admin(&headers, &state)?;
+
+    if !state.hot_paths.enabled() {
+        return Err((StatusCode::NOT_FOUND, "Hot path tracking is not enabled".to_string()));
+    }
+    Ok(Json(state.hot_paths.snapshot()))
+}
+
+/// Start the hot path counts over.
+pub async fn reset_hot_paths_handler(
+    headers: HeaderMap,
+    State(state): State<ProxyState>,
+) -> Result<StatusCode, AdminError> {
+    require_admin(&headers, &state)?;
+
+    state.hot_paths.reset();
+    info!("Hot path counts reset");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignQuery {
+    ttl: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SignedUrl {
+    pub url: String,
+    pub expires: u64,
+}
+
+/// Mint a signed URL for a path, valid for `?ttl=` seconds (default: one hour). The path is
+/// taken as sent, so percent-encoding survives into the minted URL.
+pub async fn sign_handler(
+    uri: Uri,
+    Query(query): Query<SignQuery>,
+    headers: HeaderMap,
+    State(state): State<ProxyState>,
+) -> Result<Json<SignedUrl>, AdminError> {
+    require_admin(&headers, &state)?;
+
+    let Some(secret) = state.config.auth.signing_secret.as_deref() else {
+        return Err((StatusCode::NOT_FOUND, "URL signing is not configured".to_string()));
+    };
+
+    let path = uri.path().strip_prefix("/admin/sign").unwrap_or(uri.path());
+    let expires = unix_now() + query.ttl.unwrap_or(3600);
+    Ok(Json(SignedUrl { url: signing::signed_url(secret, path, expires), expires }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArtworkPurgeReport {
+    pub artwork_id: String,
+    pub deleted: Vec<String>,
+    pub burst_cleared: Vec<String>, // Deleted keys that also had a burst cache entry
+    pub failed: Vec<String>,
+}
+
+/// Delete every stored object recorded for an artwork, along with its burst and negative cache
+/// entries.
+/// Failed keys stay in the group so the purge can be re-run.
+pub async fn purge_artwork_handler(
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    State(state): State<ProxyState>,
+) -> Result<Json<ArtworkPurgeReport>, AdminError> {
+    require_admin(&headers, &state)?;
+
+    if !state.cache.artwork_grouping_enabled() {
+        return Err((StatusCode::NOT_FOUND, "Artwork grouping is not configured".to_string()));
+    }
+
+    let keys = state.cache.artwork_objects(&id).await
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e.to_string()))?;
+
+    let mut report = ArtworkPurgeReport { artwork_id: id.clone(), deleted: Vec::new(), burst_cleared: Vec::new(), failed: Vec::new() };
+    for key in keys {
+        match state.storage.delete_object(&key).await {
+            Ok(()) => {
+                state.memory_cache.remove(&key);
+                match state.cache.remove_burst(&key).await {
+                    Ok(true) => report.burst_cleared.push(key.clone()),
+                    Ok(false) => {},
+                    Err(e) => warn!("Failed to clear burst cache for {}: {}", key, e),
+                }
+                if let Err(e) = state.cache.remove_cache(&key).await {
+                    warn!("Failed to clear negative cache for {}: {}", key, e);
+                }
+                if let Err(e) = state.cache.forget_artwork_object(&id, &key).await {
+                    warn!("{}", e);
+                }
+                report.deleted.push(key);
+            },
+            Err(e) => {
+                warn!("Failed to purge {} of artwork {}: {}", key, id, e);
+                report.failed.push(key);
+            }
+        }
+    }
+
+    info!("Purged artwork {}: {} deleted, {} failed", id, report.deleted.len(), report.failed.len());
+    Ok(Json(report))
+}
+
+#[derive(Debug, Serialize)]
+pub struct PurgeReport {
+    pub key: String,
+    pub deleted: Vec<String>, // Stored copies removed from S3
+    pub burst_cleared: bool,
+    pub negative_cleared: bool,
+}
+
+/// Evict one object everywhere it is cached: the stored copy (in the main bucket or the cold
+/// tier) with its variants and thumbnail, the burst and negative cache entries, and this
+/// instance's memory copy. Answers 404 when nothing was cached.
+pub async fn purge_handler(
+    Path(path): Path<String>,
+    RawQuery(raw_query): RawQuery,
+    headers: HeaderMap,
+    State(state): State<ProxyState>,
+) -> Result<Json<PurgeReport>, AdminError> {
+    require_admin(&headers, &state)?;
+
+    // The URL as clients request it, keyed the way the proxy stored it
+    let key = proxy::storage_key(&state, &path, raw_query.as_deref());
+    let mut stored = vec![(&state.storage, key.clone())];
+    if let Some(cold_tier) = &state.cold_tier {
+        stored.push((cold_tier.storage(), cold_tier.key(&key)));
+    }
+
+    // Derived copies are found by listing, since variants at explicit qualities have open-ended keys
+    let derived_prefix = transform::derived_key(&key, "");
+    let mut continuation = None;
+    loop {
+        let (objects, next) = state.storage.list_objects(&derived_prefix, continuation.as_deref()).await
+            .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+        stored.extend(objects.into_iter().map(|object| (&state.storage, object.key)));
+        match next {
+            Some(token) => continuation = Some(token),
+            None => break,
+        }
+    }
+
+    let mut deleted = Vec::new();
+    for (storage, stored_key) in stored {
+        let exists = storage.head_object(&stored_key).await
+            .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+        if exists {
+            storage.delete_object(&stored_key).await
+                .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+            deleted.push(stored_key);
+        }
+    }
+
+    state.memory_cache.remove(&key);
+    state.recent_writes.remove(&key);
+    let unavailable = |e: anyhow::Error| (StatusCode::SERVICE_UNAVAILABLE, e.to_string());
+    if state.cold_tier.is_some() {
+        state.cache.clear_cold(&key).await.map_err(unavailable)?;
+    }
+    if let Some(id) = state.cache.artwork_id(&key)
+        && let Err(e) = state.cache.forget_artwork_object(&id, &key).await
+    {
+        warn!("{}", e);
+    }
+    let burst_cleared = state.cache.remove_burst(&key).await.map_err(unavailable)?;
+    let negative_cleared = state.cache.should_reject(&key).await.map_err(unavailable)?.is_some();
+    if negative_cleared {
+        state.cache.remove_cache(&key).await.map_err(unavailable)?;
+    }
+
+    if deleted.is_empty() && !burst_cleared && !negative_cleared {
+        return Err((StatusCode::NOT_FOUND, format!("{} is not cached", key)));
+    }
+
+    info!("Purged {}: {} stored copies deleted", key, deleted.len());
+    Ok(Json(PurgeReport { key, deleted, burst_cleared, negative_cleared }))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WarmStatus {
+    Cached,
+    AlreadyPresent,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WarmResult {
+    pub path: String,
+    pub status: WarmStatus,
+    pub error: Option<String>,
+}
+
+/// Fetch and store each listed path that is not stored yet, a few at a time.
+/// Results are reported per path, in the order the paths were given.
+pub async fn warm_handler(
+    headers: HeaderMap,
+    State(state): State<ProxyState>,
+    Json(paths): Json<Vec<String>>,
+) -> Result<Json<Vec<WarmResult>>, AdminError> {
+    require_admin(&headers, &state)?;
+
+    info!("Warming {} paths", paths.len());
+    let results: Vec<WarmResult> = stream::iter(paths)
+        .map(|path| {
+            let state = state.clone();
+            async move {
+                match proxy::ensure_cached(&state, &path).await {
+                    Ok(Warmed::Cached) => WarmResult { path, status: WarmStatus::Cached, error: None },
+                    Ok(Warmed::AlreadyPresent) => WarmResult { path, status: WarmStatus::AlreadyPresent, error: None },
+                    Err(e) => {
+                        warn!("Failed to warm {}: {}", path, e);
+                        WarmResult { path, status: WarmStatus::Error, error: Some(e.to_string()) }
+                    }
+                }
+            }
+        })
+        .buffered(state.config.admin.warm_concurrency)
+        .collect()
+        .await;
+
+    let cached = results.iter().filter(|result| matches!(result.status, WarmStatus::Cached)).count();
+    info!("Warm finished: {} of {} paths fetched", cached, results.len());
+    Ok(Json(results))
+}
+
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+    pub key: String,
+    pub size: usize,
+    pub format: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub valid: bool,
+    pub error: Option<String>,
+}
+
+/// Read a stored object through the full retrieval pipeline and check that it decodes
+/// as an image, without serving the bytes.
+pub async fn verify_handler(
+    Path(path): Path<String>,
+    RawQuery(raw_query): RawQuery,
+    headers: HeaderMap,
+    State(state): State<ProxyState>,
+) -> Result<Json<VerifyReport>, AdminError> {
+    require_admin(&headers, &state)?;
+
+    let key = proxy::storage_key(&state, &path, raw_query.as_deref());
+    let data = match state.storage.get_stored_object(&key).await {
+        Ok(Some(object)) => object.data,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, format!("{} is not stored", key))),
+        // Decryption or decompression failures are what this endpoint exists to report
+        Err(e) if e.downcast_ref::<CorruptObject>().is_some() => {
+            warn!("Verification of {} failed: {}", key, e);
+            return Ok(Json(VerifyReport {
+                key,
+                size: 0,
+                format: None,
+                width: None,
+                height: None,
+                valid: false,
+                error: Some(e.to_string()),
+            }));
+        },
+        Err(e) => return Err((StatusCode::BAD_GATEWAY, e.to_string())),
+    };
+
+    let format = image::guess_format(&data).ok();
+    let dimensions = format.and_then(|format| {
+        ImageReader::with_format(Cursor::new(data.as_ref()), format).into_dimensions().ok()
+    });
+    let validation = validate_image(&data, &key);
+
+    info!("Verified {}: {} bytes, format {:?}, valid {}", key, data.len(), format, validation.is_ok());
+
+    Ok(Json(VerifyReport {
+        key,
+        size: data.len(),
+        format: format.map(|format| format!("{:?}", format).to_lowercase()),
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        valid: validation.is_ok(),
+        error: validation.err().map(|e| e.to_string()),
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub size: usize,
+    pub stored_size: usize,
+    pub process_for_storage_ms: f64,
+    pub s3_put_ms: f64,
+    pub s3_get_ms: f64,
+    pub process_for_retrieval_ms: f64,
+    pub total_ms: f64,
+    pub verified: bool,
+}
+
+/// Run a deterministic payload through the full store-then-retrieve data path and report timings.
+pub async fn bench_handler(
+    Path(size): Path<usize>,
+    headers: HeaderMap,
+    State(state): State<ProxyState>,
+) -> Result<Json<BenchReport>, AdminError> {
+    require_admin(&headers, &state)?;
+
+    if !state.config.admin.bench_enabled {
+        return Err((StatusCode::NOT_FOUND, "Benchmark endpoint is disabled".to_string()));
+    }
+
+    if size == 0 || size > state.config.admin.bench_max_bytes {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Size must be between 1 and {} bytes", state.config.admin.bench_max_bytes),
+        ));
+    }
+
+    let internal_error = |e: anyhow::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let payload = bench_payload(size);
+    let processor = state.storage.crypto_processor();
+    let started = Instant::now();
+
+    let step = Instant::now();
+    let stored = processor.process_for_storage(payload.clone(), Some("application/octet-stream"))
+        .await
+        .map_err(internal_error)?;
+    let process_for_storage_ms = elapsed_ms(step);
+    let stored_size = stored.len();
+
+    let step = Instant::now();
+    state.storage.put_raw_object(BENCH_KEY, stored, Some("application/octet-stream"), None)
+        .await
+        .map_err(internal_error)?;
+    let s3_put_ms = elapsed_ms(step);
+
+    let step = Instant::now();
+    let fetched = state.storage.get_raw_object(BENCH_KEY)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| (StatusCode::INTERNAL_SERVER_ERROR, "Benchmark object vanished after upload".to_string()))?;
+    let s3_get_ms = elapsed_ms(step);
+
+    let step = Instant::now();
+    let restored = processor.process_for_retrieval(fetched).await.map_err(internal_error)?;
+    let process_for_retrieval_ms = elapsed_ms(step);
+
+    let report = BenchReport {
+        size,
+        stored_size,
+        process_for_storage_ms,
+        s3_put_ms,
+        s3_get_ms,
+        process_for_retrieval_ms,
+        total_ms: elapsed_ms(started),
+        verified: restored == payload,
+    };
+    info!("Benchmark of {} bytes finished in {:.2}ms", size, report.total_ms);
+
+    Ok(Json(report))
+}
+
+// Deterministic xorshift output so repeated runs compress identically
+fn bench_payload(size: usize) -> Bytes {
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        data.extend_from_slice(&state.to_le_bytes());
+    }
+    data.truncate(size);
+    Bytes::from(data)
+}
+
+fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
@@ -0,0 +1,322 @@
+//! In-process stand-in for Redis, speaking just enough RESP2 for the commands `KVStore`
+//! sends. Keys expire like in Redis; the Lua scripts are recognised by hash and run natively.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+
+use super::{REGISTER_VARIANT_SCRIPT, RELEASE_LOCK_SCRIPT};
+
+enum Value {
+    Str(Vec<u8>),
+    Set(BTreeSet<Vec<u8>>),
+}
+
+struct Entry {
+    value: Value,
+    expires: Option<Instant>,
+}
+
+enum Reply {
+    Ok,
+    Error(String),
+    Int(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+type Db = Arc<Mutex<HashMap<Vec<u8>, Entry>>>;
+
+#[derive(Clone)]
+pub struct FakeRedis {
+    url: String,
+    db: Db,
+    connections: Arc<AtomicUsize>, // Connections accepted so far
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl FakeRedis {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let db = Db::default();
+        let (shutdown, stopped) = watch::channel(false);
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        let (server_db, accepted_count) = (db.clone(), connections.clone());
+        tokio::spawn(async move {
+            let mut stopped_accepting = stopped.clone();
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => {
+                        let Ok((stream, _)) = accepted else { return };
+                        accepted_count.fetch_add(1, Ordering::SeqCst);
+                        tokio::spawn(serve(stream, server_db.clone(), stopped.clone()));
+                    },
+                    _ = stopped_accepting.changed() => return,
+                }
+            }
+        });
+
+        Self { url, db, connections, shutdown: Arc::new(shutdown) }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// Take the server down for every handle, as in a Redis outage: open connections are
+    /// closed and new ones refused.
+    pub fn stop(&self) {
+        let _ = self.shutdown.send(true);
+    }
+
+    pub fn keys(&self, prefix: &str) -> Vec<String> {
+        let mut keys: Vec<String> = self.db.lock().unwrap()
+            .keys()
+            .map(|key| String::from_utf8_lossy(key).into_owned())
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        keys.sort();
+        keys
+    }
+}
+
+impl Drop for FakeRedis {
+    fn drop(&mut self) {
+        // The last handle takes the server down
+        if Arc::strong_count(&self.shutdown) == 1 {
+            let _ = self.shutdown.send(true);
+        }
+    }
+}
+
+fn live<'a>(db: &'a mut HashMap<Vec<u8>, Entry>, key: &[u8]) -> Option<&'a mut Entry> {
+    if db.get(key).is_some_and(|entry| entry.expires.is_some_and(|at| at <= Instant::now())) {
+        db.remove(key);
+    }
+    db.get_mut(key)
+}
+
+async fn serve(stream: TcpStream, db: Db, mut stopped: watch::Receiver<bool>) {
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+    loop {
+        let command = tokio::select! {
+            command = read_command(&mut reader) => command,
+            _ = stopped.changed() => return,
+        };
+        let Some(command) = command else { return };
+
+        let reply = {
+            let mut db = db.lock().unwrap();
+            execute(&mut db, &command)
+        };
+        let mut out = Vec::new();
+        encode(&reply, &mut out);
+        if write.write_all(&out).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn read_line(reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>) -> Option<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await.ok()? == 0 {
+        return None;
+    }
+    Some(line.trim_end().to_string())
+}
+
+// Clients only send arrays of bulk strings
+async fn read_command(reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>) -> Option<Vec<Vec<u8>>> {
+    let count: usize = read_line(reader).await?.strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len: usize = read_line(reader).await?.strip_prefix('$')?.parse().ok()?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await.ok()?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Some(args)
+}
+
+fn encode(reply: &Reply, out: &mut Vec<u8>) {
+    match reply {
+        Reply::Ok => out.extend_from_slice(b"+OK\r\n"),
+        Reply::Error(message) => out.extend_from_slice(format!("-{}\r\n", message).as_bytes()),
+        Reply::Int(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+        Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+        Reply::Bulk(Some(value)) => {
+            out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+            out.extend_from_slice(value);
+            out.extend_from_slice(b"\r\n");
+        },
+        Reply::Array(items) => {
+            out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+            for item in items {
+                encode(item, out);
+            }
+        },
+    }
+}
+
+fn text(arg: &[u8]) -> String {
+    String::from_utf8_lossy(arg).to_uppercase()
+}
+
+fn number(arg: &[u8]) -> i64 {
+    String::from_utf8_lossy(arg).parse().unwrap_or(0)
+}
+
+fn bulk(value: &[u8]) -> Reply {
+    Reply::Bulk(Some(value.to_vec()))
+}
+
+fn execute(db: &mut HashMap<Vec<u8>, Entry>, command: &[Vec<u8>]) -> Reply {
+    let Some((name, args)) = command.split_first() else {
+        return Reply::Error("ERR empty command".to_string());
+    };
+
+    match text(name).as_str() {
+        "PING" => Reply::Bulk(Some(b"PONG".to_vec())),
+        "CLIENT" | "SELECT" => Reply::Ok,
+        "GET" => match live(db, &args[0]) {
+            Some(Entry { value: Value::Str(value), .. }) => bulk(value),
+            Some(_) => Reply::Error("WRONGTYPE".to_string()),
+            None => Reply::Bulk(None),
+        },
+        "MGET" => Reply::Array(
+            args.iter()
+                .map(|key| match live(db, key) {
+                    Some(Entry { value: Value::Str(value), .. }) => bulk(value),
+                    _ => Reply::Bulk(None),
+                })
+                .collect(),
+        ),
+        "SET" => {
+            let mut expires = None;
+            let mut only_new = false;
+            let mut options = args[2..].iter();
+            while let Some(option) = options.next() {
+                match text(option).as_str() {
+                    "NX" => only_new = true,
+                    "EX" => expires = options.next().map(|secs| Duration::from_secs(number(secs) as u64)),
+                    "PX" => expires = options.next().map(|ms| Duration::from_millis(number(ms) as u64)),
+                    _ => return Reply::Error("ERR syntax error".to_string()),
+                }
+            }
+            if only_new && live(db, &args[0]).is_some() {
+                return Reply::Bulk(None);
+            }
+            let entry = Entry { value: Value::Str(args[1].clone()), expires: expires.map(|ttl| Instant::now() + ttl) };
+            db.insert(args[0].clone(), entry);
+            Reply::Ok
+        },
+        "SETEX" => {
+            let ttl = Duration::from_secs(number(&args[1]) as u64);
+            db.insert(args[0].clone(), Entry { value: Value::Str(args[2].clone()), expires: Some(Instant::now() + ttl) });
+            Reply::Ok
+        },
+        "DEL" => Reply::Int(args.iter().filter(|key| live(db, key).is_some() && db.remove(*key).is_some()).count() as i64),
+        "EXISTS" => Reply::Int(args.iter().filter(|key| live(db, key).is_some()).count() as i64),
+        "INCR" | "INCRBY" => {
+            let delta = args.get(1).map_or(1, |delta| number(delta));
+            let entry = match live(db, &args[0]) {
+                Some(entry) => entry,
+                None => db.entry(args[0].clone()).or_insert(Entry { value: Value::Str(b"0".to_vec()), expires: None }),
+            };
+            let Value::Str(value) = &mut entry.value else {
+                return Reply::Error("WRONGTYPE".to_string());
+            };
+            let next = number(value) + delta;
+            *value = next.to_string().into_bytes();
+            Reply::Int(next)
+        },
+        "EXPIRE" => match live(db, &args[0]) {
+            Some(entry) => {
+                entry.expires = Some(Instant::now() + Duration::from_secs(number(&args[1]) as u64));
+                Reply::Int(1)
+            },
+            None => Reply::Int(0),
+        },
+        "TTL" => match live(db, &args[0]) {
+            Some(Entry { expires: Some(at), .. }) => Reply::Int(at.saturating_duration_since(Instant::now()).as_secs_f64().round() as i64),
+            Some(_) => Reply::Int(-1),
+            None => Reply::Int(-2),
+        },
+        "SADD" | "SREM" => {
+            let adding = text(name) == "SADD";
+            if adding && live(db, &args[0]).is_none() {
+                db.insert(args[0].clone(), Entry { value: Value::Set(BTreeSet::new()), expires: None });
+            }
+            let Some(Entry { value: Value::Set(members), .. }) = live(db, &args[0]) else {
+                return Reply::Int(0);
+            };
+            let changed = args[1..].iter()
+                .filter(|member| if adding { members.insert(member.to_vec()) } else { members.remove(*member) })
+                .count();
+            Reply::Int(changed as i64)
+        },
+        "SMEMBERS" => match live(db, &args[0]) {
+            Some(Entry { value: Value::Set(members), .. }) => Reply::Array(members.iter().map(|member| bulk(member)).collect()),
+            _ => Reply::Array(Vec::new()),
+        },
+        "SCAN" => {
+            let pattern = args.iter()
+                .position(|arg| text(arg) == "MATCH")
+                .map(|i| String::from_utf8_lossy(&args[i + 1]).into_owned())
+                .unwrap_or_else(|| "*".to_string());
+            let prefix = pattern.trim_end_matches('*').as_bytes().to_vec();
+            let keys: Vec<Vec<u8>> = db.keys().filter(|key| key.starts_with(&prefix)).cloned().collect();
+            let keys = keys.into_iter().filter(|key| live(db, key).is_some()).map(|key| bulk(&key)).collect();
+            Reply::Array(vec![bulk(b"0"), Reply::Array(keys)])
+        },
+        "DBSIZE" => Reply::Int(db.len() as i64),
+        "SCRIPT" => Reply::Bulk(args.get(1).map(|script| redis::Script::new(&String::from_utf8_lossy(script)).get_hash().as_bytes().to_vec())),
+        "EVALSHA" => run_script(db, &String::from_utf8_lossy(&args[0]), &args[1..]),
+        other => Reply::Error(format!("ERR unknown command '{}'", other)),
+    }
+}
+
+// Native versions of the scripts in `super`, looked up by their SHA1 like EVALSHA does
+fn run_script(db: &mut HashMap<Vec<u8>, Entry>, hash: &str, args: &[Vec<u8>]) -> Reply {
+    let key_count = number(&args[0]) as usize;
+    let (keys, argv) = args[1..].split_at(key_count);
+
+    if hash == redis::Script::new(RELEASE_LOCK_SCRIPT).get_hash() {
+        let owned = matches!(live(db, &keys[0]), Some(Entry { value: Value::Str(token), .. }) if *token == argv[0]);
+        return Reply::Int(i64::from(owned && db.remove(&keys[0]).is_some()));
+    }
+
+    if hash == redis::Script::new(REGISTER_VARIANT_SCRIPT).get_hash() {
+        if live(db, &keys[0]).is_none() {
+            db.insert(keys[0].clone(), Entry { value: Value::Set(BTreeSet::new()), expires: None });
+        }
+        let Some(Entry { value: Value::Set(members), .. }) = live(db, &keys[0]) else {
+            return Reply::Error("WRONGTYPE".to_string());
+        };
+        if members.contains(&argv[0]) {
+            return Reply::Int(1);
+        }
+        if members.len() as i64 >= number(&argv[1]) {
+            return Reply::Int(0);
+        }
+        members.insert(argv[0].clone());
+        return Reply::Int(1);
+    }
+
+    Reply::Error("NOSCRIPT No matching script".to_string())
+}
@@ -1,65 +1,461 @@
-use serde::Deserialize;
-use std::env;
-use anyhow::Result;
+use std::{collections::HashMap, env, str::FromStr};
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Deserializer, de::Error as _};
 
-#[derive(Debug, Clone, Deserialize)]
+use crate::hash::HashAlgorithm;
+
+/// Effective settings. A config file holds the same structure as TOML, one table per section.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub server: ServerConfig,
     pub upstream: UpstreamConfig,
     pub storage: StorageConfig,
     pub cache: CacheConfig,
+    pub health: HealthConfig,
+    pub admin: AdminConfig,
+    pub auth: AuthConfig,
+    pub stats: StatsConfig,
+    pub transform: TransformConfig,
 }
 
 #[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
     pub cert_path: Option<String>,
     pub key_path: Option<String>,
+    pub landing_response: Option<String>, // Body served for "/" and directory-like paths
+    pub request_deadline_secs: u64, // Overall time budget per request (0 = disabled)
+    pub pixel_fallback_enabled: bool, // Allow `?fallback=pixel` to turn 404s into a transparent pixel
+    pub server_timing_enabled: bool, // Emit a per-stage Server-Timing header on image responses
+    pub timing_allow_origin: Option<String>, // Timing-Allow-Origin value, e.g. "*"
+    pub stream_threshold_bytes: u64, // Originals this large are streamed to the client (0 = always buffered)
+    pub conditional_requests: bool, // Answer If-None-Match/If-Modified-Since with 304 when the served representation matches
+    pub range_requests: bool, // Answer single byte ranges of images with 206
+    pub keep_alive: bool,                  // HTTP/1 keep-alive; off closes after every response
+    pub header_read_timeout_secs: u64,     // Also bounds idle keep-alive connections (0 = no limit)
+    pub http2_max_concurrent_streams: u32,
+    pub http2_keep_alive_interval_secs: u64, // HTTP/2 PING interval (0 = disabled)
+    pub allowed_hosts: Vec<String>, // Accepted Host values, lowercase (empty = any host)
+    pub metrics_enabled: bool, // Serve Prometheus metrics at /metrics
+    pub rate_limit_per_minute: u64, // Requests per client IP per sliding minute (0 = unlimited)
+    pub trusted_proxies: Vec<String>, // Peer IPs whose X-Forwarded-For is believed
+}
+
+impl ServerConfig {
+    fn validate(&self) -> Result<()> {
+        if self.header_read_timeout_secs > 3600 {
+            return Err(anyhow!("SERVER_HEADER_READ_TIMEOUT_SECS must be at most 3600"));
+        }
+        if self.http2_max_concurrent_streams == 0 {
+            return Err(anyhow!("SERVER_HTTP2_MAX_CONCURRENT_STREAMS must be at least 1"));
+        }
+        if self.http2_keep_alive_interval_secs > 3600 {
+            return Err(anyhow!("SERVER_HTTP2_KEEP_ALIVE_INTERVAL_SECS must be at most 3600"));
+        }
+        for host in &self.allowed_hosts {
+            if host.contains('@') || host.parse::<axum::http::uri::Authority>().is_err() {
+                return Err(anyhow!("Invalid host in ALLOWED_HOSTS: '{}'", host));
+            }
+        }
+        for proxy in &self.trusted_proxies {
+            if proxy.parse::<std::net::IpAddr>().is_err() {
+                return Err(anyhow!("Invalid IP address in TRUSTED_PROXIES: '{}'", proxy));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_header_read_timeout_secs() -> u64 {
+    30
+}
+
+fn default_http2_max_concurrent_streams() -> u32 {
+    200
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 8080,
+            cert_path: None,
+            key_path: None,
+            landing_response: None,
+            request_deadline_secs: 0,
+            pixel_fallback_enabled: false,
+            server_timing_enabled: false,
+            timing_allow_origin: None,
+            stream_threshold_bytes: 0,
+            conditional_requests: true,
+            range_requests: true,
+            keep_alive: true,
+            header_read_timeout_secs: default_header_read_timeout_secs(),
+            http2_max_concurrent_streams: default_http2_max_concurrent_streams(),
+            http2_keep_alive_interval_secs: 0,
+            allowed_hosts: Vec::new(),
+            metrics_enabled: false,
+            rate_limit_per_minute: 0,
+            trusted_proxies: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct UpstreamConfig {
     pub host: String,
     pub referer: String,
+    pub client_cert: Option<String>, // PEM client certificate presented to upstream (mTLS)
+    pub client_key: Option<String>,  // PEM private key for `client_cert`
+    pub parent_proxy_url: Option<String>, // Parent instance consulted before upstream
+    pub parent_proxy_max_hops: u32,       // Requests that passed this many proxies skip the parent
+    #[serde(deserialize_with = "host_rules_from_file")]
+    pub host_rules: Vec<HostRule>,        // First matching path prefix picks the host, else `host`
+    #[serde(deserialize_with = "rewrite_rules_from_file")]
+    pub rewrite_rules: Vec<RewriteRule>,  // Legacy path rewrites, first match wins
+    pub path_templates_enabled: bool,     // Refuse request paths matching no path template
+    pub path_templates: Vec<String>,      // Accepted path shapes (empty = built-in Pixiv templates)
+    pub path_patterns: Vec<String>,       // Regexes accepted on top of the templates
+    pub first_byte_timeout_ms: u64,       // Limit on waiting for upstream response headers (0 = none)
+    pub error_header: bool,               // Report why a fetch failed in `X-Upstream-Error`
+    pub max_body_bytes: u64,              // Largest upstream body read, with or without Content-Length (0 = none)
+    pub max_retries: u32,                 // Retries of connection errors and 5xx answers
+    pub retry_base_ms: u64,               // First retry delay, doubled for each further retry
+    pub auth_header: String,              // Header carrying the upstream auth token
+    pub auth_prefix: String,              // Prepended to the token, e.g. "Bearer "
+    pub auth_token: Option<String>,       // Static upstream auth token
+    pub auth_token_url: Option<String>,   // Endpoint returning a token, fetched on a schedule and on 401
+    pub auth_refresh_secs: u64,
+}
+
+fn default_upstream_max_retries() -> u32 {
+    2
+}
+
+fn default_upstream_retry_base_ms() -> u64 {
+    100
+}
+
+fn default_auth_header() -> String {
+    "Authorization".to_string()
+}
+
+fn default_auth_refresh_secs() -> u64 {
+    300
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            host: "https://i.pximg.net".to_string(),
+            referer: "https://www.pixiv.net/".to_string(),
+            client_cert: None,
+            client_key: None,
+            parent_proxy_url: None,
+            parent_proxy_max_hops: default_parent_proxy_max_hops(),
+            host_rules: Vec::new(),
+            rewrite_rules: Vec::new(),
+            path_templates_enabled: false,
+            path_templates: Vec::new(),
+            path_patterns: Vec::new(),
+            first_byte_timeout_ms: 0,
+            error_header: false,
+            max_body_bytes: 0,
+            max_retries: default_upstream_max_retries(),
+            retry_base_ms: default_upstream_retry_base_ms(),
+            auth_header: default_auth_header(),
+            auth_prefix: String::new(),
+            auth_token: None,
+            auth_token_url: None,
+            auth_refresh_secs: default_auth_refresh_secs(),
+        }
+    }
+}
+
+/// Regex rewrite of legacy request paths; `replacement` may use `$1`-style captures.
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    pub pattern: String,
+    pub replacement: String,
+}
+
+// Parse `PATH_REWRITE_RULES`: one `pattern => replacement` rule per line
+fn parse_rewrite_rules(value: &str) -> Result<Vec<RewriteRule>> {
+    value
+        .lines()
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let (pattern, replacement) = rule.split_once("=>")
+                .ok_or_else(|| anyhow!("Invalid path rewrite rule '{}': expected pattern => replacement", rule))?;
+            Ok(RewriteRule {
+                pattern: pattern.trim().to_string(),
+                replacement: replacement.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// S3 object tag applied to stored objects whose key matches `pattern` (a regex).
+#[derive(Debug, Clone)]
+pub struct TagRule {
+    pub pattern: String,
+    pub key: String,
+    pub value: String,
+}
+
+// Parse `S3_OBJECT_TAGS`: comma-separated `key=value` pairs
+fn parse_object_tags(value: &str) -> Result<Vec<(String, String)>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| {
+            let (key, value) = tag.split_once('=')
+                .ok_or_else(|| anyhow!("Invalid S3 object tag '{}': expected key=value", tag))?;
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+// Parse `S3_ENCRYPTION_KEYS`: comma-separated `id=base64key` pairs. Base64 padding may
+// contain `=`, so only the first one separates the id.
+fn parse_encryption_keys(value: &str) -> Result<Vec<(String, String)>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (id, key) = entry.split_once('=')
+                .ok_or_else(|| anyhow!("Invalid S3_ENCRYPTION_KEYS entry: expected id=base64key"))?;
+            Ok((id.trim().to_string(), key.trim().to_string()))
+        })
+        .collect()
+}
+
+// Parse `S3_TAG_RULES`: one `pattern => key=value` rule per line
+fn parse_tag_rules(value: &str) -> Result<Vec<TagRule>> {
+    value
+        .lines()
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let (pattern, tag) = rule.rsplit_once("=>")
+                .ok_or_else(|| anyhow!("Invalid S3 tag rule '{}': expected pattern => key=value", rule))?;
+            let (key, value) = tag.split_once('=')
+                .ok_or_else(|| anyhow!("Invalid S3 tag rule '{}': expected pattern => key=value", rule))?;
+            Ok(TagRule {
+                pattern: pattern.trim().to_string(),
+                key: key.trim().to_string(),
+                value: value.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct HostRule {
+    pub prefix: String,
+    pub host: String,
+}
+
+impl UpstreamConfig {
+    /// Upstream host serving `path`.
+    pub fn host_for(&self, path: &str) -> &str {
+        self.host_rules
+            .iter()
+            .find(|rule| path.starts_with(&rule.prefix))
+            .map_or(&self.host, |rule| &rule.host)
+    }
+
+    /// Every distinct upstream host, the default first.
+    pub fn all_hosts(&self) -> Vec<String> {
+        let mut hosts = vec![self.host.clone()];
+        for rule in &self.host_rules {
+            if !hosts.contains(&rule.host) {
+                hosts.push(rule.host.clone());
+            }
+        }
+        hosts
+    }
+}
+
+fn default_parent_proxy_max_hops() -> u32 {
+    3
+}
+
+// Parse `UPSTREAM_HOST_RULES`, e.g. "/c/=https://a.example,/img-original/=https://b.example"
+fn parse_host_rules(value: &str) -> Result<Vec<HostRule>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let (prefix, host) = rule.split_once('=')
+                .ok_or_else(|| anyhow!("Invalid upstream host rule '{}': expected prefix=host", rule))?;
+            let (prefix, host) = (prefix.trim(), host.trim().trim_end_matches('/'));
+            if !prefix.starts_with('/') {
+                return Err(anyhow!("Invalid upstream host rule '{}': prefix must start with '/'", rule));
+            }
+            reqwest::Url::parse(host)
+                .map_err(|e| anyhow!("Invalid upstream host rule '{}': {}", rule, e))?;
+            Ok(HostRule { prefix: prefix.to_string(), host: host.to_string() })
+        })
+        .collect()
+}
+
+// Parse "family=content/type" pairs, rejecting families the sniffer never reports
+fn parse_content_type_overrides(value: &str) -> Result<Vec<(String, String)>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let (family, content_type) = rule.split_once('=')
+                .ok_or_else(|| anyhow!("Invalid content type override '{}': expected family=type", rule))?;
+            let (family, content_type) = (family.trim().to_lowercase(), content_type.trim().to_lowercase());
+            if !crate::transform::FORMAT_FAMILIES.contains(&family.as_str()) {
+                return Err(anyhow!(
+                    "Invalid content type override '{}': unknown family, expected one of {}",
+                    rule,
+                    crate::transform::FORMAT_FAMILIES.join(", ")
+                ));
+            }
+            if !content_type.split_once('/').is_some_and(|(kind, subtype)| !kind.is_empty() && !subtype.is_empty()) {
+                return Err(anyhow!("Invalid content type override '{}': '{}' is not a content type", rule, content_type));
+            }
+            Ok((family, content_type))
+        })
+        .collect()
+}
+
+// Parse an inclusive "min-max" quality range within 0-100
+fn parse_quality_range(value: &str) -> Result<(u8, u8)> {
+    let invalid = || anyhow!("Invalid WEBP_QUERY_QUALITY_RANGE '{}': expected min-max within 0-100", value);
+    let (min, max) = value.split_once('-').ok_or_else(invalid)?;
+    let min: u8 = min.trim().parse().map_err(|_| invalid())?;
+    let max: u8 = max.trim().parse().map_err(|_| invalid())?;
+    if min > max || max > 100 {
+        return Err(invalid());
+    }
+    Ok((min, max))
+}
+
+// An empty range leaves `?q=` ignored
+fn parse_optional_quality_range(value: &str) -> Result<Option<(u8, u8)>> {
+    Some(value).filter(|v| !v.trim().is_empty()).map(parse_quality_range).transpose()
 }
 
 #[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct StorageConfig {
     pub endpoint: String,
     pub bucket: String,
     pub region: String,
     pub access_key: String,
     pub secret_key: String,
-    #[serde(default)]
     pub encryption: EncryptionConfig,
-    #[serde(default)]
     pub compression: CompressionConfig,
+    pub validate_image_on_store: bool,
+    pub crypto_legacy_fallback: bool,
+    pub write_once: bool,
+    pub auto_region: bool,
+    pub consistency_grace_ms: u64, // Serve our own fresh writes this long after storing (0 = disabled)
+    pub self_heal_on_corruption: bool, // Delete objects that fail decryption/decompression and refetch
+    pub encrypt_on_read_migration: bool, // Re-store plaintext objects encrypted when they are read
+    pub recompress_on_read: bool, // Re-store objects compressed differently from the current settings when read
+    pub crypto_read_concurrency: usize, // Objects decrypted/decompressed at once on the blocking pool
+    pub store_retries: u32, // Extra attempts at a failed background store, reusing the fetched bytes
+    pub store_retry_backoff_ms: u64, // Delay before the first retry, doubled for each further one
+    pub max_background_uploads: usize, // Background S3 stores running at once; more are dropped (0 = unlimited)
+    pub max_retries: u32, // Retries of each S3 request after a connection error or 5xx
+    pub retry_backoff_ms: u64, // Delay before the first S3 request retry, doubled for each further one
+    #[serde(deserialize_with = "object_tags_from_file")]
+    pub object_tags: Vec<(String, String)>, // S3 tags set on every stored object
+    #[serde(deserialize_with = "tag_rules_from_file")]
+    pub tag_rules: Vec<TagRule>,            // S3 tags set on objects whose key matches
+    pub client_cert: Option<String>, // PEM client certificate presented to S3 (mTLS)
+    pub client_key: Option<String>,  // PEM private key for `client_cert`
+    pub cold_tier: Option<ColdTierConfig>, // Where objects nobody has read in a while are moved
 }
 
+/// Secondary bucket, or prefix of the same bucket, for objects that aged out of the main
+/// one. Objects are moved as stored, so both tiers share the crypto settings.
 #[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ColdTierConfig {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    pub prefix: String, // Prepended to object keys in the cold bucket
+    pub storage_class: Option<String>, // x-amz-storage-class of moved objects, e.g. STANDARD_IA
+    pub migration_age_secs: u64, // Objects neither stored nor read for this long are moved
+    pub migration_interval_secs: u64, // Seconds between migration runs, each covering one listing page
+}
+
+fn default_cold_migration_age_secs() -> u64 {
+    30 * 86400
+}
+
+fn default_cold_migration_interval_secs() -> u64 {
+    600
+}
+
+// Endpoint, region and credentials left empty fall back to the main storage settings
+impl Default for ColdTierConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: String::new(),
+            bucket: String::new(),
+            region: String::new(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            prefix: String::new(),
+            storage_class: None,
+            migration_age_secs: default_cold_migration_age_secs(),
+            migration_interval_secs: default_cold_migration_interval_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct EncryptionConfig {
     pub enabled: bool,
-    #[serde(default = "default_encryption_algorithm")]
     pub algorithm: String,
-    pub key: Option<String>,
+    pub key: Option<String>, // Key of objects stored without a key id
+    #[serde(deserialize_with = "encryption_keys_from_file")]
+    pub keys: Vec<(String, String)>, // Key id -> base64 key, for rotation
+    pub active_key: Option<String>, // Id of the key new objects are encrypted with
 }
 
 #[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct CompressionConfig {
     pub enabled: bool,
-    #[serde(default = "default_compression_algorithm")]
     pub algorithm: String,
-    #[serde(default = "default_compression_level")]
     pub level: u32,
+    pub content_types: Vec<String>, // Content types to compress; "type/*" and "*" wildcards allowed
+    pub gzip_passthrough: bool, // Serve gzip-at-rest objects compressed to clients that accept gzip
+    pub svg_brotli: bool, // Accept SVG, store it brotli-compressed and serve it as `br` when accepted
+    pub dictionary: Option<String>, // Path of a trained zstd dictionary used for new objects
+    pub retired_dictionaries: Vec<String>, // Paths of earlier dictionaries, kept only to read old objects
 }
 
 impl Default for EncryptionConfig {
     fn default() -> Self {
         Self {
             enabled: false,
-            algorithm: "AES-256-GCM".to_string(),
+            algorithm: default_encryption_algorithm(),
             key: None,
+            keys: Vec::new(),
+            active_key: None,
         }
     }
 }
@@ -68,8 +464,13 @@ impl Default for CompressionConfig {
     fn default() -> Self {
         Self {
             enabled: false,
-            algorithm: "gzip".to_string(),
-            level: 6,
+            algorithm: default_compression_algorithm(),
+            level: default_compression_level(),
+            content_types: default_compression_content_types(),
+            gzip_passthrough: false,
+            svg_brotli: false,
+            dictionary: None,
+            retired_dictionaries: Vec::new(),
         }
     }
 }
@@ -86,68 +487,998 @@ fn default_compression_level() -> u32 {
     6
 }
 
+// Already-compressed image formats gain nothing from another compression pass
+fn default_compression_content_types() -> Vec<String> {
+    vec![
+        "image/svg+xml".to_string(),
+        "application/octet-stream".to_string(),
+        "application/json".to_string(),
+        "text/*".to_string(),
+    ]
+}
+
+fn default_max_background_uploads() -> usize {
+    64
+}
+
+fn default_s3_max_retries() -> u32 {
+    2
+}
+
+fn default_s3_retry_backoff_ms() -> u64 {
+    100
+}
+
+fn default_store_retry_backoff_ms() -> u64 {
+    200
+}
+
+fn default_store_lock_ttl_ms() -> u64 {
+    30_000
+}
+
+fn default_burst_max_bytes() -> usize {
+    2 * 1024 * 1024
+}
+
+fn default_redis_max_value_bytes() -> usize {
+    8 * 1024 * 1024
+}
+
+fn default_query_key_mode() -> String {
+    "strip".to_string()
+}
+
+fn default_crypto_read_concurrency() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4)
+}
+
+// The endpoint, bucket and credentials are required, so they have no default
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: String::new(),
+            bucket: String::new(),
+            region: "us-east-1".to_string(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            encryption: EncryptionConfig::default(),
+            compression: CompressionConfig::default(),
+            validate_image_on_store: false,
+            crypto_legacy_fallback: true,
+            write_once: false,
+            auto_region: false,
+            consistency_grace_ms: 0,
+            self_heal_on_corruption: false,
+            encrypt_on_read_migration: false,
+            recompress_on_read: false,
+            crypto_read_concurrency: default_crypto_read_concurrency(),
+            store_retries: 0,
+            store_retry_backoff_ms: default_store_retry_backoff_ms(),
+            max_background_uploads: default_max_background_uploads(),
+            max_retries: default_s3_max_retries(),
+            retry_backoff_ms: default_s3_retry_backoff_ms(),
+            object_tags: Vec::new(),
+            tag_rules: Vec::new(),
+            client_cert: None,
+            client_key: None,
+            cold_tier: None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct CacheConfig {
     pub redis_url: String,
     pub not_found_ttl: u64,    // TTL in seconds for 404 responses (1 day = 86400)
     pub server_error_ttl: u64, // TTL in seconds for 5xx responses (20 min = 1200)
+    pub server_error_revalidate_secs: u64, // Seconds between background retries of a cached 5xx (0 = disabled)
+    pub negative_max_ttl: u64, // Ceiling on any negative cache TTL (0 = no ceiling)
+    pub negative_resample_interval: u64, // Seconds between early expiries of sampled negative entries (0 = disabled)
+    pub negative_resample_rate: f64, // Fraction of old negative entries expired early per run
+    pub burst_ttl: u64,        // TTL in seconds for short-lived positive responses (0 = disabled)
+    pub burst_max_bytes: usize, // Largest body kept in the burst cache
+    pub memory_cache_mb: u64, // Size of the per-instance in-memory LRU (0 = disabled)
+    pub store_lock_ttl_ms: u64, // Expiry of the per-key store lock in ms (0 = no locking)
+    pub coalesce_window_ms: u64, // How long other instances wait on an in-flight fetch (0 = disabled)
+    pub query_key_mode: String, // "strip", "allowlist" or "include" query params in cache keys
+    pub query_key_allowlist: Vec<String>, // Params kept in the key in "allowlist" mode
+    #[serde(deserialize_with = "from_str")]
+    pub content_hash_algo: HashAlgorithm, // Hash of response bodies and stored objects, used as ETag
+    pub no_cache_content_types: Vec<String>, // Served through but never stored or negatively cached
+    pub max_value_bytes: usize, // Largest body ever written to Redis
+    pub stale_if_error_secs: u64, // stale-if-error advertised to downstream caches (0 = omitted)
+    pub error_circuit_threshold: u64, // Fleet-wide upstream server errors per window that open the circuit (0 = disabled)
+    pub error_circuit_window_secs: u64,
+    pub error_circuit_open_secs: u64, // How long upstream fetches are skipped once the circuit opens
+    pub cache_control: String, // Cache-Control of image responses no rule matches
+    #[serde(deserialize_with = "cache_control_rules_from_file")]
+    pub cache_control_rules: Vec<(String, String)>, // Content type pattern -> Cache-Control, first match wins
+    pub hex_segment_pattern: Option<String>, // Regex of hash segments lowercased in cache keys
+    pub artwork_id_pattern: Option<String>, // Regex whose first capture group is a path's artwork id
+    #[serde(deserialize_with = "from_str")]
+    pub outage_negative_cache: OutagePolicy, // Negative cache lookups while Redis is unreachable
+    #[serde(deserialize_with = "from_str")]
+    pub outage_store_lock: OutagePolicy, // S3 stores while the store lock cannot be taken
+    #[serde(deserialize_with = "from_str")]
+    pub outage_rate_limit: OutagePolicy, // Rate-limited requests while their count cannot be kept
+}
+
+/// What a Redis-backed feature does while Redis is unreachable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutagePolicy {
+    #[default]
+    Open,   // Carry on without the feature
+    Closed, // Refuse the work the feature guards
+}
+
+impl std::str::FromStr for OutagePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "open" => Ok(OutagePolicy::Open),
+            "closed" => Ok(OutagePolicy::Closed),
+            _ => Err(anyhow!("Unsupported Redis outage policy: {} (expected open or closed)", name)),
+        }
+    }
+}
+
+fn default_negative_resample_rate() -> f64 {
+    0.01
+}
+
+fn default_error_circuit_window_secs() -> u64 {
+    60
+}
+
+fn default_error_circuit_open_secs() -> u64 {
+    30
+}
+
+fn default_cache_control() -> String {
+    "public, max-age=604800".to_string() // 7 days
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            redis_url: String::new(),
+            not_found_ttl: 86400,
+            server_error_ttl: 1200,
+            server_error_revalidate_secs: 0,
+            negative_max_ttl: 0,
+            negative_resample_interval: 0,
+            negative_resample_rate: default_negative_resample_rate(),
+            burst_ttl: 0,
+            burst_max_bytes: default_burst_max_bytes(),
+            memory_cache_mb: 0,
+            store_lock_ttl_ms: default_store_lock_ttl_ms(),
+            coalesce_window_ms: 0,
+            query_key_mode: default_query_key_mode(),
+            query_key_allowlist: Vec::new(),
+            content_hash_algo: HashAlgorithm::default(),
+            no_cache_content_types: Vec::new(),
+            max_value_bytes: default_redis_max_value_bytes(),
+            stale_if_error_secs: 0,
+            error_circuit_threshold: 0,
+            error_circuit_window_secs: default_error_circuit_window_secs(),
+            error_circuit_open_secs: default_error_circuit_open_secs(),
+            cache_control: default_cache_control(),
+            cache_control_rules: Vec::new(),
+            hex_segment_pattern: None,
+            artwork_id_pattern: None,
+            outage_negative_cache: OutagePolicy::default(),
+            outage_store_lock: OutagePolicy::default(),
+            outage_rate_limit: OutagePolicy::default(),
+        }
+    }
+}
+
+// Parse "content/type => directives" rules, one per line
+fn parse_cache_control_rules(value: &str) -> Result<Vec<(String, String)>> {
+    value
+        .lines()
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let (pattern, directives) = rule.split_once("=>")
+                .ok_or_else(|| anyhow!("Invalid Cache-Control rule '{}': expected content/type => directives", rule))?;
+            let (pattern, directives) = (pattern.trim().to_lowercase(), directives.trim().to_string());
+            if pattern.is_empty() || directives.is_empty() || axum::http::HeaderValue::from_str(&directives).is_err() {
+                return Err(anyhow!("Invalid Cache-Control rule '{}': expected content/type => directives", rule));
+            }
+            Ok((pattern, directives))
+        })
+        .collect()
+}
+
+impl CacheConfig {
+    /// Cache-Control for an image response of `content_type`: the first matching rule,
+    /// else the global value.
+    pub fn cache_control_for(&self, content_type: &str) -> &str {
+        self.cache_control_rules
+            .iter()
+            .find(|(pattern, _)| content_type_matches(std::slice::from_ref(pattern), Some(content_type)))
+            .map(|(_, directives)| directives.as_str())
+            .unwrap_or(&self.cache_control)
+    }
+
+    /// Whether serving depends on Redis being reachable, i.e. some feature fails closed.
+    pub fn requires_redis(&self) -> bool {
+        self.outage_negative_cache == OutagePolicy::Closed
+            || self.outage_store_lock == OutagePolicy::Closed
+            || self.outage_rate_limit == OutagePolicy::Closed
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    pub interval: u64,              // Seconds between upstream probes
+    pub timeout: u64,               // Timeout in seconds for a single probe
+    pub upstream_probe_path: String,
+    pub upstream_probe_statuses: Vec<u16>, // Empty means any non-5xx status is healthy
+    pub healthz_cache_secs: u64, // How long a passing /healthz check is reused (0 = check every time)
+}
+
+fn default_healthz_cache_secs() -> u64 {
+    2
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub token: Option<String>, // Image requests need this bearer or ?token= when set
+    pub signing_secret: Option<String>, // Image requests need a valid ?expires=&sig= when set
 }
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub token: Option<String>,  // Admin API is disabled when unset
+    pub bench_enabled: bool,
+    pub bench_max_bytes: usize,
+    pub manifest_source: Option<ManifestSourceConfig>, // Bucket that manifest imports copy from
+    pub manifest_import_concurrency: usize,
+    pub warm_concurrency: usize, // Paths fetched in parallel by `POST /admin/warm`
+}
+
+/// Source bucket for manifest imports. Objects are copied as stored, so the source
+/// must use the same encryption key as this deployment. Everything but the bucket falls
+/// back to the main storage settings when left empty.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ManifestSourceConfig {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            bench_enabled: false,
+            bench_max_bytes: default_bench_max_bytes(),
+            manifest_source: None,
+            manifest_import_concurrency: default_manifest_import_concurrency(),
+            warm_concurrency: default_warm_concurrency(),
+        }
+    }
+}
+
+fn default_bench_max_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_manifest_import_concurrency() -> usize {
+    8
+}
+
+fn default_warm_concurrency() -> usize {
+    4
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransformConfig {
+    pub store_as_webp: bool,     // Replace stored JPEG/PNG originals with a WebP copy
+    pub webp_quality: f32,       // 0-100
+    pub max_pixels: u64,         // Images above this size are never transcoded
+    pub format_preferences: Vec<String>, // Variant tokens ("avif", "webp") in order of preference
+    pub thumbnail_on_store: bool,  // Store a WebP thumbnail sidecar next to every original
+    pub thumbnail_size: u32,       // Longest thumbnail edge in pixels
+    pub thumbnail_quality: f32,    // 0-100
+    pub max_variants_per_original: usize, // Stored variants (formats, thumbnail) per original (0 = unlimited)
+    #[serde(deserialize_with = "content_type_overrides_from_file")]
+    pub content_type_overrides: Vec<(String, String)>, // Sniffed format family -> content type served
+    #[serde(deserialize_with = "quality_range_from_file")]
+    pub query_quality_range: Option<(u8, u8)>, // Inclusive WebP qualities accepted in `?q=` (None = ignored)
+}
+
+impl Default for TransformConfig {
+    fn default() -> Self {
+        Self {
+            store_as_webp: false,
+            webp_quality: default_webp_quality(),
+            max_pixels: default_transform_max_pixels(),
+            format_preferences: Vec::new(),
+            thumbnail_on_store: false,
+            thumbnail_size: default_thumbnail_size(),
+            thumbnail_quality: default_thumbnail_quality(),
+            max_variants_per_original: 0,
+            content_type_overrides: Vec::new(),
+            query_quality_range: None,
+        }
+    }
+}
+
+fn default_thumbnail_size() -> u32 {
+    320
+}
+
+fn default_thumbnail_quality() -> f32 {
+    75.0
+}
+
+fn default_webp_quality() -> f32 {
+    80.0
+}
+
+fn default_transform_max_pixels() -> u64 {
+    40_000_000
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsConfig {
+    pub interval: u64,           // Seconds between key space samples (0 = disabled)
+    pub redis_method: String,    // "scan" (negative-cache entries) or "dbsize" (all keys)
+    pub redis_scan_limit: u64,   // Stop scanning Redis after this many keys
+    pub s3_max_pages: u32,       // Stop listing S3 after this many pages of 1000 objects
+    pub shared_sample_secs: u64, // How long a sample in Redis is reused by every instance (0 = not shared)
+    pub hot_paths_top_n: usize,  // Paths reported by /admin/hot (0 = tracking disabled)
+    pub hot_paths_window: u64,   // Seconds before hot path counts start over (0 = never)
+    pub fleet_stats: bool,       // Sum request counters across instances in Redis
+    pub flush_interval: u64,     // Seconds between flushes of this instance's counters to Redis
+}
+
+fn default_flush_interval() -> u64 {
+    60
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            interval: 300,
+            redis_method: "scan".to_string(),
+            redis_scan_limit: 100_000,
+            s3_max_pages: 10,
+            shared_sample_secs: 0,
+            hot_paths_top_n: 0,
+            hot_paths_window: 0,
+            fleet_stats: false,
+            flush_interval: default_flush_interval(),
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            interval: 30,
+            timeout: 5,
+            upstream_probe_path: "/".to_string(),
+            upstream_probe_statuses: Vec::new(),
+            healthz_cache_secs: default_healthz_cache_secs(),
+        }
+    }
+}
+
+const REDACTED: &str = "***";
+
+// Settings without a default, reported together when any are missing
+const REQUIRED_SETTINGS: &[&str] = &["S3_ENDPOINT", "S3_BUCKET", "S3_ACCESS_KEY", "S3_SECRET_KEY", "REDIS_URL"];
+
 impl Config {
+    /// Pretty debug dump of the effective configuration with all secrets replaced by `***`.
+    pub fn redacted_debug(&self) -> String {
+        let mut config = self.clone();
+
+        config.storage.access_key = REDACTED.to_string();
+        config.storage.secret_key = REDACTED.to_string();
+        if config.storage.encryption.key.is_some() {
+            config.storage.encryption.key = Some(REDACTED.to_string());
+        }
+        for (_, key) in config.storage.encryption.keys.iter_mut() {
+            *key = REDACTED.to_string();
+        }
+        config.cache.redis_url = redact_url_password(&config.cache.redis_url);
+        if config.admin.token.is_some() {
+            config.admin.token = Some(REDACTED.to_string());
+        }
+        if config.auth.token.is_some() {
+            config.auth.token = Some(REDACTED.to_string());
+        }
+        if config.auth.signing_secret.is_some() {
+            config.auth.signing_secret = Some(REDACTED.to_string());
+        }
+        if config.upstream.auth_token.is_some() {
+            config.upstream.auth_token = Some(REDACTED.to_string());
+        }
+        if let Some(url) = config.upstream.auth_token_url.as_mut() {
+            *url = redact_url_password(url);
+        }
+        if let Some(source) = config.admin.manifest_source.as_mut() {
+            source.access_key = REDACTED.to_string();
+            source.secret_key = REDACTED.to_string();
+        }
+        if let Some(cold_tier) = config.storage.cold_tier.as_mut() {
+            cold_tier.access_key = REDACTED.to_string();
+            cold_tier.secret_key = REDACTED.to_string();
+        }
+
+        format!("{:#?}", config)
+    }
+
     pub fn from_env() -> Result<Self> {
-        Ok(Config {
-            server: ServerConfig {
-                host: env::var("SERVER_HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
-                port: env::var("SERVER_PORT")
-                    .unwrap_or_else(|_| "8080".to_string())
-                    .parse()
-                    .unwrap_or(8080),
-                cert_path: env::var("SSL_CERT_PATH").ok(),
-                key_path: env::var("SSL_KEY_PATH").ok(),
-            },
-            upstream: UpstreamConfig {
-                host: env::var("UPSTREAM_HOST").unwrap_or_else(|_| "https://i.pximg.net".to_string()),
-                referer: env::var("UPSTREAM_REFERER").unwrap_or_else(|_| "https://www.pixiv.net/".to_string()),
-            },
-            storage: StorageConfig {
-                endpoint: env::var("S3_ENDPOINT")?,
-                bucket: env::var("S3_BUCKET")?,
-                region: env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
-                access_key: env::var("S3_ACCESS_KEY")?,
-                secret_key: env::var("S3_SECRET_KEY")?,
-                encryption: EncryptionConfig {
-                    enabled: env::var("S3_ENCRYPTION_ENABLED")
-                        .unwrap_or_else(|_| "false".to_string())
-                        .parse()
-                        .unwrap_or(false),
-                    algorithm: env::var("S3_ENCRYPTION_ALGORITHM")
-                        .unwrap_or_else(|_| "AES-256-GCM".to_string()),
-                    key: env::var("S3_ENCRYPTION_KEY").ok(),
-                },
-                compression: CompressionConfig {
-                    enabled: env::var("S3_COMPRESSION_ENABLED")
-                        .unwrap_or_else(|_| "false".to_string())
-                        .parse()
-                        .unwrap_or(false),
-                    algorithm: env::var("S3_COMPRESSION_ALGORITHM")
-                        .unwrap_or_else(|_| "gzip".to_string()),
-                    level: env::var("S3_COMPRESSION_LEVEL")
-                        .unwrap_or_else(|_| "6".to_string())
-                        .parse()
-                        .unwrap_or(6),
-                },
-            },
-            cache: CacheConfig {
-                redis_url: env::var("REDIS_URL")?,
-                not_found_ttl: env::var("CACHE_404_TTL")
-                    .unwrap_or_else(|_| "86400".to_string())
-                    .parse()
-                    .unwrap_or(86400),
-                server_error_ttl: env::var("CACHE_ERROR_TTL")
-                    .unwrap_or_else(|_| "1200".to_string())
-                    .parse()
-                    .unwrap_or(1200),
-            },
-        })
+        Self::from_source(Config::default(), &HashMap::new())
+    }
+
+    /// Load settings from a TOML file laid out like `Config`: one table per section with the
+    /// field names as keys, e.g. `[storage]` with `bucket = "pixiv"`. Variables set in the
+    /// environment take precedence, so containers can still override single settings.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read config file {}: {}", path, e))?;
+        let file: Config = toml::from_str(&contents)
+            .map_err(|e| anyhow!("Invalid config file {}: {}", path, e))?;
+        Self::from_source(file, &HashMap::new())
+    }
+
+    // Every setting set in the environment, or else in `settings`, overrides `config`
+    fn from_source(mut config: Config, settings: &HashMap<String, String>) -> Result<Self> {
+        let o = Overrides { settings };
+
+        let server = &mut config.server;
+        o.set("SERVER_HOST", &mut server.host);
+        o.set("SERVER_PORT", &mut server.port);
+        o.set_optional("SSL_CERT_PATH", &mut server.cert_path);
+        o.set_optional("SSL_KEY_PATH", &mut server.key_path);
+        o.set_optional("LANDING_RESPONSE", &mut server.landing_response);
+        o.set("REQUEST_DEADLINE_SECS", &mut server.request_deadline_secs);
+        o.set("PIXEL_FALLBACK_ENABLED", &mut server.pixel_fallback_enabled);
+        o.set("SERVER_TIMING_ENABLED", &mut server.server_timing_enabled);
+        o.set_optional("TIMING_ALLOW_ORIGIN", &mut server.timing_allow_origin);
+        o.set("STREAM_THRESHOLD_BYTES", &mut server.stream_threshold_bytes);
+        o.set("CONDITIONAL_REQUESTS_ENABLED", &mut server.conditional_requests);
+        o.set("RANGE_REQUESTS_ENABLED", &mut server.range_requests);
+        o.set("SERVER_KEEP_ALIVE", &mut server.keep_alive);
+        o.set("SERVER_HEADER_READ_TIMEOUT_SECS", &mut server.header_read_timeout_secs);
+        o.set("SERVER_HTTP2_MAX_CONCURRENT_STREAMS", &mut server.http2_max_concurrent_streams);
+        o.set("SERVER_HTTP2_KEEP_ALIVE_INTERVAL_SECS", &mut server.http2_keep_alive_interval_secs);
+        o.set("METRICS_ENABLED", &mut server.metrics_enabled);
+        o.set_list("ALLOWED_HOSTS", &mut server.allowed_hosts);
+        o.set("RATE_LIMIT_PER_MINUTE", &mut server.rate_limit_per_minute);
+        o.set_list("TRUSTED_PROXIES", &mut server.trusted_proxies);
+
+        let upstream = &mut config.upstream;
+        o.set("UPSTREAM_HOST", &mut upstream.host);
+        o.set("UPSTREAM_REFERER", &mut upstream.referer);
+        o.set_optional("UPSTREAM_CLIENT_CERT", &mut upstream.client_cert);
+        o.set_optional("UPSTREAM_CLIENT_KEY", &mut upstream.client_key);
+        o.set_optional("PARENT_PROXY_URL", &mut upstream.parent_proxy_url);
+        o.set("PARENT_PROXY_MAX_HOPS", &mut upstream.parent_proxy_max_hops);
+        o.set_parsed("UPSTREAM_HOST_RULES", &mut upstream.host_rules, parse_host_rules)?;
+        o.set_parsed("PATH_REWRITE_RULES", &mut upstream.rewrite_rules, parse_rewrite_rules)?;
+        o.set("PATH_TEMPLATES_ENABLED", &mut upstream.path_templates_enabled);
+        o.set_lines("PATH_TEMPLATES", &mut upstream.path_templates);
+        o.set_lines("PATH_PATTERNS", &mut upstream.path_patterns);
+        o.set("UPSTREAM_FIRST_BYTE_TIMEOUT_MS", &mut upstream.first_byte_timeout_ms);
+        o.set("UPSTREAM_ERROR_HEADER", &mut upstream.error_header);
+        o.set("UPSTREAM_MAX_RETRIES", &mut upstream.max_retries);
+        o.set("UPSTREAM_RETRY_BASE_MS", &mut upstream.retry_base_ms);
+        o.set("MAX_UPSTREAM_BYTES", &mut upstream.max_body_bytes);
+        o.set("UPSTREAM_AUTH_HEADER", &mut upstream.auth_header);
+        o.set("UPSTREAM_AUTH_PREFIX", &mut upstream.auth_prefix);
+        o.set_optional("UPSTREAM_AUTH_TOKEN", &mut upstream.auth_token);
+        o.set_optional("UPSTREAM_AUTH_TOKEN_URL", &mut upstream.auth_token_url);
+        o.set("UPSTREAM_AUTH_REFRESH_SECS", &mut upstream.auth_refresh_secs);
+
+        let storage = &mut config.storage;
+        o.set("S3_ENDPOINT", &mut storage.endpoint);
+        o.set("S3_BUCKET", &mut storage.bucket);
+        o.set("S3_REGION", &mut storage.region);
+        o.set("S3_ACCESS_KEY", &mut storage.access_key);
+        o.set("S3_SECRET_KEY", &mut storage.secret_key);
+        o.set("S3_ENCRYPTION_ENABLED", &mut storage.encryption.enabled);
+        o.set("S3_ENCRYPTION_ALGORITHM", &mut storage.encryption.algorithm);
+        o.set_optional("S3_ENCRYPTION_KEY", &mut storage.encryption.key);
+        o.set_parsed("S3_ENCRYPTION_KEYS", &mut storage.encryption.keys, parse_encryption_keys)?;
+        o.set_optional("S3_ENCRYPTION_ACTIVE_KEY", &mut storage.encryption.active_key);
+        o.set("S3_COMPRESSION_ENABLED", &mut storage.compression.enabled);
+        o.set("S3_COMPRESSION_ALGORITHM", &mut storage.compression.algorithm);
+        o.set("S3_COMPRESSION_LEVEL", &mut storage.compression.level);
+        o.set_list("S3_COMPRESSION_CONTENT_TYPES", &mut storage.compression.content_types);
+        o.set("S3_GZIP_PASSTHROUGH", &mut storage.compression.gzip_passthrough);
+        o.set("SVG_BROTLI_ENABLED", &mut storage.compression.svg_brotli);
+        o.set_optional("S3_COMPRESSION_DICTIONARY", &mut storage.compression.dictionary);
+        o.set_list("S3_COMPRESSION_RETIRED_DICTIONARIES", &mut storage.compression.retired_dictionaries);
+        o.set("VALIDATE_IMAGE_ON_STORE", &mut storage.validate_image_on_store);
+        o.set("CRYPTO_LEGACY_FALLBACK", &mut storage.crypto_legacy_fallback);
+        o.set("STORAGE_WRITE_ONCE", &mut storage.write_once);
+        o.set("S3_AUTO_REGION", &mut storage.auto_region);
+        o.set("S3_CONSISTENCY_GRACE_MS", &mut storage.consistency_grace_ms);
+        o.set("SELF_HEAL_ON_CORRUPTION", &mut storage.self_heal_on_corruption);
+        o.set("ENCRYPT_ON_READ_MIGRATION", &mut storage.encrypt_on_read_migration);
+        o.set("RECOMPRESS_ON_READ", &mut storage.recompress_on_read);
+        o.set("CRYPTO_READ_CONCURRENCY", &mut storage.crypto_read_concurrency);
+        o.set("STORE_RETRIES", &mut storage.store_retries);
+        o.set("STORE_RETRY_BACKOFF_MS", &mut storage.store_retry_backoff_ms);
+        o.set("MAX_BACKGROUND_UPLOADS", &mut storage.max_background_uploads);
+        o.set("S3_MAX_RETRIES", &mut storage.max_retries);
+        o.set("S3_RETRY_BACKOFF_MS", &mut storage.retry_backoff_ms);
+        o.set_parsed("S3_OBJECT_TAGS", &mut storage.object_tags, parse_object_tags)?;
+        o.set_parsed("S3_TAG_RULES", &mut storage.tag_rules, parse_tag_rules)?;
+        o.set_optional("S3_CLIENT_CERT", &mut storage.client_cert);
+        o.set_optional("S3_CLIENT_KEY", &mut storage.client_key);
+        if let Some(bucket) = o.get("COLD_TIER_BUCKET") {
+            storage.cold_tier.get_or_insert_with(ColdTierConfig::default).bucket = bucket;
+        }
+        if let Some(cold) = storage.cold_tier.as_mut() {
+            o.set("COLD_TIER_ENDPOINT", &mut cold.endpoint);
+            o.set("COLD_TIER_REGION", &mut cold.region);
+            o.set("COLD_TIER_ACCESS_KEY", &mut cold.access_key);
+            o.set("COLD_TIER_SECRET_KEY", &mut cold.secret_key);
+            o.set("COLD_TIER_PREFIX", &mut cold.prefix);
+            o.set_optional("COLD_TIER_STORAGE_CLASS", &mut cold.storage_class);
+            o.set("COLD_TIER_MIGRATION_AGE", &mut cold.migration_age_secs);
+            o.set("COLD_TIER_MIGRATION_INTERVAL", &mut cold.migration_interval_secs);
+        }
+
+        let cache = &mut config.cache;
+        o.set("REDIS_URL", &mut cache.redis_url);
+        o.set("CACHE_404_TTL", &mut cache.not_found_ttl);
+        o.set("CACHE_ERROR_TTL", &mut cache.server_error_ttl);
+        o.set("NEGATIVE_CACHE_MAX_TTL", &mut cache.negative_max_ttl);
+        o.set("SERVER_ERROR_REVALIDATE_SECS", &mut cache.server_error_revalidate_secs);
+        o.set("NEGATIVE_RESAMPLE_INTERVAL", &mut cache.negative_resample_interval);
+        o.set("NEGATIVE_RESAMPLE_RATE", &mut cache.negative_resample_rate);
+        o.set("BURST_CACHE_TTL", &mut cache.burst_ttl);
+        o.set("BURST_CACHE_MAX_BYTES", &mut cache.burst_max_bytes);
+        o.set("MEMORY_CACHE_MB", &mut cache.memory_cache_mb);
+        o.set("STORE_LOCK_TTL_MS", &mut cache.store_lock_ttl_ms);
+        o.set("COALESCE_WINDOW_MS", &mut cache.coalesce_window_ms);
+        o.set("QUERY_KEY_MODE", &mut cache.query_key_mode);
+        o.set_list("QUERY_KEY_ALLOWLIST", &mut cache.query_key_allowlist);
+        o.set_parsed("CONTENT_HASH_ALGO", &mut cache.content_hash_algo, str::parse)?;
+        o.set_list("NO_CACHE_CONTENT_TYPES", &mut cache.no_cache_content_types);
+        o.set("REDIS_MAX_VALUE_BYTES", &mut cache.max_value_bytes);
+        o.set("STALE_IF_ERROR_SECS", &mut cache.stale_if_error_secs);
+        o.set("ERROR_CIRCUIT_THRESHOLD", &mut cache.error_circuit_threshold);
+        o.set("ERROR_CIRCUIT_WINDOW_SECS", &mut cache.error_circuit_window_secs);
+        o.set("ERROR_CIRCUIT_OPEN_SECS", &mut cache.error_circuit_open_secs);
+        o.set("CACHE_CONTROL", &mut cache.cache_control);
+        o.set_parsed("CACHE_CONTROL_RULES", &mut cache.cache_control_rules, parse_cache_control_rules)?;
+        o.set_optional("KEY_HEX_SEGMENT_PATTERN", &mut cache.hex_segment_pattern);
+        o.set_optional("ARTWORK_ID_PATTERN", &mut cache.artwork_id_pattern);
+        o.set_parsed("REDIS_OUTAGE_NEGATIVE_CACHE", &mut cache.outage_negative_cache, str::parse)?;
+        o.set_parsed("REDIS_OUTAGE_STORE_LOCK", &mut cache.outage_store_lock, str::parse)?;
+        o.set_parsed("REDIS_OUTAGE_RATE_LIMIT", &mut cache.outage_rate_limit, str::parse)?;
+
+        let health = &mut config.health;
+        o.set("HEALTH_CHECK_INTERVAL", &mut health.interval);
+        o.set("HEALTH_CHECK_TIMEOUT", &mut health.timeout);
+        o.set("UPSTREAM_PROBE_PATH", &mut health.upstream_probe_path);
+        if let Some(statuses) = o.get("UPSTREAM_PROBE_STATUSES") {
+            health.upstream_probe_statuses = statuses.split(',').filter_map(|s| s.trim().parse().ok()).collect();
+        }
+        o.set("HEALTHZ_CACHE_SECS", &mut health.healthz_cache_secs);
+
+        o.set_optional("PROXY_AUTH_TOKEN", &mut config.auth.token);
+        o.set_optional("URL_SIGNING_SECRET", &mut config.auth.signing_secret);
+
+        let admin = &mut config.admin;
+        o.set_optional("ADMIN_TOKEN", &mut admin.token);
+        o.set("ADMIN_BENCH_ENABLED", &mut admin.bench_enabled);
+        o.set("ADMIN_BENCH_MAX_BYTES", &mut admin.bench_max_bytes);
+        if let Some(bucket) = o.get("MANIFEST_SOURCE_BUCKET") {
+            admin.manifest_source.get_or_insert_with(ManifestSourceConfig::default).bucket = bucket;
+        }
+        if let Some(source) = admin.manifest_source.as_mut() {
+            o.set("MANIFEST_SOURCE_ENDPOINT", &mut source.endpoint);
+            o.set("MANIFEST_SOURCE_REGION", &mut source.region);
+            o.set("MANIFEST_SOURCE_ACCESS_KEY", &mut source.access_key);
+            o.set("MANIFEST_SOURCE_SECRET_KEY", &mut source.secret_key);
+        }
+        o.set("MANIFEST_IMPORT_CONCURRENCY", &mut admin.manifest_import_concurrency);
+        o.set("WARM_CONCURRENCY", &mut admin.warm_concurrency);
+
+        let stats = &mut config.stats;
+        o.set("STATS_INTERVAL", &mut stats.interval);
+        o.set("STATS_REDIS_METHOD", &mut stats.redis_method);
+        o.set("STATS_REDIS_SCAN_LIMIT", &mut stats.redis_scan_limit);
+        o.set("STATS_S3_MAX_PAGES", &mut stats.s3_max_pages);
+        o.set("STATS_SHARED_SAMPLE_SECS", &mut stats.shared_sample_secs);
+        o.set("HOT_PATHS_TOP_N", &mut stats.hot_paths_top_n);
+        o.set("HOT_PATHS_WINDOW_SECS", &mut stats.hot_paths_window);
+        o.set("FLEET_STATS_ENABLED", &mut stats.fleet_stats);
+        o.set("METRICS_FLUSH_INTERVAL", &mut stats.flush_interval);
+
+        let transform = &mut config.transform;
+        o.set("STORE_AS_WEBP", &mut transform.store_as_webp);
+        o.set("WEBP_QUALITY", &mut transform.webp_quality);
+        o.set("TRANSFORM_MAX_PIXELS", &mut transform.max_pixels);
+        o.set_list("FORMAT_PREFERENCES", &mut transform.format_preferences);
+        o.set("GENERATE_THUMBNAIL_ON_STORE", &mut transform.thumbnail_on_store);
+        o.set("THUMBNAIL_SIZE", &mut transform.thumbnail_size);
+        o.set("THUMBNAIL_QUALITY", &mut transform.thumbnail_quality);
+        o.set("MAX_VARIANTS_PER_ORIGINAL", &mut transform.max_variants_per_original);
+        o.set_parsed("CONTENT_TYPE_FAMILY_OVERRIDES", &mut transform.content_type_overrides, parse_content_type_overrides)?;
+        o.set_parsed("WEBP_QUERY_QUALITY_RANGE", &mut transform.query_quality_range, parse_optional_quality_range)?;
+
+        let missing: Vec<&str> = REQUIRED_SETTINGS
+            .iter()
+            .zip(config.required_values())
+            .filter(|(_, value)| value.trim().is_empty())
+            .map(|(name, _)| *name)
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow!("Missing required settings: {}", missing.join(", ")));
+        }
+
+        config.normalize();
+        config.server.validate()?;
+        if axum::http::HeaderValue::from_str(&config.cache.cache_control).is_err() {
+            return Err(anyhow!("CACHE_CONTROL is not a valid header value"));
+        }
+        Ok(config)
+    }
+
+    // In the order of `REQUIRED_SETTINGS`
+    fn required_values(&self) -> [&str; 5] {
+        [&self.storage.endpoint, &self.storage.bucket, &self.storage.access_key, &self.storage.secret_key, &self.cache.redis_url]
+    }
+
+    // Bring values from either source into the shape the rest of the proxy expects
+    fn normalize(&mut self) {
+        for list in [
+            &mut self.server.allowed_hosts,
+            &mut self.storage.compression.content_types,
+            &mut self.cache.no_cache_content_types,
+            &mut self.transform.format_preferences,
+        ] {
+            *list = list.iter().map(|item| item.trim().to_lowercase()).filter(|item| !item.is_empty()).collect();
+        }
+        self.cache.query_key_mode = self.cache.query_key_mode.to_lowercase();
+        if self.cache.cache_control.trim().is_empty() {
+            self.cache.cache_control = default_cache_control();
+        }
+
+        // An empty value means unset, so an empty token never enables anything
+        for value in [
+            &mut self.server.timing_allow_origin,
+            &mut self.upstream.parent_proxy_url,
+            &mut self.upstream.auth_token,
+            &mut self.upstream.auth_token_url,
+            &mut self.storage.encryption.active_key,
+            &mut self.storage.compression.dictionary,
+            &mut self.cache.hex_segment_pattern,
+            &mut self.cache.artwork_id_pattern,
+            &mut self.auth.token,
+            &mut self.auth.signing_secret,
+            &mut self.admin.token,
+        ] {
+            if value.as_deref().is_some_and(|v| v.trim().is_empty()) {
+                *value = None;
+            }
+        }
+
+        // Unset cold tier and manifest source settings fall back to this deployment's own S3 settings
+        let storage = &mut self.storage;
+        storage.cold_tier = storage.cold_tier.take().filter(|cold| !cold.bucket.trim().is_empty());
+        if let Some(cold) = storage.cold_tier.as_mut() {
+            fill_empty(&mut cold.endpoint, &storage.endpoint);
+            fill_empty(&mut cold.region, &storage.region);
+            fill_empty(&mut cold.access_key, &storage.access_key);
+            fill_empty(&mut cold.secret_key, &storage.secret_key);
+            cold.storage_class = cold.storage_class.take().filter(|class| !class.trim().is_empty());
+            cold.migration_age_secs = cold.migration_age_secs.max(1);
+            cold.migration_interval_secs = cold.migration_interval_secs.max(1);
+        }
+        self.admin.manifest_source = self.admin.manifest_source.take().filter(|source| !source.bucket.trim().is_empty());
+        if let Some(source) = self.admin.manifest_source.as_mut() {
+            fill_empty(&mut source.endpoint, &storage.endpoint);
+            fill_empty(&mut source.region, &storage.region);
+            fill_empty(&mut source.access_key, &storage.access_key);
+            fill_empty(&mut source.secret_key, &storage.secret_key);
+        }
+
+        storage.crypto_read_concurrency = storage.crypto_read_concurrency.max(1);
+        self.cache.negative_resample_rate = self.cache.negative_resample_rate.clamp(0.0, 1.0);
+        self.cache.error_circuit_window_secs = self.cache.error_circuit_window_secs.max(1);
+        self.cache.error_circuit_open_secs = self.cache.error_circuit_open_secs.max(1);
+        self.admin.manifest_import_concurrency = self.admin.manifest_import_concurrency.max(1);
+        self.admin.warm_concurrency = self.admin.warm_concurrency.max(1);
+        self.transform.webp_quality = self.transform.webp_quality.clamp(0.0, 100.0);
+        self.transform.thumbnail_size = self.transform.thumbnail_size.max(1);
+        self.transform.thumbnail_quality = self.transform.thumbnail_quality.clamp(0.0, 100.0);
+    }
+}
+
+fn fill_empty(value: &mut String, fallback: &str) {
+    if value.trim().is_empty() {
+        *value = fallback.to_string();
+    }
+}
+
+// Environment variables, then `settings`, laid over a configuration. Unset settings leave a
+// value alone, and so do unparseable ones: a bad number never stops startup.
+struct Overrides<'a> {
+    settings: &'a HashMap<String, String>,
+}
+
+impl Overrides<'_> {
+    fn get(&self, name: &str) -> Option<String> {
+        env::var(name).ok().or_else(|| self.settings.get(name).cloned())
+    }
+
+    fn set<T: FromStr>(&self, name: &str, value: &mut T) {
+        if let Some(parsed) = self.get(name).and_then(|v| v.parse().ok()) {
+            *value = parsed;
+        }
+    }
+
+    fn set_optional(&self, name: &str, value: &mut Option<String>) {
+        if let Some(v) = self.get(name) {
+            *value = Some(v).filter(|v| !v.trim().is_empty());
+        }
+    }
+
+    // Comma-separated list
+    fn set_list(&self, name: &str, value: &mut Vec<String>) {
+        if let Some(v) = self.get(name) {
+            *value = v.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect();
+        }
+    }
+
+    // One entry per line
+    fn set_lines(&self, name: &str, value: &mut Vec<String>) {
+        if let Some(v) = self.get(name) {
+            *value = v.lines().map(|line| line.trim().to_string()).filter(|line| !line.is_empty()).collect();
+        }
+    }
+
+    // Settings with their own syntax, which fail startup when malformed
+    fn set_parsed<T>(&self, name: &str, value: &mut T, parse: impl Fn(&str) -> Result<T>) -> Result<()> {
+        if let Some(v) = self.get(name) {
+            *value = parse(&v)?;
+        }
+        Ok(())
+    }
+}
+
+// In a config file, rules are an array of strings, each written as in the environment variable
+fn from_file_syntax<'de, D: Deserializer<'de>, T>(deserializer: D, parse: fn(&str) -> Result<Vec<T>>) -> Result<Vec<T>, D::Error> {
+    let entries = Vec::<String>::deserialize(deserializer)?;
+    let mut parsed = Vec::new();
+    for entry in &entries {
+        parsed.extend(parse(entry).map_err(D::Error::custom)?);
+    }
+    Ok(parsed)
+}
+
+fn host_rules_from_file<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<HostRule>, D::Error> {
+    from_file_syntax(deserializer, parse_host_rules)
+}
+
+fn rewrite_rules_from_file<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<RewriteRule>, D::Error> {
+    from_file_syntax(deserializer, parse_rewrite_rules)
+}
+
+fn encryption_keys_from_file<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(String, String)>, D::Error> {
+    from_file_syntax(deserializer, parse_encryption_keys)
+}
+
+fn object_tags_from_file<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(String, String)>, D::Error> {
+    from_file_syntax(deserializer, parse_object_tags)
+}
+
+fn tag_rules_from_file<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<TagRule>, D::Error> {
+    from_file_syntax(deserializer, parse_tag_rules)
+}
+
+fn cache_control_rules_from_file<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(String, String)>, D::Error> {
+    from_file_syntax(deserializer, parse_cache_control_rules)
+}
+
+fn content_type_overrides_from_file<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(String, String)>, D::Error> {
+    from_file_syntax(deserializer, parse_content_type_overrides)
+}
+
+fn quality_range_from_file<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<(u8, u8)>, D::Error> {
+    parse_optional_quality_range(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+// Values such as "blake3" or "closed", parsed by the type's own `FromStr`
+fn from_str<'de, D: Deserializer<'de>, T: FromStr<Err = anyhow::Error>>(deserializer: D) -> Result<T, D::Error> {
+    String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+}
+
+/// Whether a response content type matches any configured pattern; `type/*` and `*`
+/// wildcards are allowed and a missing content type counts as `application/octet-stream`.
+pub fn content_type_matches(patterns: &[String], content_type: Option<&str>) -> bool {
+    let mime = content_type
+        .and_then(|ct| ct.split(';').next())
+        .map(|ct| ct.trim().to_lowercase())
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+
+    patterns.iter().any(|pattern| {
+        match pattern.strip_suffix("/*") {
+            Some(prefix) => mime.split('/').next() == Some(prefix),
+            None => pattern == "*" || *pattern == mime,
+        }
+    })
+}
+
+/// Load a TLS client identity from PEM certificate and key files.
+/// Both paths must be set together; with neither set no identity is used.
+pub fn load_client_identity(cert_path: Option<&str>, key_path: Option<&str>) -> Result<Option<reqwest::Identity>> {
+    let (cert_path, key_path) = match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) => return Ok(None),
+        _ => return Err(anyhow!("Client certificate and key must be configured together")),
+    };
+
+    let mut pem = std::fs::read(cert_path)
+        .map_err(|e| anyhow!("Failed to read client certificate {}: {}", cert_path, e))?;
+    pem.push(b'\n');
+    pem.extend(std::fs::read(key_path)
+        .map_err(|e| anyhow!("Failed to read client key {}: {}", key_path, e))?);
+
+    let identity = reqwest::Identity::from_pem(&pem)
+        .map_err(|e| anyhow!("Invalid client certificate or key ({}, {}): {}", cert_path, key_path, e))?;
+    Ok(Some(identity))
+}
+
+fn redact_url_password(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some(REDACTED));
+            parsed.to_string()
+        },
+        Ok(_) => url.to_string(),
+        // Unparseable URLs might still embed credentials, so hide them entirely
+        Err(_) => REDACTED.to_string(),
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+impl Config {
+    /// Defaults plus `settings`, with placeholders for the required settings not given.
+    pub fn for_tests(settings: &[(&str, &str)]) -> Self {
+        let mut values: HashMap<String, String> = REQUIRED_SETTINGS.iter().map(|name| (name.to_string(), "test".to_string())).collect();
+        values.extend(settings.iter().map(|(name, value)| (name.to_string(), value.to_string())));
+        Self::from_source(Config::default(), &values).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG_FILE: &str = r#"
+        [server]
+        allowed_hosts = ["IMG.example.com", "img.example.com:8443"]
+
+        [upstream]
+        rewrite_rules = ["^/old/(.*)$ => /img-original/$1", "^/legacy/(.*)$ => /c/$1"]
+
+        [storage]
+        endpoint = "http://minio:9000"
+        bucket = "pixiv"
+        region = "eu-central-1"
+        access_key = "access"
+        secret_key = "secret"
+
+        [storage.cold_tier]
+        bucket = "pixiv-cold"
+        migration_age_secs = 0
+
+        [cache]
+        redis_url = "redis://redis:6379"
+        not_found_ttl = 3600
+        content_hash_algo = "xxh3"
+        cache_control_rules = ["image/webp => public, max-age=31536000, immutable"]
+    "#;
+
+    fn file_config(name: &str, contents: &str) -> Result<Config> {
+        let path = std::env::temp_dir().join(format!("pxip-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let config = Config::from_file(&path.to_string_lossy());
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn config_file_tables_fill_each_section() {
+        let config = file_config("tables", CONFIG_FILE).unwrap();
+
+        assert_eq!(config.server.allowed_hosts, ["img.example.com", "img.example.com:8443"]);
+        let rules: Vec<_> = config.upstream.rewrite_rules.iter().map(|rule| (rule.pattern.as_str(), rule.replacement.as_str())).collect();
+        assert_eq!(rules, [("^/old/(.*)$", "/img-original/$1"), ("^/legacy/(.*)$", "/c/$1")]);
+        assert_eq!(config.storage.bucket, "pixiv");
+        assert_eq!(config.cache.not_found_ttl, 3600);
+        assert_eq!(config.cache.content_hash_algo, HashAlgorithm::Xxh3);
+        assert_eq!(config.cache.cache_control_for("image/webp"), "public, max-age=31536000, immutable");
+        // Keys left out keep their defaults
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.cache.server_error_ttl, 1200);
+
+        // The cold tier shares whatever it does not set with the main bucket
+        let cold = config.storage.cold_tier.unwrap();
+        assert_eq!((cold.bucket.as_str(), cold.endpoint.as_str(), cold.region.as_str()), ("pixiv-cold", "http://minio:9000", "eu-central-1"));
+        assert_eq!(cold.migration_age_secs, 1);
+    }
+
+    #[test]
+    fn settings_take_precedence_over_the_config_file() {
+        let file: Config = toml::from_str(CONFIG_FILE).unwrap();
+        let settings = HashMap::from([
+            ("S3_BUCKET".to_string(), "override".to_string()),
+            ("CACHE_404_TTL".to_string(), "60".to_string()),
+            ("ALLOWED_HOSTS".to_string(), String::new()),
+        ]);
+        let config = Config::from_source(file, &settings).unwrap();
+
+        assert_eq!(config.storage.bucket, "override");
+        assert_eq!(config.cache.not_found_ttl, 60);
+        assert!(config.server.allowed_hosts.is_empty());
+        assert_eq!(config.storage.endpoint, "http://minio:9000");
+    }
+
+    #[test]
+    fn config_files_with_unknown_keys_or_bad_rules_are_rejected() {
+        let misspelled = CONFIG_FILE.replace("not_found_ttl", "not_found_tll");
+        assert!(file_config("misspelled", &misspelled).unwrap_err().to_string().contains("not_found_tll"));
+
+        let bad_rule = CONFIG_FILE.replace("image/webp => ", "image/webp ");
+        assert!(file_config("bad-rule", &bad_rule).unwrap_err().to_string().contains("Invalid Cache-Control rule"));
+    }
+
+    #[test]
+    fn config_files_must_set_the_required_settings() {
+        let without_bucket = CONFIG_FILE.replace("bucket = \"pixiv\"", "");
+        let error = file_config("without-bucket", &without_bucket).unwrap_err().to_string();
+        assert_eq!(error, "Missing required settings: S3_BUCKET");
+    }
+
+    #[test]
+    fn cache_control_resolves_to_the_first_matching_rule() {
+        let rules = "image/webp => public, max-age=31536000, immutable\n\
+                     image/* => public, max-age=86400\n\
+                     IMAGE/PNG => no-store";
+        let cache = Config::for_tests(&[("CACHE_CONTROL", "public, max-age=60"), ("CACHE_CONTROL_RULES", rules)]).cache;
+
+        assert_eq!(cache.cache_control_for("image/webp"), "public, max-age=31536000, immutable");
+        // Parameters and case do not affect matching
+        assert_eq!(cache.cache_control_for("Image/WebP; charset=binary"), "public, max-age=31536000, immutable");
+        // The wildcard comes first, so the later PNG rule never applies
+        assert_eq!(cache.cache_control_for("image/png"), "public, max-age=86400");
+        assert_eq!(cache.cache_control_for("application/zip"), "public, max-age=60");
+
+        let unruled = Config::for_tests(&[]).cache;
+        assert_eq!(unruled.cache_control_for("image/webp"), default_cache_control());
+    }
+
+    #[test]
+    fn malformed_cache_control_rules_are_rejected() {
+        for rules in ["image/webp", "=> public", "image/webp =>", "image/webp => bad\u{7f}value"] {
+            assert!(parse_cache_control_rules(rules).is_err(), "{:?}", rules);
+        }
+        assert_eq!(parse_cache_control_rules("\n  * => no-cache \n").unwrap(), [("*".to_string(), "no-cache".to_string())]);
+    }
+}
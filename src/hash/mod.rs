@@ -0,0 +1,41 @@
+use anyhow::{Result, anyhow};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use xxhash_rust::xxh3::xxh3_128;
+
+/// Algorithm behind the `ETag` of image responses: every buffered body is hashed whole when
+/// served, and every stored object when it is written (see `CONTENT_HASH_HEADER`).
+///
+/// Changing it changes every hash, so existing ETags and the hashes recorded on stored
+/// objects no longer match the ones computed for buffered responses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    #[default]
+    Blake3,
+    Sha256,
+    Xxh3,
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "sha256" | "sha-256" => Ok(HashAlgorithm::Sha256),
+            "xxh3" | "xxhash" => Ok(HashAlgorithm::Xxh3),
+            _ => Err(anyhow!("Unsupported content hash algorithm: {}", name)),
+        }
+    }
+}
+
+impl HashAlgorithm {
+    /// Lowercase hex digest of `data`.
+    pub fn digest(&self, data: &[u8]) -> String {
+        match self {
+            HashAlgorithm::Blake3 => blake3::hash(data).to_hex().to_string(),
+            HashAlgorithm::Sha256 => format!("{:x}", Sha256::digest(data)),
+            HashAlgorithm::Xxh3 => format!("{:032x}", xxh3_128(data)),
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    Json,
+};
+use reqwest::Client as HttpClient;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{error, info, warn};
+
+use crate::{
+    cache::KVStore,
+    config::{CacheConfig, HealthConfig, UpstreamConfig},
+    proxy::ProxyState,
+    storage::S3Storage,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamHealth {
+    pub healthy: bool,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub checked_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RedisHealth {
+    pub healthy: bool,
+    pub required: bool, // Some feature fails closed, so an outage makes the proxy unready
+    pub error: Option<String>,
+    pub checked_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub upstreams: HashMap<String, UpstreamHealth>,
+    pub redis: Option<RedisHealth>,
+}
+
+/// Result of a `/healthz` check: `ok` or the error of each dependency.
+#[derive(Debug, Clone, Serialize)]
+pub struct LivenessReport {
+    pub s3: String,
+    pub redis: String,
+}
+
+impl LivenessReport {
+    pub fn healthy(&self) -> bool {
+        self.s3 == "ok" && self.redis == "ok"
+    }
+}
+
+#[derive(Clone)]
+pub struct HealthChecker {
+    client: HttpClient,
+    config: HealthConfig,
+    referer: String,
+    hosts: Vec<String>,
+    upstreams: Arc<RwLock<HashMap<String, UpstreamHealth>>>,
+    cache: KVStore,
+    cache_config: CacheConfig,
+    redis: Arc<RwLock<Option<RedisHealth>>>,
+    storage: S3Storage,
+    liveness: Arc<Mutex<Option<(Instant, LivenessReport)>>>, // Last passing /healthz check
+}
+
+impl HealthChecker {
+    pub fn new(
+        client: HttpClient,
+        upstream: &UpstreamConfig,
+        config: HealthConfig,
+        cache: KVStore,
+        cache_config: CacheConfig,
+        storage: S3Storage,
+    ) -> Self {
+        Self {
+            client,
+            config,
+            referer: upstream.referer.clone(),
+            hosts: upstream.all_hosts(),
+            upstreams: Arc::new(RwLock::new(HashMap::new())),
+            cache,
+            cache_config,
+            redis: Arc::new(RwLock::new(None)),
+            storage,
+            liveness: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Probe all upstream hosts and Redis on the configured interval until the process exits.
+    pub fn spawn(&self) {
+        let checker = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(checker.config.interval));
+            loop {
+                interval.tick().await;
+                checker.check_upstreams().await;
+                checker.check_redis().await;
+            }
+        });
+    }
+
+    pub async fn check_upstreams(&self) {
+        for host in &self.hosts {
+            let health = self.probe_upstream(host).await;
+            if !health.healthy {
+                warn!("Upstream {} is unhealthy: {:?} {:?}", host, health.status, health.error);
+            }
+
+            let previous = self.upstreams.write().await.insert(host.clone(), health.clone());
+            if let Some(previous) = previous
+                && previous.healthy != health.healthy
+            {
+                info!("Upstream {} health changed to {}", host, if health.healthy { "healthy" } else { "unhealthy" });
+            }
+        }
+    }
+
+    pub async fn check_redis(&self) {
+        let result = tokio::time::timeout(Duration::from_secs(self.config.timeout), self.cache.ping()).await;
+        let error = match result {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some(format!("Redis PING timed out after {}s", self.config.timeout)),
+        };
+        let health = RedisHealth {
+            healthy: error.is_none(),
+            required: self.cache_config.requires_redis(),
+            error,
+            checked_at: unix_now(),
+        };
+
+        let previous = self.redis.write().await.replace(health.clone());
+        let was_healthy = previous.is_none_or(|previous| previous.healthy);
+        if was_healthy && !health.healthy {
+            error!(
+                "Redis is unreachable ({}); negative cache lookups fail {:?}, S3 stores fail {:?}, \
+                 rate limiting fails {:?}, burst cache, fetch coalescing and variant limits are bypassed",
+                health.error.as_deref().unwrap_or_default(),
+                self.cache_config.outage_negative_cache,
+                self.cache_config.outage_store_lock,
+                self.cache_config.outage_rate_limit,
+            );
+        } else if !was_healthy && health.healthy {
+            info!("Redis is reachable again");
+        }
+    }
+
+    async fn probe_upstream(&self, host: &str) -> UpstreamHealth {
+        let url = format!("{}{}", host, self.config.upstream_probe_path);
+        let result = self.client
+            .head(&url)
+            .header("Referer", &self.referer)
+            .timeout(Duration::from_secs(self.config.timeout))
+            .send()
+            .await;
+
+        match result {
+            Ok(response) => {
+                let status = response.status().as_u16();
+                UpstreamHealth {
+                    healthy: self.is_expected_status(status),
+                    status: Some(status),
+                    error: None,
+                    checked_at: unix_now(),
+                }
+            },
+            Err(e) => UpstreamHealth {
+                healthy: false,
+                status: None,
+                error: Some(e.to_string()),
+                checked_at: unix_now(),
+            },
+        }
+    }
+
+    // With no explicit expectation, any response below 500 means the host is reachable
+    fn is_expected_status(&self, status: u16) -> bool {
+        if self.config.upstream_probe_statuses.is_empty() {
+            status < 500
+        } else {
+            self.config.upstream_probe_statuses.contains(&status)
+        }
+    }
+
+    /// Check that S3 and Redis are reachable. A passing result is reused for
+    /// HEALTHZ_CACHE_SECS, and concurrent probes wait for the check in flight,
+    /// so frequent probes cost at most one S3 HEAD and one PING per period.
+    pub async fn liveness(&self) -> LivenessReport {
+        let mut last = self.liveness.lock().await;
+        if let Some((checked, report)) = last.as_ref()
+            && checked.elapsed() < Duration::from_secs(self.config.healthz_cache_secs)
+        {
+            return report.clone();
+        }
+
+        let timeout = Duration::from_secs(self.config.timeout);
+        let (s3, redis) = tokio::join!(
+            tokio::time::timeout(timeout, self.storage.check_bucket_exists()),
+            tokio::time::timeout(timeout, self.cache.ping()),
+        );
+        let report = LivenessReport {
+            s3: match s3 {
+                Ok(Ok(true)) => "ok".to_string(),
+                Ok(Ok(false)) => "bucket not found".to_string(),
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => format!("S3 check timed out after {}s", self.config.timeout),
+            },
+            redis: match redis {
+                Ok(Ok(())) => "ok".to_string(),
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => format!("Redis PING timed out after {}s", self.config.timeout),
+            },
+        };
+
+        if report.healthy() {
+            *last = Some((Instant::now(), report.clone()));
+        } else {
+            warn!("Health check failed: S3 {}, Redis {}", report.s3, report.redis);
+            *last = None;
+        }
+        report
+    }
+
+    pub async fn report(&self) -> ReadinessReport {
+        let upstreams = self.upstreams.read().await.clone();
+        // Ready as long as at least one upstream answered the last probe as expected
+        // and Redis is reachable whenever an outage would make features fail closed
+        let redis = self.redis.read().await.clone();
+        let ready = upstreams.values().any(|health| health.healthy)
+            && redis.as_ref().is_none_or(|redis| redis.healthy || !redis.required);
+
+        ReadinessReport { ready, upstreams, redis }
+    }
+}
+
+pub async fn readiness_handler(
+    State(state): State<ProxyState>,
+) -> (StatusCode, Json<ReadinessReport>) {
+    let report = state.health.report().await;
+    let status = if report.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(report))
+}
+
+/// Liveness probe: 200 while both S3 and Redis answer, 503 naming the failing one otherwise.
+pub async fn healthz_handler(
+    State(state): State<ProxyState>,
+) -> (StatusCode, Json<LivenessReport>) {
+    let report = state.health.liveness().await;
+    let status = if report.healthy() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(report))
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use tokio::net::TcpListener;
+
+    use crate::{cache::testing::FakeRedis, config::Config, storage::testing::FakeS3};
+
+    // Readiness after a Redis outage, with an upstream answering every probe
+    async fn ready_during_outage(settings: &[(&str, &str)]) -> (bool, bool) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, Router::new().fallback(|| async { StatusCode::OK })).await;
+        });
+        let (s3, redis) = (FakeS3::start().await, FakeRedis::start().await);
+        let mut all = vec![("UPSTREAM_HOST", upstream.as_str()), ("REDIS_URL", redis.url())];
+        all.extend_from_slice(settings);
+        let config = Config::for_tests(&s3.settings(&all));
+
+        let checker = HealthChecker::new(
+            HttpClient::new(),
+            &config.upstream,
+            config.health.clone(),
+            KVStore::new(&config.cache).await.unwrap(),
+            config.cache.clone(),
+            S3Storage::new(&config.storage).await.unwrap(),
+        );
+        checker.check_upstreams().await;
+        checker.check_redis().await;
+        assert!(checker.report().await.ready);
+
+        redis.stop();
+        checker.check_redis().await;
+        let report = checker.report().await;
+        let redis = report.redis.unwrap();
+        assert!(!redis.healthy);
+        (report.ready, redis.required)
+    }
+
+    #[tokio::test]
+    async fn redis_outage_makes_unready_only_when_a_policy_fails_closed() {
+        assert_eq!(ready_during_outage(&[]).await, (true, false));
+        for policy in ["REDIS_OUTAGE_NEGATIVE_CACHE", "REDIS_OUTAGE_STORE_LOCK", "REDIS_OUTAGE_RATE_LIMIT"] {
+            assert_eq!(ready_during_outage(&[(policy, "open")]).await, (true, false), "{}", policy);
+            assert_eq!(ready_during_outage(&[(policy, "closed")]).await, (false, true), "{}", policy);
+        }
+    }
+}
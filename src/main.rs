@@ -2,27 +2,48 @@ mod config;
 mod storage;
 mod cache;
 mod proxy;
+mod health;
+mod admin;
+mod stats;
+mod transform;
+mod hash;
+mod telemetry;
+mod signing;
 pub mod crypto;
 
 use axum::{
-    routing::get,
+    middleware,
+    routing::{delete, get, post},
     Router,
 };
-use axum_server::tls_rustls::RustlsConfig;
+use axum_server::{Handle, tls_rustls::RustlsConfig};
+use hyper_util::{
+    rt::{TokioExecutor, TokioTimer},
+    server::conn::auto::Builder,
+};
 use reqwest::Client as HttpClient;
 use tower::ServiceBuilder;
 use tower_http::{
     cors::CorsLayer,
     trace::TraceLayer,
 };
-use tracing::{info, error};
+use tracing::{info, error, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use anyhow::Result;
+use std::{net::SocketAddr, time::Duration};
 
-use config::Config;
-use storage::S3Storage;
+use config::{Config, ServerConfig, load_client_identity};
+use storage::{ColdTier, S3Storage};
 use cache::KVStore;
-use proxy::{ProxyState, proxy_handler};
+use proxy::{BackgroundUploads, InFlightFetches, MemoryCache, PathRewriter, PathTemplates, ProxyState, RecentWrites, UpstreamAuth, auth_guard, host_guard, rate_limit_guard, proxy_handler, index_handler, options_handler};
+use health::{HealthChecker, healthz_handler, readiness_handler};
+use admin::{
+    bench_handler, export_manifest_handler, hot_paths_handler, import_manifest_handler,
+    purge_artwork_handler, purge_handler, reset_hot_paths_handler, sign_handler, stats_handler, verify_handler,
+    warm_handler,
+};
+use stats::{HotPaths, StatsCollector};
+use telemetry::metrics_handler;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -37,18 +58,30 @@ async fn main() -> Result<()> {
 
     info!("Starting Pixiv Image Proxy Server");
 
-    // Load configuration
-    let config = Config::from_env().map_err(|e| {
+    // Load configuration, from CONFIG_FILE when set with the environment taking precedence
+    let config = match std::env::var("CONFIG_FILE").ok().filter(|path| !path.is_empty()) {
+        Some(path) => {
+            info!("Loading configuration from {}", path);
+            Config::from_file(&path)
+        },
+        None => Config::from_env(),
+    };
+    let config = config.map_err(|e| {
         error!("Failed to load configuration: {}", e);
         e
     })?;
 
     info!("Configuration loaded successfully");
+
+    // Install the recorder before anything records metrics
+    let metrics = if config.server.metrics_enabled {
+        Some(telemetry::install().inspect_err(|e| error!("Failed to set up metrics: {}", e))?)
+    } else {
+        None
+    };
+    info!("Effective configuration:\n{}", config.redacted_debug());
     info!("Server will listen on {}:{}", config.server.host, config.server.port);
     info!("SSL mode: {}", if config.server.cert_path.is_some() && config.server.key_path.is_some() { "HTTPS" } else { "HTTP" });
-    info!("Upstream host: {}", config.upstream.host);
-    info!("S3 endpoint: {}", config.storage.endpoint);
-    info!("S3 bucket: {}", config.storage.bucket);
 
     // Initialize S3 storage
     let storage = S3Storage::new(&config.storage).await.map_err(|e| {
@@ -63,28 +96,110 @@ async fn main() -> Result<()> {
         e
     })?;
     info!("KV store initialized successfully");
+    cache.spawn_negative_resample();
+
+    // Move objects nobody has read in a while to the cold tier
+    let cold_tier = match &config.storage.cold_tier {
+        Some(cold_config) => {
+            let cold_tier = ColdTier::new(&storage, cold_config)
+                .inspect_err(|e| error!("Invalid cold tier configuration: {}", e))?;
+            if cold_tier.storage().bucket_name() != storage.bucket_name() {
+                cold_tier.storage().ensure_bucket_exists().await
+                    .inspect_err(|e| error!("Failed to ensure cold tier bucket exists: {}", e))?;
+            }
+            cold_tier.spawn_migration(storage.clone(), cache.clone());
+            info!("Cold tier enabled in bucket '{}'", cold_config.bucket);
+            Some(cold_tier)
+        },
+        None => None,
+    };
 
     // Initialize HTTP client for upstream requests
-    let http_client = HttpClient::builder()
+    let mut http_client_builder = HttpClient::builder()
         .timeout(std::time::Duration::from_secs(30))
-        .user_agent("pixiv-image-proxy/1.0")
+        .user_agent("pixiv-image-proxy/1.0");
+    if let Some(identity) = load_client_identity(
+        config.upstream.client_cert.as_deref(),
+        config.upstream.client_key.as_deref(),
+    ).inspect_err(|e| error!("Failed to load upstream client certificate: {}", e))? {
+        info!("Using TLS client certificate for upstream requests");
+        http_client_builder = http_client_builder.identity(identity);
+    }
+    let http_client = http_client_builder
         .build()
         .map_err(|e| {
             error!("Failed to create HTTP client: {}", e);
             anyhow::anyhow!("Failed to create HTTP client: {}", e)
         })?;
 
+    // Fetch the upstream auth token before serving, then keep it fresh
+    let upstream_auth = UpstreamAuth::new(http_client.clone(), &config.upstream)
+        .inspect_err(|e| error!("Invalid upstream auth configuration: {}", e))?;
+    if upstream_auth.refreshable() {
+        if let Err(e) = upstream_auth.refresh().await {
+            warn!("Failed to fetch the initial upstream auth token: {}", e);
+        }
+        upstream_auth.spawn();
+    }
+
+    // Start periodic upstream health probes for readiness reporting
+    let health = HealthChecker::new(
+        http_client.clone(),
+        &config.upstream,
+        config.health.clone(),
+        cache.clone(),
+        config.cache.clone(),
+        storage.clone(),
+    );
+    health.spawn();
+
+    // Sample the cache key space size in the background
+    let stats = StatsCollector::new(config.stats.clone(), storage.clone(), cache.clone());
+    stats.spawn();
+    stats.spawn_flush();
+    let shutdown_stats = stats.clone();
+
     // Create proxy state
     let state = ProxyState {
         config: config.clone(),
         storage,
         cache,
         http_client,
+        health,
+        stats,
+        cold_tier,
+        recent_writes: RecentWrites::new(config.storage.consistency_grace_ms),
+        uploads: BackgroundUploads::new(config.storage.max_background_uploads),
+        in_flight: InFlightFetches::default(),
+        memory_cache: MemoryCache::new(config.cache.memory_cache_mb),
+        upstream_auth,
+        rewriter: PathRewriter::new(&config.upstream.rewrite_rules, config.cache.hex_segment_pattern.as_deref())
+            .inspect_err(|e| error!("Failed to compile path rewrite rules: {}", e))?,
+        path_templates: PathTemplates::new(config.upstream.path_templates_enabled, &config.upstream.path_templates, &config.upstream.path_patterns)
+            .inspect_err(|e| error!("Failed to compile path templates: {}", e))?,
+        hot_paths: HotPaths::new(config.stats.hot_paths_top_n, config.stats.hot_paths_window),
+        metrics,
     };
 
     // Build the router
     let app = Router::new()
-        .route("/{*path}", get(proxy_handler))
+        .route("/", get(index_handler))
+        .route("/readyz", get(readiness_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/admin/stats", get(stats_handler))
+        .route("/admin/hot", get(hot_paths_handler).delete(reset_hot_paths_handler))
+        .route("/admin/bench/{size}", get(bench_handler))
+        .route("/admin/verify/{*path}", get(verify_handler))
+        .route("/admin/purge/{*path}", delete(purge_handler))
+        .route("/admin/purge-artwork/{id}", post(purge_artwork_handler))
+        .route("/admin/sign/{*path}", get(sign_handler))
+        .route("/admin/manifest", get(export_manifest_handler).post(import_manifest_handler))
+        .route("/admin/warm", post(warm_handler))
+        .route("/{*path}", get(proxy_handler).options(options_handler))
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit_guard))
+        .layer(middleware::from_fn_with_state(state.clone(), auth_guard))
+        .layer(middleware::from_fn_with_state(state.clone(), host_guard))
         .layer(
             ServiceBuilder::new()
                 .layer(TraceLayer::new_for_http())
@@ -95,6 +210,15 @@ async fn main() -> Result<()> {
     // Start the server
     let addr = format!("{}:{}", config.server.host, config.server.port);
 
+    // Stop accepting connections on SIGINT/SIGTERM and let in-flight requests finish
+    let handle = Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutting down");
+        shutdown_handle.graceful_shutdown(Some(SHUTDOWN_GRACE));
+    });
+
     // Check if SSL certificates are provided
     match (&config.server.cert_path, &config.server.key_path) {
         (Some(cert_path), Some(key_path)) => {
@@ -109,8 +233,11 @@ async fn main() -> Result<()> {
             info!("TLS configuration loaded successfully");
             info!("Server starting on https://{}", addr);
 
-            axum_server::bind_rustls(addr.parse()?, tls_config)
-                .serve(app.into_make_service())
+            let mut server = axum_server::bind_rustls(addr.parse()?, tls_config);
+            configure_connections(server.http_builder(), &config.server);
+            server
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
                 .await
                 .map_err(|e| {
                     error!("Server error: {}", e);
@@ -122,13 +249,16 @@ async fn main() -> Result<()> {
             info!("SSL certificates not provided, starting HTTP server");
             info!("Server starting on http://{}", addr);
 
-            let listener = tokio::net::TcpListener::bind(&addr).await
-                .map_err(|e| {
-                    error!("Failed to bind to address {}: {}", addr, e);
-                    anyhow::anyhow!("Failed to bind to address: {}", e)
-                })?;
+            let addr = addr.parse().map_err(|e| {
+                error!("Invalid listen address {}: {}", addr, e);
+                anyhow::anyhow!("Invalid listen address: {}", e)
+            })?;
 
-            axum::serve(listener, app)
+            let mut server = axum_server::bind(addr);
+            configure_connections(server.http_builder(), &config.server);
+            server
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
                 .await
                 .map_err(|e| {
                     error!("Server error: {}", e);
@@ -137,5 +267,59 @@ async fn main() -> Result<()> {
         }
     }
 
+    // Counts since the last periodic flush would otherwise be lost
+    shutdown_stats.flush().await;
+
     Ok(())
+}
+
+// How long in-flight requests get to finish once shutdown starts
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            },
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// Apply connection-level tuning shared by the HTTP and HTTPS servers
+fn configure_connections(builder: &mut Builder<TokioExecutor>, config: &ServerConfig) {
+    let header_read_timeout = (config.header_read_timeout_secs > 0)
+        .then(|| Duration::from_secs(config.header_read_timeout_secs));
+    let keep_alive_interval = (config.http2_keep_alive_interval_secs > 0)
+        .then(|| Duration::from_secs(config.http2_keep_alive_interval_secs));
+
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .keep_alive(config.keep_alive)
+        .header_read_timeout(header_read_timeout);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(config.http2_max_concurrent_streams)
+        .keep_alive_interval(keep_alive_interval);
 }
\ No newline at end of file
@@ -0,0 +1,128 @@
+use anyhow::{Result, anyhow};
+use axum::http::{HeaderName, HeaderValue};
+use reqwest::Client as HttpClient;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::config::UpstreamConfig;
+
+#[derive(Clone)]
+enum TokenSource {
+    None,
+    Static(HeaderValue),
+    // Token fetched from an auth endpoint, cached until the next refresh
+    Endpoint {
+        url: String,
+        refresh: Duration,
+        token: Arc<RwLock<Option<HeaderValue>>>,
+    },
+}
+
+/// Credentials sent with every upstream request, for mirrors that require them.
+#[derive(Clone)]
+pub struct UpstreamAuth {
+    client: HttpClient,
+    header: HeaderName,
+    prefix: String,
+    source: TokenSource,
+}
+
+impl UpstreamAuth {
+    pub fn new(client: HttpClient, config: &UpstreamConfig) -> Result<Self> {
+        let header = HeaderName::from_bytes(config.auth_header.as_bytes())
+            .map_err(|e| anyhow!("Invalid UPSTREAM_AUTH_HEADER '{}': {}", config.auth_header, e))?;
+
+        let source = match (&config.auth_token, &config.auth_token_url) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow!("UPSTREAM_AUTH_TOKEN and UPSTREAM_AUTH_TOKEN_URL are mutually exclusive"));
+            },
+            (Some(token), None) => {
+                let value = sensitive_value(&config.auth_prefix, token)
+                    .ok_or_else(|| anyhow!("UPSTREAM_AUTH_TOKEN is not a valid header value"))?;
+                TokenSource::Static(value)
+            },
+            (None, Some(url)) => {
+                let parsed = reqwest::Url::parse(url)
+                    .map_err(|e| anyhow!("Invalid UPSTREAM_AUTH_TOKEN_URL '{}': {}", url, e))?;
+                if !matches!(parsed.scheme(), "http" | "https") {
+                    return Err(anyhow!("UPSTREAM_AUTH_TOKEN_URL must be an http(s) URL"));
+                }
+                if config.auth_refresh_secs == 0 {
+                    return Err(anyhow!("UPSTREAM_AUTH_REFRESH_SECS must be at least 1"));
+                }
+                TokenSource::Endpoint {
+                    url: url.clone(),
+                    refresh: Duration::from_secs(config.auth_refresh_secs),
+                    token: Arc::new(RwLock::new(None)),
+                }
+            },
+            (None, None) => TokenSource::None,
+        };
+
+        Ok(Self { client, header, prefix: config.auth_prefix.clone(), source })
+    }
+
+    /// Whether the token can be fetched again, e.g. after upstream rejects it.
+    pub fn refreshable(&self) -> bool {
+        matches!(self.source, TokenSource::Endpoint { .. })
+    }
+
+    /// Refresh an endpoint token on its schedule until the process exits.
+    pub fn spawn(&self) {
+        let TokenSource::Endpoint { refresh, .. } = &self.source else {
+            return;
+        };
+
+        let auth = self.clone();
+        let refresh = *refresh;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(refresh);
+            loop {
+                interval.tick().await;
+                if let Err(e) = auth.refresh().await {
+                    warn!("Failed to refresh upstream auth token: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Fetch a fresh token from the auth endpoint. The response body, trimmed, is the token.
+    pub async fn refresh(&self) -> Result<()> {
+        let TokenSource::Endpoint { url, token, .. } = &self.source else {
+            return Ok(());
+        };
+
+        let response = self.client.get(url).send().await
+            .map_err(|e| anyhow!("Auth endpoint request failed: {}", e))?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Auth endpoint returned status {}", response.status().as_u16()));
+        }
+
+        let body = response.text().await
+            .map_err(|e| anyhow!("Failed to read auth endpoint response: {}", e))?;
+        let value = sensitive_value(&self.prefix, body.trim())
+            .ok_or_else(|| anyhow!("Auth endpoint returned a token that is not a valid header value"))?;
+
+        *token.write().await = Some(value);
+        info!("Refreshed upstream auth token");
+        Ok(())
+    }
+
+    /// Header to add to an upstream request, if any credentials are available.
+    pub async fn header(&self) -> Option<(HeaderName, HeaderValue)> {
+        let value = match &self.source {
+            TokenSource::None => return None,
+            TokenSource::Static(value) => value.clone(),
+            TokenSource::Endpoint { token, .. } => token.read().await.clone()?,
+        };
+        Some((self.header.clone(), value))
+    }
+}
+
+// Sensitive values are left out of the upstream request debug log
+fn sensitive_value(prefix: &str, token: &str) -> Option<HeaderValue> {
+    let mut value = HeaderValue::from_str(&format!("{}{}", prefix, token)).ok()?;
+    value.set_sensitive(true);
+    Some(value)
+}
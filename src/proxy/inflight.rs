@@ -0,0 +1,77 @@
+use axum::http::StatusCode;
+use bytes::Bytes;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+
+/// What the request that fetched a key learned, handed to the requests that waited for it.
+#[derive(Debug, Clone)]
+pub enum SharedFetch {
+    Image(Bytes, Option<String>), // Original bytes and the content type upstream declared
+    NotFound,
+    Failed(StatusCode, String),
+}
+
+type Outcome = watch::Receiver<Option<SharedFetch>>;
+
+/// Upstream fetches in flight on this instance, so concurrent misses for one key make a
+/// single upstream request. The Redis fetch lock does the same across instances, but only
+/// after a round trip and by polling S3; within an instance waiters get the bytes directly.
+#[derive(Clone, Default)]
+pub struct InFlightFetches {
+    entries: Arc<Mutex<HashMap<String, Outcome>>>,
+}
+
+/// Either the fetch to perform and publish, or the outcome of someone else's.
+pub enum Joined {
+    Leader(Flight),
+    Waiter(Outcome),
+}
+
+impl InFlightFetches {
+    pub fn join(&self, key: &str) -> Joined {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(outcome) = entries.get(key) {
+            return Joined::Waiter(outcome.clone());
+        }
+
+        let (sender, outcome) = watch::channel(None);
+        entries.insert(key.to_string(), outcome);
+        Joined::Leader(Flight {
+            key: key.to_string(),
+            sender,
+            entries: self.entries.clone(),
+        })
+    }
+}
+
+/// The outcome published by the leader, or `None` when it gave up without one (e.g. after
+/// a negative cache hit), in which case the waiter handles the request itself.
+pub async fn wait(mut outcome: Outcome) -> Option<SharedFetch> {
+    outcome.wait_for(Option::is_some).await.ok().and_then(|outcome| outcome.clone())
+}
+
+/// The fetch this request leads. Dropping it, published or not, releases the waiters and
+/// removes the entry, so a later miss starts a new fetch.
+pub struct Flight {
+    key: String,
+    sender: watch::Sender<Option<SharedFetch>>,
+    entries: Arc<Mutex<HashMap<String, Outcome>>>,
+}
+
+impl Flight {
+    pub fn publish(self, outcome: SharedFetch) {
+        self.sender.send_replace(Some(outcome));
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.get(&self.key).is_some_and(|outcome| outcome.same_channel(&self.sender.subscribe())) {
+            entries.remove(&self.key);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use bytes::Bytes;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, (Bytes, u64)>, // Bytes and the tick of their last use
+    order: BTreeMap<u64, String>,           // Last-use tick -> key, least recent first
+    tick: u64,
+    bytes: usize,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &str) -> Option<Bytes> {
+        let (data, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        self.tick += 1;
+        *used = self.tick;
+        self.order.insert(self.tick, key.to_string());
+        Some(data.clone())
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((data, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+            self.bytes -= data.len();
+        }
+    }
+}
+
+/// Decoded image bytes of recently served objects, bounded by their total size.
+///
+/// The S3 pipeline has already decrypted and decompressed what is kept here, so hot
+/// objects are served without an S3 round trip or another pass through the pipeline.
+#[derive(Clone)]
+pub struct MemoryCache {
+    capacity: usize,
+    lru: Arc<Mutex<Lru>>,
+}
+
+impl MemoryCache {
+    pub fn new(capacity_mb: u64) -> Self {
+        Self {
+            capacity: usize::try_from(capacity_mb.saturating_mul(1024 * 1024)).unwrap_or(usize::MAX),
+            lru: Arc::new(Mutex::new(Lru::default())),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        if self.capacity == 0 {
+            return None;
+        }
+        self.lru.lock().unwrap_or_else(|e| e.into_inner()).touch(key)
+    }
+
+    /// Keep `data` for `key`, evicting the least recently used entries to make room.
+    /// Objects larger than the whole cache are not kept.
+    pub fn insert(&self, key: &str, data: Bytes) {
+        if data.len() > self.capacity {
+            return;
+        }
+
+        let mut lru = self.lru.lock().unwrap_or_else(|e| e.into_inner());
+        lru.remove(key);
+        while lru.bytes + data.len() > self.capacity {
+            let Some((_, oldest)) = lru.order.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = lru.entries.remove(&oldest) {
+                lru.bytes -= evicted.len();
+            }
+        }
+
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.bytes += data.len();
+        lru.order.insert(tick, key.to_string());
+        lru.entries.insert(key.to_string(), (data, tick));
+    }
+
+    /// Forget `key`, e.g. after its stored copy was deleted.
+    pub fn remove(&self, key: &str) {
+        if self.capacity > 0 {
+            self.lru.lock().unwrap_or_else(|e| e.into_inner()).remove(key);
+        }
+    }
+}
@@ -1,21 +1,74 @@
+mod auth;
+mod inflight;
+mod memory;
+mod range;
+mod recent;
+mod rewrite;
+mod templates;
+mod uploads;
+
+pub use auth::UpstreamAuth;
+pub use inflight::InFlightFetches;
+pub use memory::MemoryCache;
+pub use recent::RecentWrites;
+pub use rewrite::PathRewriter;
+pub use templates::PathTemplates;
+pub use uploads::BackgroundUploads;
+
+use inflight::{Flight, Joined, SharedFetch};
+use range::{ByteRange, if_range_matches};
+use uploads::UploadRejected;
+
 use axum::{
-    extract::{Path, State},
-    http::{StatusCode, header},
-    response::Response,
+    extract::{ConnectInfo, Path, Query, RawQuery, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header, uri::Authority},
+    middleware::Next,
+    response::{IntoResponse, Response},
     body::Body,
 };
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use image::{ImageFormat, ImageReader};
+use metrics_exporter_prometheus::PrometheusHandle;
 use reqwest::Client as HttpClient;
-use anyhow::Result;
-use tracing::{info, error, warn};
+use serde::{Deserialize, Serialize};
+use anyhow::{Result, anyhow};
+use subtle::ConstantTimeEq;
+use std::{
+    future::Future,
+    io::Cursor,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{info, debug, error, warn};
 use tokio::spawn;
 
 use crate::{
-    config::{Config, UpstreamConfig},
-    storage::S3Storage,
-    cache::KVStore,
+    config::{CacheConfig, Config, OutagePolicy, TransformConfig, UpstreamConfig, content_type_matches},
+    crypto::KeepEncoded,
+    storage::{ColdTier, CorruptObject, ObjectHead, RangedObject, S3Storage, StoredBody, StoredObject, StreamedObject},
+    cache::{CacheStatus, FetchLock, KVStore},
+    health::{HealthChecker, unix_now},
+    signing,
+    stats::{HotPaths, StatsCollector},
+    telemetry,
+    transform,
 };
 
+// Upstream response headers worth logging when debugging rejected fetches
+const LOGGED_UPSTREAM_HEADERS: &[&str] = &[
+    "content-type", "content-length", "etag", "last-modified", "cache-control",
+    "age", "server", "via", "x-cache", "location", "retry-after",
+];
+
+// 1x1 fully transparent PNG served for `?fallback=pixel` misses
+const TRANSPARENT_PIXEL_PNG: &[u8] = &[
+    0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1F, 0x15, 0xC4,
+    0x89, 0x00, 0x00, 0x00, 0x0B, 0x49, 0x44, 0x41, 0x54, 0x78, 0xDA, 0x63, 0x60, 0x00, 0x02, 0x00,
+    0x00, 0x05, 0x00, 0x01, 0xE9, 0xFA, 0xDC, 0xD8, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44,
+    0xAE, 0x42, 0x60, 0x82,
+];
+
 // Allowed file extensions for proxying
 const ALLOWED_EXTENSIONS: &[&str] = &[
     "jpg", "jpeg", "png", "gif", "apng", "webp", "zip", "7z"
@@ -27,61 +80,931 @@ pub struct ProxyState {
     pub storage: S3Storage,
     pub cache: KVStore,
     pub http_client: HttpClient,
+    pub health: HealthChecker,
+    pub stats: StatsCollector,
+    pub cold_tier: Option<ColdTier>,
+    pub recent_writes: RecentWrites,
+    pub uploads: BackgroundUploads,
+    pub in_flight: InFlightFetches,
+    pub memory_cache: MemoryCache,
+    pub rewriter: PathRewriter,
+    pub path_templates: PathTemplates,
+    pub upstream_auth: UpstreamAuth,
+    pub hot_paths: HotPaths,
+    pub metrics: Option<PrometheusHandle>,
+}
+
+// Overall time budget for a single request, shared by every upstream and S3 call
+#[derive(Debug, Clone, Copy)]
+struct Deadline(Option<Instant>);
+
+impl Deadline {
+    fn new(secs: u64) -> Self {
+        if secs == 0 {
+            Self(None)
+        } else {
+            Self(Some(Instant::now() + Duration::from_secs(secs)))
+        }
+    }
+
+    fn remaining(&self) -> Option<Duration> {
+        self.0.map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    fn is_expired(&self) -> bool {
+        self.remaining().is_some_and(|left| left.is_zero())
+    }
+
+    async fn run<T>(&self, operation: impl Future<Output = Result<T>>) -> Result<T> {
+        match self.remaining() {
+            Some(left) => tokio::time::timeout(left, operation)
+                .await
+                .map_err(|_| anyhow!("Request deadline exceeded"))?,
+            None => operation.await,
+        }
+    }
+}
+
+fn deadline_exceeded(path: &str) -> (StatusCode, String) {
+    warn!("Request deadline exceeded for {}", path);
+    (StatusCode::GATEWAY_TIMEOUT, "Request deadline exceeded".to_string())
+}
+
+// Time spent in each stage of a request, reported in the Server-Timing header
+#[derive(Debug, Default)]
+struct StageTimings {
+    cache: Duration,
+    s3: Duration,
+    upstream: Duration,
+    transform: Duration,
+}
+
+impl StageTimings {
+    // Stages that never ran are left out
+    fn render(&self, total: Duration) -> String {
+        [
+            ("cache", self.cache),
+            ("s3", self.s3),
+            ("upstream", self.upstream),
+            ("transform", self.transform),
+            ("total", total),
+        ]
+        .iter()
+        .filter(|(_, duration)| !duration.is_zero())
+        .map(|(stage, duration)| format!("{};dur={:.1}", stage, duration.as_secs_f64() * 1000.0))
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+async fn timed<T>(stage: &mut Duration, operation: impl Future<Output = T>) -> T {
+    let started = Instant::now();
+    let result = operation.await;
+    *stage += started.elapsed();
+    result
+}
+
+// Number of proxy instances a request has already passed through
+const HOP_COUNT_HEADER: &str = "X-Proxy-Hops";
+
+// Methods served on image paths; HEAD is answered by the GET route
+const ALLOWED_METHODS: &[&str] = &["GET", "HEAD", "OPTIONS"];
+
+/// Key the proxy stores and caches the image requested as `path` (with or without its leading
+/// slash) and `raw_query` under: after the rewrite rules and the cache key normalization.
+pub fn storage_key(state: &ProxyState, path: &str, raw_query: Option<&str>) -> String {
+    let full_path = state.rewriter.rewrite(&format!("/{}", path.trim_start_matches('/')));
+    cache_key(&full_path, raw_query, &state.config.cache, &state.rewriter)
+}
+
+// Query params consumed by the proxy itself, never part of the key or the upstream URL
+const CONTROL_QUERY_PARAMS: &[&str] = &["download", "filename", "fallback", "preset", "q", "token", "expires", "sig"];
+
+// Cache and storage key for a request: the path plus whichever query params the configured
+// mode keeps, sorted so that param order alone cannot fragment the cache. Upstream is asked
+// for the `ImagePath` instead, so dropping a param here never drops it from the fetch. Hex
+// hash segments are lowercased first when configured.
+fn cache_key(path: &str, raw_query: Option<&str>, config: &CacheConfig, rewriter: &PathRewriter) -> String {
+    let path = rewriter.normalize_hex_segments(path);
+    let include_all = match config.query_key_mode.as_str() {
+        "include" => true,
+        "allowlist" => false,
+        _ => return path.into_owned(),
+    };
+
+    let mut params: Vec<&str> = raw_query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| {
+            let name = param.split('=').next().unwrap_or_default();
+            !name.is_empty()
+                && !CONTROL_QUERY_PARAMS.contains(&name)
+                && (include_all || config.query_key_allowlist.iter().any(|allowed| allowed == name))
+        })
+        .collect();
+
+    if params.is_empty() {
+        return path.into_owned();
+    }
+    params.sort_unstable();
+    format!("{}?{}", path, params.join("&"))
+}
+
+// A requested image as fetched on a miss: the path after rewriting, for logs and its
+// extension, and the path and query asked of upstream (or the parent proxy). The path has its
+// hex segments normalized like the key, and every query param not consumed by the proxy is
+// forwarded in its original order, whatever the key keeps.
+#[derive(Debug, Clone)]
+struct ImagePath {
+    full_path: String,
+    upstream: String,
+}
+
+impl ImagePath {
+    fn new(full_path: &str, raw_query: Option<&str>, rewriter: &PathRewriter) -> Self {
+        let path = rewriter.normalize_hex_segments(full_path);
+        let params: Vec<&str> = raw_query
+            .unwrap_or_default()
+            .split('&')
+            .filter(|param| {
+                let name = param.split('=').next().unwrap_or_default();
+                !name.is_empty() && !CONTROL_QUERY_PARAMS.contains(&name)
+            })
+            .collect();
+        let upstream = if params.is_empty() {
+            path.into_owned()
+        } else {
+            format!("{}?{}", path, params.join("&"))
+        };
+        Self { full_path: full_path.to_string(), upstream }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ProxyQuery {
+    pub download: Option<String>,
+    pub filename: Option<String>,
+    pub fallback: Option<String>,
+    pub preset: Option<String>,
+    pub q: Option<String>,
+    pub expires: Option<String>,
+    pub sig: Option<String>,
+}
+
+impl ProxyQuery {
+    fn wants_thumbnail(&self) -> bool {
+        self.preset.as_deref() == Some("thumb")
+    }
+
+    // WebP quality requested with `?q=`; ignored unless a quality range is configured
+    fn webp_quality(&self, range: Option<(u8, u8)>) -> Result<Option<u8>, (StatusCode, String)> {
+        let (Some(q), Some((min, max))) = (self.q.as_deref(), range) else {
+            return Ok(None);
+        };
+
+        match q.parse::<u8>() {
+            Ok(quality) if (min..=max).contains(&quality) => Ok(Some(quality)),
+            _ => Err((StatusCode::BAD_REQUEST, format!("Quality must be an integer from {} to {}", min, max))),
+        }
+    }
+
+    fn wants_pixel_fallback(&self) -> bool {
+        self.fallback.as_deref() == Some("pixel")
+    }
+
+    // Filename for an attachment Content-Disposition, if a download was requested
+    fn attachment_filename(&self, path: &str) -> Option<String> {
+        let wants_download = matches!(self.download.as_deref(), Some("1") | Some("true"));
+        if !wants_download && self.filename.is_none() {
+            return None;
+        }
+
+        let name = self.filename.as_deref()
+            .map(sanitize_filename)
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| sanitize_filename(path.rsplit('/').next().unwrap_or_default()));
+
+        if name.is_empty() { Some("download".to_string()) } else { Some(name) }
+    }
+}
+
+// Keep only characters that are safe inside a quoted header value
+fn sanitize_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') { c } else { '_' })
+        .collect::<String>()
+        .trim_matches('.')
+        .to_string()
+}
+
+// Directory-like paths ("/", "/foo/") and paths whose last segment has no
+// extension (or ends in a dot) can never name an image
+fn is_non_image_path(path: &str) -> bool {
+    let last_segment = path.rsplit('/').next().unwrap_or_default();
+    last_segment.is_empty() || !last_segment.contains('.') || last_segment.ends_with('.')
+}
+
+fn landing_response(state: &ProxyState) -> Result<Response<Body>, (StatusCode, String)> {
+    match &state.config.server.landing_response {
+        Some(body) => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from(body.clone()))
+            .unwrap_or_else(|_| Response::new(Body::empty()))),
+        None => Err((StatusCode::NOT_FOUND, "Not found".to_string())),
+    }
+}
+
+pub async fn index_handler(
+    State(state): State<ProxyState>,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    landing_response(&state)
+}
+
+#[derive(Debug, Serialize)]
+struct Capabilities {
+    methods: &'static [&'static str],
+    byte_ranges: bool,
+    formats: Vec<String>,
+    presets: Vec<&'static str>,
+    query_params: &'static [&'static str],
+}
+
+/// Describe what image paths support. CORS preflights never get here, the CORS layer answers them.
+pub async fn options_handler(State(state): State<ProxyState>) -> Response<Body> {
+    let capabilities = Capabilities {
+        methods: ALLOWED_METHODS,
+        byte_ranges: state.config.server.range_requests,
+        formats: state.config.transform.format_preferences.clone(),
+        presets: if state.config.transform.thumbnail_on_store { vec!["thumb"] } else { Vec::new() },
+        query_params: CONTROL_QUERY_PARAMS,
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::ALLOW, ALLOWED_METHODS.join(", "))
+        .header(header::ACCEPT_RANGES, if state.config.server.range_requests { "bytes" } else { "none" })
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&capabilities).unwrap_or_default()))
+        .unwrap_or_else(|_| {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("Failed to create response"))
+                .unwrap()
+        })
+}
+
+// Non-standard: answer a definitive miss with 200 and a transparent pixel so
+// beacon-style clients don't have to handle 404s
+fn not_found_response(
+    state: &ProxyState,
+    query: &ProxyQuery,
+    message: &str,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    pixel_fallback(state, query).ok_or_else(|| (StatusCode::NOT_FOUND, message.to_string()))
+}
+
+fn pixel_fallback(state: &ProxyState, query: &ProxyQuery) -> Option<Response<Body>> {
+    if !(state.config.server.pixel_fallback_enabled && query.wants_pixel_fallback()) {
+        return None;
+    }
+
+    Some(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "image/png")
+        .header(header::CACHE_CONTROL, "public, max-age=60")
+        .header("X-Cache-Status", "FALLBACK")
+        .header(header::CONTENT_LENGTH, TRANSPARENT_PIXEL_PNG.len())
+        .body(Body::from(Bytes::from_static(TRANSPARENT_PIXEL_PNG)))
+        .unwrap_or_else(|_| Response::new(Body::empty())))
+}
+
+// Rejection served from the negative cache, marked so it can be told apart from a live miss
+fn rejected_response(state: &ProxyState, query: &ProxyQuery, status: &CacheStatus) -> Response<Body> {
+    if let CacheStatus::NotFound = status
+        && let Some(pixel) = pixel_fallback(state, query)
+    {
+        return pixel;
+    }
+    negative_hit_response(status)
+}
+
+fn negative_hit_response(status: &CacheStatus) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .header("X-Cache-Status", "NEGATIVE-HIT")
+        .header("X-Cache-Reason", status.reason())
+        .body(Body::from("Cached as unavailable"))
+        .unwrap_or_else(|_| Response::new(Body::empty()))
 }
 
-fn is_allowed_extension(path: &str) -> bool {
-    if let Some(extension) = path.split('.').last() {
+// SVG is only accepted once it is opted into with SVG_BROTLI_ENABLED
+fn is_allowed_extension(path: &str, allow_svg: bool) -> bool {
+    if let Some(extension) = path.split('.').next_back() {
         let ext_lower = extension.to_lowercase();
-        ALLOWED_EXTENSIONS.contains(&ext_lower.as_str())
+        ALLOWED_EXTENSIONS.contains(&ext_lower.as_str()) || (allow_svg && ext_lower == "svg")
     } else {
         false
     }
 }
 
+// Confirm the body really is an image of the format implied by the extension.
+// Only the header is decoded, archives (zip/7z) are passed through unchecked.
+pub fn validate_image(data: &Bytes, path: &str) -> Result<()> {
+    let expected = match path.split('.').next_back().map(|ext| ext.to_lowercase()).as_deref() {
+        Some("jpg") | Some("jpeg") => ImageFormat::Jpeg,
+        Some("png") | Some("apng") => ImageFormat::Png,
+        Some("gif") => ImageFormat::Gif,
+        Some("webp") => ImageFormat::WebP,
+        _ => return Ok(()),
+    };
+
+    let detected = image::guess_format(data)
+        .map_err(|e| anyhow!("Unrecognized image data: {}", e))?;
+    if detected != expected {
+        return Err(anyhow!("Expected {:?} image but got {:?}", expected, detected));
+    }
+
+    ImageReader::with_format(Cursor::new(data.as_ref()), detected)
+        .into_dimensions()
+        .map_err(|e| anyhow!("Failed to decode image header: {}", e))?;
+
+    Ok(())
+}
+
+/// Reject requests whose `Host` is not in `ALLOWED_HOSTS` with 421 Misdirected Request.
+/// Health probes are exempt since they usually address the instance directly.
+pub async fn host_guard(State(state): State<ProxyState>, request: Request, next: Next) -> Response<Body> {
+    let allowed = &state.config.server.allowed_hosts;
+    if allowed.is_empty() || matches!(request.uri().path(), "/readyz" | "/healthz") {
+        return next.run(request).await;
+    }
+
+    // HTTP/2 requests carry the host in the URI authority instead of a Host header
+    let host = request.headers()
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| request.uri().authority().map(|authority| authority.to_string()));
+
+    if host.as_deref().is_some_and(|host| is_allowed_host(allowed, host)) {
+        return next.run(request).await;
+    }
+
+    warn!("Rejected request for unexpected host: {:?}", host);
+    (StatusCode::MISDIRECTED_REQUEST, "Misdirected request").into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+    expires: Option<String>,
+    sig: Option<String>,
+}
+
+// Health probes and the admin API are left alone by the request guards
+fn is_guard_exempt(path: &str) -> bool {
+    matches!(path, "/readyz" | "/healthz") || path.starts_with("/admin/")
+}
+
+/// Answer clients over `RATE_LIMIT_PER_MINUTE` with 429. Counts are kept in Redis per client
+/// IP over a sliding minute, estimated from the current and previous fixed windows, so the
+/// limit holds across instances. Redis failures let requests through.
+pub async fn rate_limit_guard(State(state): State<ProxyState>, request: Request, next: Next) -> Response<Body> {
+    let limit = state.config.server.rate_limit_per_minute;
+    if limit == 0 || is_guard_exempt(request.uri().path()) {
+        return next.run(request).await;
+    }
+    let Some(client) = client_ip(&request, &state.config.server.trusted_proxies) else {
+        return next.run(request).await;
+    };
+
+    let now = unix_now();
+    let (current, previous) = match state.cache.incr_rate(&client.to_string(), now / 60).await {
+        Ok(counts) => counts,
+        Err(e) if state.config.cache.outage_rate_limit == OutagePolicy::Closed => {
+            error!("Refusing request from {} while Redis is unavailable: {}", client, e);
+            return (StatusCode::SERVICE_UNAVAILABLE, "Rate limiter unavailable").into_response();
+        },
+        Err(e) => {
+            warn!("{}", e);
+            return next.run(request).await;
+        }
+    };
+
+    // The previous window counts for the part of it still inside the last minute
+    let elapsed = now % 60;
+    let estimate = current + previous * (60 - elapsed) / 60;
+    if estimate <= limit {
+        return next.run(request).await;
+    }
+
+    warn!("Rate limited {} ({} requests in the last minute)", client, estimate);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, (60 - elapsed).to_string())],
+        "Too many requests",
+    ).into_response()
+}
+
+// The peer address, or behind a trusted proxy the nearest X-Forwarded-For hop that is not
+// itself a trusted proxy. Hops further left are set by the client and cannot be believed.
+fn client_ip(request: &Request, trusted_proxies: &[String]) -> Option<IpAddr> {
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>()?.0.ip();
+    let trusted = |ip: &IpAddr| trusted_proxies.iter().any(|proxy| proxy.parse::<IpAddr>().is_ok_and(|proxy| proxy == *ip));
+    if !trusted(&peer) {
+        return Some(peer);
+    }
+
+    let forwarded: Vec<IpAddr> = request.headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|hop| hop.trim().parse().ok())
+        .collect();
+    Some(forwarded.into_iter().rev().find(|ip| !trusted(ip)).unwrap_or(peer))
+}
+
+/// Reject requests without the `PROXY_AUTH_TOKEN` as a bearer token or `?token=` with 401,
+/// before any cache, S3 or upstream work. Health probes are exempt and the admin API checks
+/// its own token, and requests carrying a valid URL signature pass without the token so signed
+/// URLs can be embedded. Without a configured token every request passes.
+pub async fn auth_guard(State(state): State<ProxyState>, request: Request, next: Next) -> Response<Body> {
+    let Some(expected) = state.config.auth.token.as_deref() else {
+        return next.run(request).await;
+    };
+    if is_guard_exempt(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let query = Query::<TokenQuery>::try_from_uri(request.uri()).ok().map(|query| query.0);
+    // Verified here, not just noticed, since only the image route checks signatures itself
+    if let Some(secret) = state.config.auth.signing_secret.as_deref()
+        && let Some(query) = query.as_ref().filter(|query| query.sig.is_some())
+        && let Some(path) = signing::canonical_path(request.uri().path())
+        && signing::verify_request(secret, &path, query.expires.as_deref(), query.sig.as_deref(), unix_now()).is_ok()
+    {
+        return next.run(request).await;
+    }
+
+    let bearer = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let provided = bearer.or_else(|| query.and_then(|query| query.token));
+
+    if provided.is_some_and(|provided| bool::from(provided.as_bytes().ct_eq(expected.as_bytes()))) {
+        return next.run(request).await;
+    }
+
+    warn!("Rejected request without a valid auth token: {}", request.uri().path());
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        "Invalid or missing token",
+    ).into_response()
+}
+
+// Entries match the host either exactly or, when they carry no port, on any port
+fn is_allowed_host(allowed: &[String], host: &str) -> bool {
+    let host = host.to_lowercase();
+    let Ok(authority) = host.parse::<Authority>() else {
+        return false;
+    };
+
+    allowed.iter().any(|entry| *entry == host || *entry == authority.host())
+}
+
 pub async fn proxy_handler(
     Path(path): Path<String>,
+    Query(query): Query<ProxyQuery>,
+    RawQuery(raw_query): RawQuery,
+    headers: HeaderMap,
     State(state): State<ProxyState>,
+) -> Response<Body> {
+    let started = Instant::now();
+    let mut timings = StageTimings::default();
+
+    let mut response = handle_request(&path, &query, raw_query.as_deref(), &headers, &state, &mut timings)
+        .await
+        .into_response();
+
+    if state.config.server.conditional_requests {
+        response = not_modified_if_matching(&headers, response);
+    }
+    if state.config.server.range_requests {
+        response = partial_content(&headers, response);
+    }
+
+    telemetry::record_request(&response, started.elapsed());
+
+    if state.config.server.server_timing_enabled
+        && let Ok(value) = HeaderValue::from_str(&timings.render(started.elapsed()))
+    {
+        response.headers_mut().insert("Server-Timing", value);
+    }
+    if let Some(origin) = state.config.server.timing_allow_origin.as_deref()
+        && let Ok(value) = HeaderValue::from_str(origin)
+    {
+        response.headers_mut().insert("Timing-Allow-Origin", value);
+    }
+
+    response
+}
+
+async fn handle_request(
+    path: &str,
+    query: &ProxyQuery,
+    raw_query: Option<&str>,
+    headers: &HeaderMap,
+    state: &ProxyState,
+    timings: &mut StageTimings,
 ) -> Result<Response<Body>, (StatusCode, String)> {
-    let full_path = format!("/{}", path);
+    // Signatures cover the path as routed (percent-decoded), before any rewrite
+    if let Some(secret) = state.config.auth.signing_secret.as_deref()
+        && let Err(e) = signing::verify_request(secret, &format!("/{}", path), query.expires.as_deref(), query.sig.as_deref(), unix_now())
+    {
+        warn!("Rejected request for /{}: {}", path, e);
+        return Err((StatusCode::FORBIDDEN, e.to_string()));
+    }
+
+    let deadline = Deadline::new(state.config.server.request_deadline_secs);
+    let full_path = state.rewriter.rewrite(&format!("/{}", path));
+    let key = cache_key(&full_path, raw_query, &state.config.cache, &state.rewriter);
+    let image_path = ImagePath::new(&full_path, raw_query, &state.rewriter);
+    let attachment = query.attachment_filename(&full_path);
     info!("Handling request for path: {}", full_path);
 
+    // Short-circuit paths that cannot be images without touching upstream or the cache
+    if is_non_image_path(&full_path) {
+        info!("Rejected non-image path: {}", full_path);
+        return landing_response(state);
+    }
+
+    // Only the URL shapes Pixiv serves are proxied, before any cache or upstream work
+    if !state.path_templates.matches(&full_path) {
+        warn!("Rejected path matching no path template: {}", full_path);
+        return Err((StatusCode::BAD_REQUEST, "Path does not match any accepted image URL".to_string()));
+    }
+
     // Check if the file extension is allowed
-    if !is_allowed_extension(&full_path) {
+    if !is_allowed_extension(&full_path, state.config.storage.compression.svg_brotli) {
         warn!("Rejected request for disallowed file type: {}", full_path);
         return Err((StatusCode::FORBIDDEN, "File type not allowed".to_string()));
     }
 
+    // An explicit quality asks for WebP whatever the Accept header says
+    let quality = query.webp_quality(state.config.transform.query_quality_range)?;
+    let variant = match quality {
+        Some(_) => transform::Variant::Webp,
+        None => transform::normalize_accept(
+            headers.get(header::ACCEPT).and_then(|value| value.to_str().ok()),
+            &state.config.transform.format_preferences,
+        ),
+    };
+    let requested = transform::VariantRequest { variant, quality };
+
+    // Thumbnail sidecars are generated at store time; until one exists the original is served
+    let wants_thumbnail = state.config.transform.thumbnail_on_store && query.wants_thumbnail();
+
+    let request = ImageRequest { key, image_path, headers, requested, wants_thumbnail };
+    let resolution = match resolve_path(state, &request, &deadline, timings).await {
+        Ok(resolution) => resolution,
+        Err(ResolveError::NotFound) => return not_found_response(state, query, "Image not found"),
+        Err(ResolveError::Rejected(status)) => return Ok(rejected_response(state, query, &status)),
+        Err(ResolveError::Failed { status, message, upstream_error }) => {
+            if let Some(kind) = upstream_error
+                && state.config.upstream.error_header
+            {
+                let mut response = (status, message).into_response();
+                response.headers_mut().insert("X-Upstream-Error", HeaderValue::from_static(kind));
+                return Ok(response);
+            }
+            return Err((status, message));
+        }
+    };
+
+    let ImageRequest { key, image_path, .. } = request;
+    let resolved = match resolution {
+        Resolution::Image(resolved) => resolved,
+        Resolution::Exact { object, vary } => {
+            let StoredObject { data, last_modified, content_type, content_encoding, .. } = object;
+            let etag = content_etag(&state.config, &data);
+            let mut response = create_image_response(data, &full_path, etag, attachment.as_deref(), &state.config, content_type.as_deref());
+            if let Some(encoding) = content_encoding {
+                response.headers_mut().insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+            }
+            if vary {
+                response = with_vary(state, response);
+            }
+            return Ok(with_age(response, last_modified));
+        },
+        Resolution::StoredRange { object, start, size, etag } => {
+            let last_modified = object.last_modified;
+            let response = create_partial_image_response(object, start, size, &full_path, etag, attachment.as_deref(), &state.config);
+            return Ok(with_age(with_vary(state, response), last_modified));
+        },
+        Resolution::StoredStream(object, etag) => {
+            let last_modified = object.last_modified;
+            let response = create_streamed_image_response(*object, &full_path, etag, attachment.as_deref(), &state.config);
+            return Ok(with_age(with_vary(state, response), last_modified));
+        },
+        Resolution::Range(upstream) => {
+            fetch_whole_in_background(state, &key, &image_path);
+            return Ok(upstream_range_response(state, &full_path, *upstream, attachment.as_deref()));
+        },
+        Resolution::Stream(upstream, fetch_lock, flight) => {
+            return Ok(stream_from_upstream(state, &key, &full_path, *upstream, attachment.as_deref(), fetch_lock, flight));
+        },
+    };
+
+    let ResolvedImage { data, content_type, source, stored_at, .. } = resolved;
+    if source == ImageSource::Uncached {
+        let etag = content_etag(&state.config, &data);
+        let mut response = create_image_response(data, &full_path, etag, attachment.as_deref(), &state.config, content_type.as_deref());
+        response.headers_mut().insert("X-Cache-Status", HeaderValue::from_static("BYPASS"));
+        return Ok(response);
+    }
+
+    let mut response = serve_image(state, &full_path, &key, data.clone(), requested, attachment.as_deref(), timings).await;
+    if source == ImageSource::Upstream {
+        response.headers_mut().insert("X-Cache-Status", HeaderValue::from_static("MISS"));
+    }
+
+    // The original goes out exactly as it was stored or fetched, so that content type applies
+    if matches!(source, ImageSource::Stored | ImageSource::InFlight | ImageSource::Fleet | ImageSource::Upstream)
+        && variant == transform::Variant::Original
+        && let Ok(value) = HeaderValue::from_str(&resolve_content_type(content_type.as_deref(), &data, &full_path, &state.config.transform))
+    {
+        response.headers_mut().insert(header::CONTENT_TYPE, value);
+    }
+
+    Ok(with_age(response, stored_at))
+}
+
+// What a request asks for, as parsed by `handle_request`
+struct ImageRequest<'a> {
+    key: String,
+    image_path: ImagePath,
+    headers: &'a HeaderMap,
+    requested: transform::VariantRequest,
+    wants_thumbnail: bool,
+}
+
+/// Where the bytes of a resolved image came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ImageSource {
+    Memory,      // This instance's memory cache
+    Burst,       // The burst cache shared across instances
+    Stored,      // The original as stored in S3 or the cold tier
+    RecentWrite, // This instance's copy of an object it is still storing
+    InFlight,    // Fetched by a concurrent request on this instance
+    Fleet,       // Fetched and stored by another instance
+    Upstream,    // Fetched here and kept
+    Uncached,    // Fetched here, but of a content type that is never cached
+}
+
+struct ResolvedImage {
+    data: Bytes,
+    content_type: Option<String>, // As stored or declared upstream, when known
+    source: ImageSource,
+    stored_at: Option<SystemTime>,
+    storing: bool, // Whether this resolution started a store into S3
+}
+
+enum Resolution {
+    // Bytes served through `serve_image`, which encodes the requested variant
+    Image(ResolvedImage),
+    // Stored bytes that go out exactly as kept: an encoded variant, a thumbnail sidecar or a
+    // still-compressed original. Only thumbnails do not vary with negotiation.
+    Exact { object: StoredObject, vary: bool },
+    // A single range of a stored original, read straight from S3
+    StoredRange { object: RangedObject, start: u64, size: u64, etag: Option<String> },
+    // A large stored original, streamed as it arrives from S3
+    StoredStream(Box<StreamedObject>, Option<String>),
+    // A large original to stream to the client as it arrives, kept once complete
+    Stream(Box<reqwest::Response>, Option<FetchLock>, Flight),
+    // Upstream's answer to the client's range of an original, passed through and never kept
+    Range(Box<reqwest::Response>),
+}
+
+enum ResolveError {
+    NotFound,
+    Rejected(CacheStatus), // The path is negatively cached, here or by another instance's fetch
+    Failed {
+        status: StatusCode,
+        message: String,
+        upstream_error: Option<&'static str>, // Kind of transport error, reported in `X-Upstream-Error`
+    },
+}
+
+impl From<(StatusCode, String)> for ResolveError {
+    fn from((status, message): (StatusCode, String)) -> Self {
+        Self::Failed { status, message, upstream_error: None }
+    }
+}
+
+// Resolve a request from wherever the image is found first: the negative cache, a stored
+// variant or thumbnail sidecar, the memory and burst caches, S3 or the cold tier, and
+// finally the miss path of `resolve_miss`. Bookkeeping such as hit counts, access records
+// and read-time migrations happens here; building the response is left to the caller.
+async fn resolve_path(
+    state: &ProxyState,
+    request: &ImageRequest<'_>,
+    deadline: &Deadline,
+    timings: &mut StageTimings,
+) -> Result<Resolution, ResolveError> {
+    let ImageRequest { key, image_path, headers, requested, wants_thumbnail } = request;
+    let (key, requested, wants_thumbnail) = (key.as_str(), *requested, *wants_thumbnail);
+    let full_path = image_path.full_path.as_str();
+    let variant = requested.variant;
+    let resolved = |data, content_type, source, stored_at| {
+        Ok(Resolution::Image(ResolvedImage { data, content_type, source, stored_at, storing: false }))
+    };
+
+    state.hot_paths.record(full_path);
+
     // Check if we should reject this request due to cached errors
-    match state.cache.should_reject(&full_path).await {
-        Ok(true) => {
-            return Err((StatusCode::NOT_FOUND, "Cached as unavailable".to_string()));
+    match timed(&mut timings.cache, state.cache.should_reject(key)).await {
+        Ok(Some(status)) => {
+            if matches!(status, CacheStatus::ServerError) {
+                revalidate_in_background(state, key, image_path);
+            }
+            return Err(ResolveError::Rejected(status));
+        },
+        Ok(None) => {},
+        Err(e) if state.config.cache.outage_negative_cache == OutagePolicy::Closed => {
+            error!("Error checking cache, refusing {} while Redis is unavailable: {}", full_path, e);
+            return Err((StatusCode::SERVICE_UNAVAILABLE, "Cache unavailable".to_string()).into());
         },
-        Ok(false) => {},
         Err(e) => {
             error!("Error checking cache: {}", e);
             // Continue processing if cache check fails
         }
     }
 
+    // Serve a previously encoded variant without touching the original
+    if variant != transform::Variant::Original {
+        let key = requested.key(key);
+        match timed(&mut timings.s3, deadline.run(state.storage.get_stored_object(&key))).await {
+            Ok(Some(object)) => {
+                info!("Serving {} variant of {} from S3 storage ({} bytes)", requested.token(), full_path, object.data.len());
+                state.stats.record_hit();
+                migrate_if_outdated(state, &key, full_path, &object);
+                return Ok(Resolution::Exact { object, vary: true });
+            },
+            Ok(None) => {},
+            Err(e) => {
+                warn!("Error fetching {} variant of {}: {}", requested.token(), full_path, e);
+            }
+        }
+    }
+
+    if wants_thumbnail {
+        let thumbnail_key = transform::thumbnail_key(key);
+        match timed(&mut timings.s3, deadline.run(state.storage.get_stored_object(&thumbnail_key))).await {
+            Ok(Some(object)) => {
+                info!("Serving thumbnail of {} from S3 storage ({} bytes)", full_path, object.data.len());
+                state.stats.record_hit();
+                migrate_if_outdated(state, &thumbnail_key, full_path, &object);
+                return Ok(Resolution::Exact { object, vary: false });
+            },
+            Ok(None) => {},
+            Err(e) => {
+                warn!("Error fetching thumbnail of {}: {}", full_path, e);
+            }
+        }
+    }
+
+    // Hot objects are served from this instance's memory, already through the S3 pipeline. Like
+    // the burst cache it only holds originals, so a thumbnail request whose sidecar is missing
+    // reads the original from S3 below, which also generates the sidecar.
+    if !wants_thumbnail
+        && let Some(data) = state.memory_cache.get(key)
+    {
+        info!("Serving {} from memory cache ({} bytes)", full_path, data.len());
+        state.stats.record_hit();
+        return resolved(data, None, ImageSource::Memory, None);
+    }
+
+    // Serve from the short-lived burst cache shared across instances
+    let burst = if wants_thumbnail { Ok(None) } else { timed(&mut timings.cache, state.cache.get_burst(key)).await };
+    match burst {
+        Ok(Some(data)) => {
+            info!("Serving {} from burst cache ({} bytes)", full_path, data.len());
+            state.stats.record_hit();
+            return resolved(data, None, ImageSource::Burst, None);
+        },
+        Ok(None) => {},
+        Err(e) => {
+            warn!("Error reading burst cache: {}", e);
+        }
+    }
+
+    // Only the original as stored can be passed through; variants and thumbnails need the decoded bytes
+    let as_stored = variant == transform::Variant::Original && !wants_thumbnail;
+    let keep_encoded = KeepEncoded {
+        gzip: as_stored && can_pass_through_gzip(state, headers, full_path),
+        brotli: as_stored && can_pass_through_brotli(state, headers, full_path),
+    };
+
+    // Objects moved to the cold tier are read from there, everything else from the main bucket
+    let (store, stored_key, cold) = match timed(&mut timings.cache, stored_location(state, key)).await {
+        Some(cold_key) => (state.cold_tier.as_ref().map_or(&state.storage, ColdTier::storage), cold_key, true),
+        None => (&state.storage, key.to_string(), false),
+    };
+
     // Check if file exists in S3 storage first
-    match state.storage.head_object(&full_path).await {
-        Ok(true) => {
-            // File exists, now fetch it
-            match state.storage.get_object(&full_path).await {
-                Ok(Some(data)) => {
-                    info!("Serving {} from S3 storage ({} bytes)", full_path, data.len());
-                    return Ok(create_image_response(data, &full_path));
+    match timed(&mut timings.s3, deadline.run(store.object_head(&stored_key))).await {
+        Ok(Some(ObjectHead { size, etag })) => {
+            // A single range of an original is read straight from S3 when it is stored as served
+            let range = ByteRange::parse(headers)
+                .filter(|_| state.config.server.range_requests && as_stored && if_range_matches(headers, etag.as_deref()))
+                .and_then(|range| range.resolve(size));
+            if let Some((start, end)) = range {
+                match timed(&mut timings.s3, deadline.run(store.get_object_range(&stored_key, start, end))).await {
+                    Ok(Some(object)) => {
+                        info!("Serving bytes {}-{} of {} from S3 storage", start, end, full_path);
+                        state.stats.record_hit();
+                        record_access(state, key, cold);
+                        return Ok(Resolution::StoredRange { object, start, size, etag });
+                    },
+                    Ok(None) => {}, // Read completely below and sliced in `partial_content`
+                    Err(e) => warn!("Error fetching a range of {} from S3: {}", full_path, e),
+                }
+            }
+
+            // File exists, now fetch it. Large originals go out as they arrive from S3.
+            let stored = if streams_stored(state, as_stored, size) {
+                match timed(&mut timings.s3, deadline.run(store.get_stored_body(&stored_key, keep_encoded))).await {
+                    Ok(Some(StoredBody::Streamed(object))) => {
+                        info!("Streaming {} from S3 storage ({} bytes)", full_path, size);
+                        state.stats.record_hit();
+                        record_access(state, key, cold);
+                        return Ok(Resolution::StoredStream(Box::new(object), etag));
+                    },
+                    Ok(Some(StoredBody::Buffered(object))) => Ok(Some(object)),
+                    Ok(None) => Ok(None),
+                    Err(e) => Err(e),
+                }
+            } else {
+                timed(&mut timings.s3, deadline.run(store.get_stored_object_encoded(&stored_key, keep_encoded))).await
+            };
+
+            match stored {
+                Ok(Some(object @ StoredObject { content_encoding: Some(encoding), .. })) => {
+                    info!("Serving {} from S3 storage {}-encoded ({} bytes)", full_path, encoding, object.data.len());
+                    state.stats.record_hit();
+                    record_access(state, key, cold);
+                    // The body is still compressed, so only the stored content type can describe it
+                    return Ok(Resolution::Exact { object, vary: true });
+                },
+                Ok(Some(object)) => {
+                    info!("Serving {} from S3 storage ({} bytes)", full_path, object.data.len());
+                    state.stats.record_hit();
+                    record_access(state, key, cold);
+                    // Read-time migrations re-store into the main bucket, so cold objects are left as they are
+                    if !cold {
+                        migrate_if_outdated(state, key, full_path, &object);
+                    }
+                    let StoredObject { data, last_modified, content_type, .. } = object;
+                    state.memory_cache.insert(key, data.clone());
+                    // Originals stored before thumbnails were enabled get theirs on first request
+                    if wants_thumbnail {
+                        store_thumbnail_in_background(state, key, full_path, data.clone());
+                    }
+                    return resolved(data, content_type, ImageSource::Stored, last_modified);
                 },
                 Ok(None) => {
                     // This shouldn't happen since head_object returned true
                     warn!("Head object succeeded but get object returned None for {}", full_path);
                 },
+                Err(e) if e.downcast_ref::<CorruptObject>().is_some() => {
+                    error!("{}", e);
+                    state.stats.record_corrupt_object();
+
+                    // Drop the bad copy so the upstream fetch below can replace it, even in write-once mode
+                    if state.config.storage.self_heal_on_corruption
+                        && let Err(e) = store.delete_object(&stored_key).await
+                    {
+                        warn!("Failed to delete corrupt object {}: {}", full_path, e);
+                    }
+                },
                 Err(e) => {
                     error!("Error fetching {} from S3 after successful head: {}", full_path, e);
                 }
             }
         },
-        Ok(false) => {
+        Ok(None) if cold => {
+            // The recorded tier is stale; the upstream fetch below stores a new copy in the main bucket
+            warn!("Cold tier copy of {} is missing, checking upstream", full_path);
+            if let Err(e) = state.cache.clear_cold(key).await {
+                warn!("{}", e);
+            }
+        },
+        Ok(None) => {
             info!("File {} not found in S3, checking upstream", full_path);
         },
         Err(e) => {
@@ -90,125 +1013,2739 @@ pub async fn proxy_handler(
         }
     }
 
-    // Fetch from upstream
-    match fetch_from_upstream(&state.http_client, &state.config.upstream, &full_path).await {
+    resolve_miss(state, key, image_path, headers, deadline, as_stored, timings).await
+}
+
+// Resolve a path that neither the caches nor S3 could serve: from this instance's recent
+// writes, a fetch already in flight here or elsewhere in the fleet, or upstream. Whatever is
+// fetched is validated, kept and negatively cached as configured; turning the outcome into a
+// response is left to the caller. Only with `as_stored` are large originals streamed, and
+// ranges of them forwarded upstream.
+async fn resolve_miss(
+    state: &ProxyState,
+    key: &str,
+    image_path: &ImagePath,
+    headers: &HeaderMap,
+    deadline: &Deadline,
+    as_stored: bool,
+    timings: &mut StageTimings,
+) -> Result<Resolution, ResolveError> {
+    let full_path = image_path.full_path.as_str();
+    let resolved = |data, content_type, source, stored_at| {
+        Ok(Resolution::Image(ResolvedImage { data, content_type, source, stored_at, storing: false }))
+    };
+
+    // A store from this instance may not be visible in S3 yet
+    if let Some(data) = state.recent_writes.get(key) {
+        info!("Serving {} from recently stored copy ({} bytes)", full_path, data.len());
+        state.stats.record_hit();
+        return resolved(data, None, ImageSource::RecentWrite, None);
+    }
+
+    if deadline.is_expired() {
+        return Err(deadline_exceeded(full_path).into());
+    }
+
+    // During a broad upstream outage nothing is fetched until the circuit closes again
+    if state.cache.error_circuit_enabled() {
+        match state.cache.error_circuit_open().await {
+            Ok(true) => {
+                metrics::gauge!("upstream_error_circuit_open").set(1.0);
+                warn!("Upstream error circuit is open, not fetching {}", full_path);
+                return Err((StatusCode::SERVICE_UNAVAILABLE, "Upstream temporarily unavailable".to_string()).into());
+            },
+            Ok(false) => metrics::gauge!("upstream_error_circuit_open").set(0.0),
+            Err(e) => warn!("{}", e),
+        }
+    }
+
+    // A client asking for part of an original gets that part as soon as upstream sends it,
+    // rather than after the whole object; the caller fetches the whole object for the store.
+    // Anything but a range answer is dropped unread and the miss handled as usual below.
+    if let Some(range) = forwarded_range(state, headers, as_stored) {
+        let upstream_started = Instant::now();
+        match open_upstream(&state.http_client, &state.config.upstream, &state.upstream_auth, &image_path.upstream, Some(range), deadline.remaining()).await {
+            Ok(response) if matches!(response.status().as_u16(), 206 | 416) => {
+                info!("Upstream answered {} to {} of {}", response.status().as_u16(), range, full_path);
+                timings.upstream += upstream_started.elapsed();
+                return Ok(Resolution::Range(Box::new(response)));
+            },
+            Ok(response) => debug!("Upstream answered {} to a range of {}, fetching it whole", response.status().as_u16(), full_path),
+            Err(e) => debug!("Ranged fetch of {} failed, fetching it whole: {}", full_path, e),
+        }
+    }
+
+    // Concurrent misses on this instance share one fetch. When the fetching request gives up
+    // without an outcome, one of the waiters takes over the fetch.
+    let flight = loop {
+        let outcome = match state.in_flight.join(key) {
+            Joined::Leader(flight) => break flight,
+            Joined::Waiter(outcome) => outcome,
+        };
+        debug!("Waiting for the in-flight fetch of {}", full_path);
+        let shared = deadline.run(async { Ok(inflight::wait(outcome).await) }).await
+            .map_err(|_| deadline_exceeded(full_path))?;
+        match shared {
+            Some(SharedFetch::Image(data, content_type)) => {
+                info!("Serving {} fetched by a concurrent request ({} bytes)", full_path, data.len());
+                state.stats.record_hit();
+                return resolved(data, content_type, ImageSource::InFlight, None);
+            },
+            Some(SharedFetch::NotFound) => return Err(ResolveError::NotFound),
+            Some(SharedFetch::Failed(status, message)) => return Err((status, message).into()),
+            None => continue,
+        }
+    };
+
+    // Across the fleet only one instance fetches a hot miss; the others wait for its result
+    let fetch_lock = match coalesce_fetch(state, key, deadline).await {
+        Coalesced::Fetch(lock) => lock,
+        Coalesced::Found(data, content_type, last_modified) => {
+            info!("Serving {} fetched by another instance ({} bytes)", full_path, data.len());
+            state.stats.record_hit();
+            flight.publish(SharedFetch::Image(data.clone(), content_type.clone()));
+            return resolved(data, content_type, ImageSource::Fleet, last_modified);
+        },
+        Coalesced::Rejected(status) => return Err(ResolveError::Rejected(status)),
+    };
+
+    // Fetch from the parent proxy first when configured, then from upstream
+    let upstream_started = Instant::now();
+    let mut upstream = match fetch_via_parent(state, headers, &image_path.upstream, deadline).await {
+        Some(result) => result,
+        None => match open_upstream(&state.http_client, &state.config.upstream, &state.upstream_auth, &image_path.upstream, None, deadline.remaining()).await {
+            Ok(response) if streams_from_upstream(state, as_stored, &response) => {
+                info!("Streaming {} from upstream ({:?} bytes)", full_path, response.content_length());
+                state.stats.record_miss();
+                timings.upstream += upstream_started.elapsed();
+                metrics::histogram!("upstream_fetch_duration_seconds").record(upstream_started.elapsed().as_secs_f64());
+                return Ok(Resolution::Stream(Box::new(response), fetch_lock, flight));
+            },
+            Ok(response) => read_fetched(response, &image_path.upstream, state.config.upstream.max_body_bytes).await,
+            Err(e) => Err(e),
+        },
+    };
+
+    // A 304 leaves nothing to serve when our copy vanished between reading its validators
+    // and the upstream answer, so repeat the fetch unconditionally to force a full body
+    if matches!(&upstream, Ok((status, _, _)) if *status == reqwest::StatusCode::NOT_MODIFIED) {
+        warn!("Upstream returned 304 for {} without a stored copy to serve (rare race), refetching", full_path);
+        upstream = fetch_from_upstream(&state.http_client, &state.config.upstream, &state.upstream_auth, &image_path.upstream, deadline.remaining()).await;
+    }
+    timings.upstream += upstream_started.elapsed();
+    metrics::histogram!("upstream_fetch_duration_seconds").record(upstream_started.elapsed().as_secs_f64());
+
+    match upstream {
         Ok((status, data, content_type)) => {
+            // Content types configured as uncacheable are passed through live and never cached,
+            // whichever other content type settings they match
+            let cacheable = !content_type_matches(&state.config.cache.no_cache_content_types, content_type.as_deref());
+
             match status.as_u16() {
                 200 => {
                     info!("Successfully fetched {} from upstream ({} bytes)", full_path, data.len());
-                    
-                    // Store in S3 asynchronously
-                    let storage_clone = state.storage.clone();
-                    let path_clone = full_path.clone();
-                    let data_clone = data.clone();
-                    let content_type_clone = content_type.clone();
-                    
-                    spawn(async move {
-                        if let Err(e) = storage_clone.put_object(&path_clone, data_clone, content_type_clone.as_deref()).await {
-                            error!("Failed to store {} in S3: {}", path_clone, e);
-                        }
-                    });
-
-                    // Remove any cached error status
-                    if let Err(e) = state.cache.remove_cache(&full_path).await {
-                        warn!("Failed to remove cache for {}: {}", full_path, e);
+                    state.stats.record_miss();
+
+                    if state.config.storage.validate_image_on_store
+                        && let Err(e) = validate_image(&data, full_path)
+                    {
+                        error!("Upstream body for {} failed image validation: {}", full_path, e);
+                        let failure = (StatusCode::BAD_GATEWAY, "Upstream returned an invalid image".to_string());
+                        flight.publish(SharedFetch::Failed(failure.0, failure.1.clone()));
+                        return Err(failure.into());
                     }
 
-                    Ok(create_image_response(data, &full_path))
-                },
+                    if !cacheable {
+                        info!("Passing {} through without caching ({:?})", full_path, content_type);
+                        return resolved(data, content_type, ImageSource::Uncached, None);
+                    }
+
+                    let Kept { data, content_type, storing } =
+                        keep_fetched(state, key, full_path, data, content_type, fetch_lock, &mut timings.transform).await;
+                    flight.publish(SharedFetch::Image(data.clone(), content_type.clone()));
+
+                    // The copy being stored is about as old as this response
+                    Ok(Resolution::Image(ResolvedImage {
+                        data,
+                        content_type,
+                        source: ImageSource::Upstream,
+                        stored_at: Some(SystemTime::now()),
+                        storing,
+                    }))
+                },
                 404 => {
                     info!("Upstream returned 404 for {}", full_path);
                     
                     // Cache 404 response
-                    if let Err(e) = state.cache.cache_not_found(&full_path).await {
+                    if cacheable
+                        && let Err(e) = state.cache.cache_not_found(key).await
+                    {
                         error!("Failed to cache 404 for {}: {}", full_path, e);
                     }
+                    flight.publish(SharedFetch::NotFound);
                     
-                    Err((StatusCode::NOT_FOUND, "Image not found".to_string()))
+                    Err(ResolveError::NotFound)
                 },
                 status_code if status_code >= 500 => {
                     error!("Upstream returned server error {} for {}", status_code, full_path);
                     
-                    // Cache server error
-                    if let Err(e) = state.cache.cache_server_error(&full_path).await {
-                        error!("Failed to cache server error for {}: {}", full_path, e);
+                    if cacheable {
+                        cache_server_error(state, key, full_path).await;
                     }
                     
-                    Err((StatusCode::BAD_GATEWAY, "Upstream server error".to_string()))
+                    let failure = (StatusCode::BAD_GATEWAY, "Upstream server error".to_string());
+                    flight.publish(SharedFetch::Failed(failure.0, failure.1.clone()));
+                    Err(failure.into())
                 },
                 _ => {
                     warn!("Upstream returned status {} for {}", status.as_u16(), full_path);
-                    Err((StatusCode::BAD_GATEWAY, format!("Upstream error: {}", status.as_u16())))
+                    let failure = (StatusCode::BAD_GATEWAY, format!("Upstream error: {}", status.as_u16()));
+                    flight.publish(SharedFetch::Failed(failure.0, failure.1.clone()));
+                    Err(failure.into())
                 }
             }
         },
         Err(e) => {
-            error!("Failed to fetch {} from upstream: {}", full_path, e);
-            
-            // Cache as server error
-            if let Err(cache_err) = state.cache.cache_server_error(&full_path).await {
-                error!("Failed to cache server error for {}: {}", full_path, cache_err);
+            let kind = upstream_error_kind(&e);
+            error!("Failed to fetch {} from upstream ({}): {}", full_path, kind, e);
+            metrics::counter!("upstream_fetch_errors_total", "kind" => kind).increment(1);
+
+            // Running out of our own time budget says nothing about upstream health, and
+            // waiters that started later may still have time to fetch it themselves
+            if deadline.is_expired() {
+                return Err(deadline_exceeded(full_path).into());
             }
-            
-            Err((StatusCode::BAD_GATEWAY, "Failed to fetch from upstream".to_string()))
+
+            let (status, message) = match kind {
+                // A truncated transfer is a one-off failure, not a reason to reject the path
+                "incomplete" => (StatusCode::BAD_GATEWAY, "Incomplete response from upstream".to_string()),
+                // Upstream is fine, the image is just more than we are configured to handle
+                "too_large" => body_too_large(),
+                // Neither is a single slow answer, so timeouts are not negatively cached
+                "timeout" => (StatusCode::GATEWAY_TIMEOUT, "Upstream did not respond in time".to_string()),
+                "connect" => {
+                    cache_server_error(state, key, full_path).await;
+                    (StatusCode::BAD_GATEWAY, "Could not connect to upstream".to_string())
+                },
+                _ => {
+                    cache_server_error(state, key, full_path).await;
+                    (StatusCode::BAD_GATEWAY, "Failed to fetch from upstream".to_string())
+                }
+            };
+            flight.publish(SharedFetch::Failed(status, message.clone()));
+            Err(ResolveError::Failed { status, message, upstream_error: Some(kind) })
         }
     }
 }
 
-async fn fetch_from_upstream(
-    client: &HttpClient,
-    config: &UpstreamConfig,
+// Negatively cache a server error for one path. With the error circuit enabled the error
+// also counts towards the fleet-wide rate; once the circuit is open the outage is handled
+// as one event, so no per-path entries pile up to expire and re-stampede independently.
+async fn cache_server_error(state: &ProxyState, key: &str, path: &str) {
+    if state.cache.error_circuit_enabled() {
+        metrics::counter!("upstream_server_errors_total").increment(1);
+        match state.cache.record_server_error().await {
+            Ok((count, open)) => {
+                metrics::gauge!("upstream_server_errors_in_window").set(count as f64);
+                if open {
+                    debug!("Error circuit is open, not caching server error for {}", path);
+                    return;
+                }
+            },
+            Err(e) => warn!("{}", e),
+        }
+    }
+
+    if let Err(e) = state.cache.cache_server_error(key).await {
+        error!("Failed to cache server error for {}: {}", path, e);
+    }
+}
+
+// Stale-while-revalidate for cached server errors: the rejection is served as usual, while
+// at most one request per interval across the fleet retries upstream in the background.
+// Once upstream answers again the image is kept like any fetched one, which clears the entry,
+// or the entry is replaced by a 404 entry.
+fn revalidate_in_background(state: &ProxyState, key: &str, image_path: &ImagePath) {
+    if !state.cache.revalidation_enabled() {
+        return;
+    }
+
+    let state = state.clone();
+    let key = key.to_string();
+    let image_path = image_path.clone();
+    spawn(async move {
+        match state.cache.revalidate(&key).await {
+            Ok(true) => {},
+            Ok(false) => return,
+            Err(e) => {
+                warn!("{}", e);
+                return;
+            }
+        }
+
+        let full_path = &image_path.full_path;
+        match fetch_from_upstream(&state.http_client, &state.config.upstream, &state.upstream_auth, &image_path.upstream, None).await {
+            Ok((status, data, content_type)) if status == reqwest::StatusCode::OK => {
+                if state.config.storage.validate_image_on_store
+                    && let Err(e) = validate_image(&data, full_path)
+                {
+                    debug!("Revalidation of {} still failing: {}", key, e);
+                    return;
+                }
+
+                if content_type_matches(&state.config.cache.no_cache_content_types, content_type.as_deref()) {
+                    info!("Upstream recovered for {}, clearing cached server error", key);
+                    if let Err(e) = state.cache.remove_cache(&key).await {
+                        warn!("Failed to remove cache for {}: {}", key, e);
+                    }
+                    return;
+                }
+
+                info!("Upstream recovered for {}, storing it in place of the cached server error", key);
+                let mut transform_time = Duration::ZERO;
+                keep_fetched(&state, &key, full_path, data, content_type, None, &mut transform_time).await;
+            },
+            Ok((status, _, _)) if status == reqwest::StatusCode::NOT_FOUND => {
+                info!("Upstream now returns 404 for {}, caching it", key);
+                if let Err(e) = state.cache.cache_not_found(&key).await {
+                    error!("Failed to cache 404 for {}: {}", key, e);
+                }
+            },
+            Ok((status, _, _)) => debug!("Revalidation of {} still failing with status {}", key, status.as_u16()),
+            Err(e) => debug!("Revalidation of {} still failing: {}", key, e),
+        }
+    });
+}
+
+// How often instances waiting on another instance's fetch look for its result
+const COALESCE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+enum Coalesced {
+    Fetch(Option<FetchLock>), // Fetch here, holding the fleet-wide lock if we got it
+    Found(Bytes, Option<String>, Option<SystemTime>), // Content type when read back from S3
+    Rejected(CacheStatus),
+}
+
+// Take the fetch lock for `key`, or wait up to the coalescing window for the instance holding
+// it to store the object or cache an error. On timeout we fetch ourselves, without the lock.
+async fn coalesce_fetch(state: &ProxyState, key: &str, deadline: &Deadline) -> Coalesced {
+    if !state.cache.coalescing_enabled() {
+        return Coalesced::Fetch(None);
+    }
+
+    match state.cache.acquire_fetch_lock(key).await {
+        Ok(Some(lock)) => return Coalesced::Fetch(Some(lock)),
+        Ok(None) => {},
+        Err(e) => {
+            warn!("Fetching {} without coalescing: {}", key, e);
+            return Coalesced::Fetch(None);
+        }
+    }
+
+    info!("Waiting for another instance to fetch {}", key);
+    let window = Instant::now() + Duration::from_millis(state.config.cache.coalesce_window_ms);
+    loop {
+        // Checked before looking for the result, so a lock released right after the
+        // result appeared still gets one last look
+        let finished = !state.cache.fetch_in_progress(key).await.unwrap_or(false);
+
+        if let Ok(Some(status)) = state.cache.should_reject(key).await {
+            return Coalesced::Rejected(status);
+        }
+        if let Ok(Some(data)) = state.cache.get_burst(key).await {
+            return Coalesced::Found(data, None, None);
+        }
+        if let Ok(Some(object)) = deadline.run(state.storage.get_stored_object(key)).await {
+            return Coalesced::Found(object.data, object.content_type, object.last_modified);
+        }
+
+        if finished || Instant::now() >= window || deadline.is_expired() {
+            break;
+        }
+        tokio::time::sleep(COALESCE_POLL_INTERVAL).await;
+    }
+
+    info!("No result from the coalesced fetch of {}, fetching it here", key);
+    Coalesced::Fetch(None)
+}
+
+// A parent 200 or 404 is final since the parent already asked upstream; anything else
+// (including loop protection) returns None and the caller goes to upstream directly
+async fn fetch_via_parent(
+    state: &ProxyState,
+    headers: &HeaderMap,
     path: &str,
-) -> Result<(reqwest::StatusCode, Bytes, Option<String>)> {
-    let url = format!("{}{}", config.host, path);
-    
-    let response = client
-        .get(&url)
-        .header("Referer", &config.referer)
-        .header("User-Agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36")
-        .send()
-        .await?;
+    deadline: &Deadline,
+) -> Option<Result<(reqwest::StatusCode, Bytes, Option<String>)>> {
+    let parent_url = state.config.upstream.parent_proxy_url.as_deref()?;
 
-    let status = response.status();
-    let content_type = response
-        .headers()
-        .get("content-type")
-        .and_then(|ct| ct.to_str().ok())
-        .map(|s| s.to_string());
-    
-    let data = response.bytes().await?;
-    
-    Ok((status, data, content_type))
+    let hops: u32 = headers
+        .get(HOP_COUNT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    if hops >= state.config.upstream.parent_proxy_max_hops {
+        warn!("Not forwarding {} to parent proxy after {} hops", path, hops);
+        return None;
+    }
+
+    // Instances of one hierarchy share the token, so the parent's auth guard lets us through
+    let result = fetch_from_parent(&state.http_client, &state.config, parent_url, path, hops, deadline.remaining()).await;
+
+    // Only a 404 is a real miss; failures of the parent itself are logged and counted apart
+    match result {
+        Ok((status, data, content_type)) if status.as_u16() == 200 => {
+            info!("Parent proxy served {}", path);
+            Some(Ok((status, data, content_type)))
+        },
+        Ok((status, data, content_type)) if status.as_u16() == 404 => {
+            info!("Parent proxy reports {} missing upstream", path);
+            Some(Ok((status, data, content_type)))
+        },
+        Ok((status, _, _)) => {
+            if matches!(status.as_u16(), 401 | 403) {
+                error!("Parent proxy refused {} with {}, check that PROXY_AUTH_TOKEN matches the parent's; fetching upstream directly", path, status.as_u16());
+            } else {
+                warn!("Parent proxy returned {} for {}, fetching upstream directly", status.as_u16(), path);
+            }
+            metrics::counter!("parent_proxy_errors_total", "status" => status.as_u16().to_string()).increment(1);
+            None
+        },
+        Err(e) => {
+            warn!("Parent proxy failed for {}, fetching upstream directly: {}", path, e);
+            metrics::counter!("parent_proxy_errors_total", "status" => "error").increment(1);
+            None
+        }
+    }
 }
 
-fn create_image_response(data: Bytes, path: &str) -> Response<Body> {
-    let mut response = Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CACHE_CONTROL, "public, max-age=604800") // 7 days
-        .header("X-Cache-Status", "HIT");
+// Respond with the negotiated variant, encoding and storing it on first request.
+// Anything that cannot be encoded is served as the original.
+async fn serve_image(
+    state: &ProxyState,
+    path: &str,
+    key: &str,
+    data: Bytes,
+    requested: transform::VariantRequest,
+    attachment: Option<&str>,
+    timings: &mut StageTimings,
+) -> Response<Body> {
+    let variant = requested.variant;
+    let original_etag = content_etag(&state.config, &data);
+    if variant == transform::Variant::Original || !transform::is_transcodable(&data, path) {
+        return with_vary(state, create_image_response(data, path, original_etag, attachment, &state.config, None));
+    }
+
+    let encode = transform::encode_variant_blocking(data.clone(), requested, state.config.transform.clone());
+    match timed(&mut timings.transform, encode).await {
+        Ok(encoded) => {
+            info!("Encoded {} variant of {} ({} -> {} bytes)", requested.token(), path, data.len(), encoded.len());
+            let content_type = format!("image/{}", variant.token());
+            store_variant_in_background(state, key, &requested.token(), encoded.clone(), content_type);
+            let etag = content_etag(&state.config, &encoded);
+            with_vary(state, create_image_response(encoded, path, etag, attachment, &state.config, None))
+        },
+        Err(e) => {
+            debug!("Serving original of {} instead of {} variant: {}", path, requested.token(), e);
+            with_vary(state, create_image_response(data, path, original_etag, attachment, &state.config, None))
+        }
+    }
+}
+
+// Turn a 200 into a 304 when the client's If-None-Match names its ETag. The ETag hashes the
+// body served (see `content_etag`), so it already differs between variants
+// (Accept) and between encoded and decoded copies (Accept-Encoding): a validator obtained for
+// one representation never matches another, and a client whose negotiation changed gets the
+// full response.
+// Clients that only kept the date get a 304 from If-Modified-Since instead.
+fn not_modified_if_matching(headers: &HeaderMap, response: Response<Body>) -> Response<Body> {
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let unchanged = match headers.get(header::IF_NONE_MATCH).and_then(|value| value.to_str().ok()) {
+        Some(if_none_match) => response.headers()
+            .get(header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|etag| etag_matches(if_none_match, etag)),
+        // If-Modified-Since is only evaluated without If-None-Match
+        None => unmodified_since(headers, response.headers()),
+    };
+    if !unchanged {
+        return response;
+    }
+
+    // A 304 carries the headers a cache needs to refresh its stored copy, and no body
+    let mut not_modified = Response::new(Body::empty());
+    *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+    for name in [header::ETAG, header::LAST_MODIFIED, header::CACHE_CONTROL, header::VARY, header::AGE, header::CONTENT_LOCATION] {
+        if let Some(value) = response.headers().get(&name) {
+            not_modified.headers_mut().insert(name, value.clone());
+        }
+    }
+    if let Some(value) = response.headers().get("X-Cache-Status") {
+        not_modified.headers_mut().insert("X-Cache-Status", value.clone());
+    }
+    not_modified
+}
+
+// Whether the response was last modified no later than the request's If-Modified-Since
+fn unmodified_since(request: &HeaderMap, response: &HeaderMap) -> bool {
+    let date = |headers: &HeaderMap, name| {
+        headers.get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+    };
+    match (date(request, header::IF_MODIFIED_SINCE), date(response, header::LAST_MODIFIED)) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+// Weak comparison, as If-None-Match requires: `W/` prefixes are ignored
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+// Answer a single byte range of a complete image response with 206, or 416 when the range
+// lies beyond the body. The body is sliced here whatever its source, so ranges also work for
+// variants, burst cache hits and bodies streamed from S3 or upstream, which are sliced as they
+// arrive rather than collected. A body of unknown length (a chunked upstream stream) goes out
+// whole, as RFC 9110 allows. Encoded bodies are left alone, a range of them would not be a
+// range of the image.
+fn partial_content(headers: &HeaderMap, response: Response<Body>) -> Response<Body> {
+    let ranged = response.status() == StatusCode::OK
+        && response.headers().get(header::ACCEPT_RANGES).is_some_and(|value| value == "bytes")
+        && !response.headers().contains_key(header::CONTENT_ENCODING);
+    if !ranged {
+        return response;
+    }
+    let Some(range) = ByteRange::parse(headers) else {
+        return response;
+    };
+    let etag = response.headers().get(header::ETAG).and_then(|value| value.to_str().ok());
+    if !if_range_matches(headers, etag) {
+        return response;
+    }
+    let Some(length) = response.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+    else {
+        return response;
+    };
+
+    let Some((start, end)) = range.resolve(length) else {
+        return Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", length))
+            .body(Body::empty())
+            .unwrap_or_else(|_| Response::new(Body::empty()));
+    };
+
+    let (mut parts, body) = response.into_parts();
+    parts.status = StatusCode::PARTIAL_CONTENT;
+    parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start + 1));
+    if let Ok(value) = HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, length)) {
+        parts.headers.insert(header::CONTENT_RANGE, value);
+    }
+    Response::from_parts(parts, slice_body(body, start, end))
+}
+
+// Bytes `start..=end` of `body`, passed on chunk by chunk. Reading stops after `end`.
+fn slice_body(body: Body, start: u64, end: u64) -> Body {
+    let chunks = futures::StreamExt::scan(body.into_data_stream(), 0u64, move |offset, chunk| {
+        let chunk_start = *offset;
+        if chunk_start > end {
+            return futures::future::ready(None);
+        }
+        let sliced = chunk.map(|chunk| {
+            *offset += chunk.len() as u64;
+            let from = start.saturating_sub(chunk_start).min(chunk.len() as u64) as usize;
+            let to = (end + 1).saturating_sub(chunk_start).min(chunk.len() as u64) as usize;
+            chunk.slice(from..to.max(from))
+        });
+        futures::future::ready(Some(sliced))
+    });
+    Body::from_stream(chunks)
+}
+
+// The `Range` header to forward upstream on a miss: a single range of an original, when ranges
+// are enabled and no parent proxy is asked first (the parent serves the range from its copy),
+// and without an `If-Range`: the ETag it names is a hash of a body not fetched yet
+fn forwarded_range<'a>(state: &ProxyState, headers: &'a HeaderMap, as_stored: bool) -> Option<&'a str> {
+    if !state.config.server.range_requests || !as_stored || state.config.upstream.parent_proxy_url.is_some() {
+        return None;
+    }
+    ByteRange::parse(headers)?;
+    if !if_range_matches(headers, None) {
+        return None;
+    }
+    headers.get(header::RANGE)?.to_str().ok()
+}
+
+// Fetch and keep the whole object after a range of it was served from upstream, exactly as a
+// miss would. A large object is streamed to a response nobody reads; the tee keeps reading
+// it for the store.
+fn fetch_whole_in_background(state: &ProxyState, key: &str, image_path: &ImagePath) {
+    let state = state.clone();
+    let key = key.to_string();
+    let image_path = image_path.clone();
+    spawn(async move {
+        let full_path = &image_path.full_path;
+        let deadline = Deadline::new(state.config.server.request_deadline_secs);
+        let mut timings = StageTimings::default();
+        match resolve_miss(&state, &key, &image_path, &HeaderMap::new(), &deadline, true, &mut timings).await {
+            Ok(Resolution::Stream(upstream, fetch_lock, flight)) => {
+                drop(stream_from_upstream(&state, &key, full_path, *upstream, None, fetch_lock, flight));
+            },
+            Ok(_) => debug!("Fetched {} whole after serving a range of it", full_path),
+            Err(ResolveError::NotFound) => debug!("{} was not found upstream after a range of it was", full_path),
+            Err(ResolveError::Rejected(status)) => debug!("{} is negatively cached ({})", full_path, status.reason()),
+            Err(ResolveError::Failed { message, .. }) => warn!("Failed to fetch {} whole after serving a range of it: {}", full_path, message),
+        }
+    });
+}
+
+// Upstream's 206 or 416 for a range of an original, passed through with our headers
+// It carries no ETag, since the hash of the whole body is not known
+fn upstream_range_response(state: &ProxyState, path: &str, upstream: reqwest::Response, attachment: Option<&str>) -> Response<Body> {
+    let status = StatusCode::from_u16(upstream.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let content_range = upstream.headers().get(header::CONTENT_RANGE).cloned();
+    let mut response = if status == StatusCode::PARTIAL_CONTENT {
+        let content_type = upstream.headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let content_type = resolve_content_type(content_type.as_deref(), &[], path, &state.config.transform);
+        let length = upstream.content_length();
+        let body = futures::stream::unfold(upstream, |mut upstream| async move {
+            upstream.chunk().await.transpose().map(|chunk| (chunk, upstream))
+        });
+        let mut response = image_response(Body::from_stream(body), length, content_type, None, attachment, &state.config);
+        *response.status_mut() = status;
+        with_vary(state, response)
+    } else {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = status;
+        response
+    };
+    if let Some(content_range) = content_range {
+        response.headers_mut().insert(header::CONTENT_RANGE, content_range);
+    }
+    response.headers_mut().insert("X-Cache-Status", HeaderValue::from_static("MISS"));
+    response
+}
+
+// Originals at or above the stream threshold are passed through as they arrive from S3
+fn streams_stored(state: &ProxyState, as_stored: bool, size: u64) -> bool {
+    let threshold = state.config.server.stream_threshold_bytes;
+    threshold > 0 && as_stored && size >= threshold
+}
+
+// An upstream answer is streamed when it is a large original of known length that needs no
+// processing before it is stored; anything else is read completely and handled as usual
+fn streams_from_upstream(state: &ProxyState, as_stored: bool, response: &reqwest::Response) -> bool {
+    let config = &state.config;
+    let content_type = response.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    // A body of unknown length may be large, so it is streamed whenever streaming is enabled
+    let large = match response.content_length() {
+        Some(length) => streams_stored(state, as_stored, length)
+            && (config.upstream.max_body_bytes == 0 || length <= config.upstream.max_body_bytes),
+        None => streams_stored(state, as_stored, u64::MAX),
+    };
+
+    response.status() == reqwest::StatusCode::OK
+        && large
+        && !config.storage.validate_image_on_store
+        && !config.transform.store_as_webp
+        && !config.transform.thumbnail_on_store
+        && !content_type_matches(&config.cache.no_cache_content_types, content_type)
+}
+
+// Chunks in flight between the upstream reader and a slow client
+const STREAM_BUFFER_CHUNKS: usize = 16;
+
+// Tee the upstream body: chunks go to the client as they arrive while a copy is collected
+// for the store, which needs the whole object. The upstream read continues when the client
+// goes away, so the object is still stored; a short body aborts the client response and
+// nothing is stored. Concurrent requests waiting on the fetch get the complete copy.
+// Without a Content-Length the client gets a chunked response; once such a body passes
+// MAX_UPSTREAM_BYTES the copy is dropped and nothing is stored, but the client still gets
+// the whole body.
+fn stream_from_upstream(
+    state: &ProxyState,
+    key: &str,
+    path: &str,
+    mut upstream: reqwest::Response,
+    attachment: Option<&str>,
+    fetch_lock: Option<FetchLock>,
+    flight: Flight,
+) -> Response<Body> {
+    let expected = upstream.content_length();
+    let max_bytes = state.config.upstream.max_body_bytes;
+    let content_type = upstream.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let (sender, receiver) = tokio::sync::mpsc::channel::<std::io::Result<Bytes>>(STREAM_BUFFER_CHUNKS);
 
-    // Set content type based on file extension
-    if let Some(ext) = path.split('.').last() {
-        let content_type = match ext.to_lowercase().as_str() {
-            "jpg" | "jpeg" => "image/jpeg",
-            "png" => "image/png",
-            "gif" => "image/gif",
-            "apng" => "image/apng",
-            "webp" => "image/webp",
-            "zip" => "application/zip",
-            "7z" => "application/x-7z-compressed",
-            _ => "application/octet-stream",
+    let task_state = state.clone();
+    let task_key = key.to_string();
+    let task_content_type = content_type.clone();
+    spawn(async move {
+        // The copy for the store, dropped along with the flight once the body is too large
+        let mut tee = Some((BytesMut::with_capacity(expected.unwrap_or_default() as usize), flight));
+        let mut received = 0u64;
+        let mut client_gone = false;
+        loop {
+            match upstream.chunk().await {
+                Ok(Some(chunk)) => {
+                    received += chunk.len() as u64;
+                    if max_bytes > 0
+                        && received > max_bytes
+                        && let Some((_, flight)) = tee.take()
+                    {
+                        warn!("Upstream body for {} exceeded the {} byte limit while streaming, not storing it", task_key, max_bytes);
+                        metrics::counter!("upstream_fetch_errors_total", "kind" => "too_large").increment(1);
+                        let (status, message) = body_too_large();
+                        flight.publish(SharedFetch::Failed(status, message));
+                    }
+                    if let Some((data, _)) = tee.as_mut() {
+                        data.extend_from_slice(&chunk);
+                    }
+                    client_gone = client_gone || sender.send(Ok(chunk)).await.is_err();
+                    // Nobody is left to read the rest
+                    if client_gone && tee.is_none() {
+                        return;
+                    }
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Failed to stream {} from upstream: {}", task_key, e);
+                    if let Some((_, flight)) = tee {
+                        flight.publish(SharedFetch::Failed(StatusCode::BAD_GATEWAY, "Failed to fetch from upstream".to_string()));
+                    }
+                    let _ = sender.send(Err(std::io::Error::other(e))).await;
+                    return;
+                }
+            }
+        }
+
+        if let Some(expected) = expected
+            && received != expected
+        {
+            let error = TruncatedBody { expected, received };
+            error!("Upstream body for {} is incomplete: {}", task_key, error);
+            if let Some((_, flight)) = tee {
+                flight.publish(SharedFetch::Failed(StatusCode::BAD_GATEWAY, "Incomplete response from upstream".to_string()));
+            }
+            let _ = sender.send(Err(std::io::Error::other(error))).await;
+            return;
+        }
+        drop(sender);
+
+        let Some((data, flight)) = tee else {
+            return;
         };
-        response = response.header(header::CONTENT_TYPE, content_type);
+        let data = data.freeze();
+        flight.publish(SharedFetch::Image(data.clone(), task_content_type.clone()));
+        task_state.memory_cache.insert(&task_key, data.clone());
+        store_in_background(&task_state, &task_key, data.clone(), task_content_type, fetch_lock);
+        if let Err(e) = task_state.cache.cache_burst(&task_key, &data).await {
+            warn!("Failed to store {} in burst cache: {}", task_key, e);
+        }
+        if let Err(e) = task_state.cache.remove_cache(&task_key).await {
+            warn!("Failed to remove cache for {}: {}", task_key, e);
+        }
+    });
+
+    let body = futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+    let content_type = resolve_content_type(content_type.as_deref(), &[], path, &state.config.transform);
+    // The body is hashed only once it has been read, too late for an ETag
+    let mut response = image_response(Body::from_stream(body), expected, content_type, None, attachment, &state.config);
+    response.headers_mut().insert("X-Cache-Status", HeaderValue::from_static("MISS"));
+    with_vary(state, with_age(response, Some(SystemTime::now())))
+}
+
+// Cold tier key of `key` when the object has been moved there. A failed lookup falls back to the
+// main bucket, where a moved object is simply missing and fetched from upstream again.
+async fn stored_location(state: &ProxyState, key: &str) -> Option<String> {
+    let cold_tier = state.cold_tier.as_ref()?;
+    match state.cache.is_cold(key).await {
+        Ok(true) => Some(cold_tier.key(key)),
+        Ok(false) => None,
+        Err(e) => {
+            warn!("{}", e);
+            None
+        }
     }
+}
+
+// Keep an object read from the main bucket there for another migration age
+fn record_access(state: &ProxyState, key: &str, cold: bool) {
+    let Some(cold_tier) = state.cold_tier.as_ref().filter(|_| !cold) else {
+        return;
+    };
+
+    let cache = state.cache.clone();
+    let key = key.to_string();
+    let ttl = cold_tier.access_ttl();
+    spawn(async move {
+        if let Err(e) = cache.record_access(&key, ttl).await {
+            warn!("{}", e);
+        }
+    });
+}
 
+// Age of a copy read from S3, so downstream caches count our storage time against max-age,
+// and its store time as Last-Modified for If-Modified-Since
+fn with_age(mut response: Response<Body>, stored_at: Option<SystemTime>) -> Response<Body> {
+    let Some(stored_at) = stored_at else {
+        return response;
+    };
+    if let Ok(age) = SystemTime::now().duration_since(stored_at) {
+        response.headers_mut().insert(header::AGE, HeaderValue::from(age.as_secs()));
+    }
+    if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(stored_at)) {
+        response.headers_mut().insert(header::LAST_MODIFIED, value);
+    }
     response
-        .header(header::CONTENT_LENGTH, data.len())
-        .body(Body::from(data))
-        .unwrap_or_else(|_| {
-            Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from("Failed to create response"))
-                .unwrap()
+}
+
+// Responses differ by Accept only when format negotiation is configured,
+// and by Accept-Encoding only when gzip or SVG brotli passthrough is enabled
+fn with_vary(state: &ProxyState, mut response: Response<Body>) -> Response<Body> {
+    let mut vary = Vec::new();
+    if !state.config.transform.format_preferences.is_empty() {
+        vary.push("Accept");
+    }
+    let compression = &state.config.storage.compression;
+    if compression.gzip_passthrough || compression.svg_brotli {
+        vary.push("Accept-Encoding");
+    }
+    if !vary.is_empty()
+        && let Ok(value) = HeaderValue::from_str(&vary.join(", "))
+    {
+        response.headers_mut().insert(header::VARY, value);
+    }
+    response
+}
+
+// Whether a gzip-at-rest object may be served without decompressing it: passthrough is enabled,
+// the client accepts gzip and the content type is one the compression policy covers
+fn can_pass_through_gzip(state: &ProxyState, headers: &HeaderMap, path: &str) -> bool {
+    let compression = &state.config.storage.compression;
+    if !compression.gzip_passthrough {
+        return false;
+    }
+
+    accepts_encoding(headers, "gzip")
+        && content_type_matches(&compression.content_types, Some(content_type_for_path(path)))
+}
+
+// Brotli-at-rest SVGs are served as stored to clients that accept br
+fn can_pass_through_brotli(state: &ProxyState, headers: &HeaderMap, path: &str) -> bool {
+    state.config.storage.compression.svg_brotli
+        && content_type_for_path(path) == "image/svg+xml"
+        && accepts_encoding(headers, "br")
+}
+
+// Whether Accept-Encoding lists `coding` without refusing it with q=0
+fn accepts_encoding(headers: &HeaderMap, coding: &str) -> bool {
+    headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value.split(',').any(|accepted| {
+                let mut parts = accepted.split(';');
+                let name = parts.next().unwrap_or_default().trim();
+                let refused = parts.any(|param| {
+                    param.trim()
+                        .strip_prefix("q=")
+                        .and_then(|q| q.trim().parse::<f32>().ok())
+                        .is_some_and(|q| q <= 0.0)
+                });
+                name.eq_ignore_ascii_case(coding) && !refused
+            })
         })
-}
\ No newline at end of file
+}
+
+// Re-store an object read back as plaintext, encrypted with a retired key or algorithm, or compressed
+// differently from the current settings, through the current pipeline. The store lock keeps this from racing a fresh
+// store of the same key.
+fn migrate_if_outdated(state: &ProxyState, key: &str, path: &str, object: &StoredObject) {
+    // Passed-through compressed bodies are not decoded, so there is nothing to re-store
+    if object.content_encoding.is_some() {
+        return;
+    }
+
+    let storage_config = &state.config.storage;
+    let content_type = resolve_content_type(object.content_type.as_deref(), &object.data, path, &state.config.transform);
+    let needs_encryption = storage_config.encrypt_on_read_migration
+        && storage_config.encryption.enabled
+        && (!object.encrypted || object.stale_key);
+    let needs_recompression = storage_config.recompress_on_read
+        && !state.storage.crypto_processor().compression_is_current(object.compression, Some(&content_type));
+    if !needs_encryption && !needs_recompression {
+        return;
+    }
+
+    let Ok(slot) = state.uploads.try_start(key) else {
+        debug!("No background upload slot for {}, skipping migration", key);
+        return;
+    };
+
+    let storage = state.storage.clone();
+    let cache = state.cache.clone();
+    let key = key.to_string();
+    let data = object.data.clone();
+    let hash_algo = state.config.cache.content_hash_algo;
+
+    spawn(async move {
+        let _slot = slot;
+        let token = match cache.acquire_store_lock(&key).await {
+            Ok(Some(token)) => token,
+            Ok(None) => {
+                debug!("Another writer holds the store lock for {}, skipping migration", key);
+                return;
+            },
+            Err(e) => {
+                warn!("Failed to acquire store lock for {}, skipping migration: {}", key, e);
+                return;
+            }
+        };
+
+        let content_hash = hash_algo.digest(&data);
+        match storage.write_object(&key, data, Some(&content_type), Some(&content_hash)).await {
+            Ok(()) => info!("Migrated {} to the current storage settings", key),
+            Err(e) => warn!("Failed to migrate {} to the current storage settings: {}", key, e),
+        }
+
+        if let Err(e) = cache.release_store_lock(&key, &token).await {
+            warn!("Failed to release store lock for {}: {}", key, e);
+        }
+    });
+}
+
+// Generate and store a thumbnail sidecar. Failures are only logged, the original is unaffected.
+fn store_thumbnail_in_background(state: &ProxyState, key: &str, path: &str, data: Bytes) {
+    if !transform::is_transcodable(&data, path) {
+        return;
+    }
+
+    let state = state.clone();
+    let key = key.to_string();
+    spawn(async move {
+        match transform::thumbnail_blocking(data, state.config.transform.clone()).await {
+            Ok(thumbnail) => {
+                debug!("Generated {} byte thumbnail for {}", thumbnail.len(), key);
+                store_variant_in_background(&state, &key, transform::THUMBNAIL_TOKEN, thumbnail, "image/webp".to_string());
+            },
+            Err(e) => warn!("Failed to generate thumbnail for {}: {}", key, e),
+        }
+    });
+}
+
+// Store a derived copy of the original at `key` as `key@token`, unless the original already
+// has its configured number of stored variants; the copy was served either way
+fn store_variant_in_background(state: &ProxyState, key: &str, token: &str, data: Bytes, content_type: String) {
+    let state = state.clone();
+    let key = key.to_string();
+    let token = token.to_string();
+
+    spawn(async move {
+        let limit = state.config.transform.max_variants_per_original;
+        match state.cache.register_variant(&key, &token, limit).await {
+            Ok(true) => {},
+            Ok(false) => {
+                info!("{} already has {} stored variants, not storing {}", key, limit, token);
+                return;
+            },
+            Err(e) => warn!("Failed to check variant count for {}, storing anyway: {}", key, e),
+        }
+
+        store_in_background(&state, &transform::derived_key(&key, &token), data, Some(content_type), None);
+    });
+}
+
+// An upstream image after `keep_fetched`: the bytes and content type to serve, and
+// whether a store was started (it is skipped when all background upload slots are busy)
+struct Kept {
+    data: Bytes,
+    content_type: Option<String>,
+    storing: bool,
+}
+
+// Everything a cacheable upstream 200 is kept as: its thumbnail, the WebP copy when configured,
+// the memory and burst copies and the background S3 store. Clears any negative cache entry.
+async fn keep_fetched(
+    state: &ProxyState,
+    key: &str,
+    path: &str,
+    data: Bytes,
+    content_type: Option<String>,
+    fetch_lock: Option<FetchLock>,
+    transform_time: &mut Duration,
+) -> Kept {
+    // Thumbnails are taken from the original, before any WebP transcode
+    if state.config.transform.thumbnail_on_store {
+        store_thumbnail_in_background(state, key, path, data.clone());
+    }
+
+    // Keep only a WebP copy when configured, serving it to every client
+    let (data, content_type) = if state.config.transform.store_as_webp
+        && transform::is_transcodable(&data, path)
+    {
+        let transcode = transform::transcode_to_webp_blocking(data.clone(), state.config.transform.clone());
+        match timed(transform_time, transcode).await {
+            Ok(webp) => {
+                info!("Transcoded {} to WebP ({} -> {} bytes)", path, data.len(), webp.len());
+                (webp, Some("image/webp".to_string()))
+            },
+            Err(e) => {
+                warn!("Failed to transcode {} to WebP, storing original: {}", path, e);
+                (data, content_type)
+            }
+        }
+    } else {
+        (data, content_type)
+    };
+
+    state.memory_cache.insert(key, data.clone());
+
+    // Store in S3 asynchronously
+    let storing = store_in_background(state, key, data.clone(), content_type.clone(), fetch_lock);
+
+    if let Err(e) = state.cache.cache_burst(key, &data).await {
+        warn!("Failed to store {} in burst cache: {}", path, e);
+    }
+
+    // Remove any cached error status
+    if let Err(e) = state.cache.remove_cache(key).await {
+        warn!("Failed to remove cache for {}: {}", path, e);
+    }
+
+    Kept { data, content_type, storing }
+}
+
+/// How [`ensure_cached`] left a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warmed {
+    Cached,         // Fetched from upstream and handed to a background store
+    AlreadyPresent, // Already stored, nothing was fetched
+}
+
+/// Make sure the image at `path` is stored, fetching it from upstream and keeping it exactly
+/// as a cache miss would when it is not. Negative cache entries are not honoured, so an
+/// explicit warm always asks upstream again.
+pub async fn ensure_cached(state: &ProxyState, path: &str) -> Result<Warmed> {
+    let full_path = state.rewriter.rewrite(&format!("/{}", path.trim_start_matches('/')));
+    let key = cache_key(&full_path, None, &state.config.cache, &state.rewriter);
+
+    if !state.path_templates.matches(&full_path)
+        || !is_allowed_extension(&full_path, state.config.storage.compression.svg_brotli)
+    {
+        return Err(anyhow!("{} is not an accepted image path", full_path));
+    }
+
+    if state.recent_writes.get(&key).is_some()
+        || stored_location(state, &key).await.is_some()
+        || state.storage.head_object(&key).await?
+    {
+        return Ok(Warmed::AlreadyPresent);
+    }
+
+    let deadline = Deadline::new(state.config.server.request_deadline_secs);
+    let mut timings = StageTimings::default();
+    match resolve_miss(state, &key, &ImagePath::new(&full_path, None, &state.rewriter), &HeaderMap::new(), &deadline, false, &mut timings).await {
+        Ok(Resolution::Image(ResolvedImage { source: ImageSource::Upstream, storing: true, .. })) => Ok(Warmed::Cached),
+        Ok(Resolution::Image(ResolvedImage { source: ImageSource::Upstream, .. })) => {
+            Err(anyhow!("No background upload slot was free to store {}", full_path))
+        },
+        Ok(Resolution::Image(ResolvedImage { source: ImageSource::Uncached, content_type, .. })) => {
+            Err(anyhow!("{} has an uncacheable content type ({:?})", full_path, content_type))
+        },
+        Ok(_) => Ok(Warmed::AlreadyPresent),
+        Err(ResolveError::NotFound) => Err(anyhow!("{} was not found upstream", full_path)),
+        Err(ResolveError::Rejected(status)) => Err(anyhow!("{} is negatively cached ({})", full_path, status.reason())),
+        Err(ResolveError::Failed { message, .. }) => Err(anyhow!("{}: {}", message, full_path)),
+    }
+}
+
+// Store freshly fetched bytes without delaying the response. A per-key Redis lock
+// keeps concurrent writers (possibly on other instances) from racing on the same object.
+// A fetch lock is held until the object is in S3, where waiting instances look for it.
+// Returns whether the object is being stored, by this call or one already in progress.
+fn store_in_background(state: &ProxyState, path: &str, data: Bytes, content_type: Option<String>, fetch_lock: Option<FetchLock>) -> bool {
+    let storage = state.storage.clone();
+    let cache = state.cache.clone();
+    let recent_writes = state.recent_writes.clone();
+    let cache_config = state.config.cache.clone();
+    let storage_config = state.config.storage.clone();
+    let access_ttl = state.cold_tier.as_ref().map(ColdTier::access_ttl);
+    let path = path.to_string();
+
+    let slot = match state.uploads.try_start(&path) {
+        Ok(slot) => slot,
+        Err(UploadRejected::InFlight) => {
+            debug!("{} is already being stored, skipping duplicate store", path);
+            return true;
+        },
+        Err(UploadRejected::Busy) => {
+            warn!("All {} background upload slots are busy, not storing {}", state.config.storage.max_background_uploads, path);
+            metrics::counter!("background_uploads_dropped_total").increment(1);
+            return false;
+        },
+    };
+
+    recent_writes.insert(&path, data.clone());
+
+    spawn(async move {
+        let _slot = slot;
+        let lock = match cache.acquire_store_lock(&path).await {
+            Ok(Some(token)) => Some(token),
+            Ok(None) => {
+                info!("Another writer holds the store lock for {}, skipping store", path);
+                recent_writes.mark_stored(&path);
+                return;
+            },
+            Err(e) if cache_config.outage_store_lock == OutagePolicy::Closed => {
+                error!("Failed to acquire store lock for {}, skipping store: {}", path, e);
+                recent_writes.mark_stored(&path);
+                return;
+            },
+            Err(e) => {
+                warn!("Failed to acquire store lock for {}, storing without it: {}", path, e);
+                None
+            }
+        };
+
+        // Registered before the put so an artwork purge never misses a stored object
+        if let Err(e) = cache.register_artwork_object(&path).await {
+            warn!("{}", e);
+        }
+
+        // `data` stays alive across attempts, so a retry never needs another upstream fetch
+        let content_hash = cache_config.content_hash_algo.digest(&data);
+        let mut backoff = Duration::from_millis(storage_config.store_retry_backoff_ms);
+        let mut attempt = 0;
+        loop {
+            match storage.put_object(&path, data.clone(), content_type.as_deref(), Some(&content_hash)).await {
+                Ok(()) => {
+                    recent_writes.mark_stored(&path);
+                    // A fresh store starts the object's time in the main bucket over
+                    if let Some(ttl) = access_ttl
+                        && let Err(e) = cache.record_access(&path, ttl).await
+                    {
+                        warn!("{}", e);
+                    }
+                    break;
+                },
+                Err(e) if attempt < storage_config.store_retries => {
+                    attempt += 1;
+                    warn!(
+                        "Failed to store {} in S3, retrying in {:?} ({}/{}): {}",
+                        path, backoff, attempt, storage_config.store_retries, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                },
+                Err(e) => {
+                    error!("Failed to store {} in S3: {}", path, e);
+                    recent_writes.remove(&path);
+                    break;
+                }
+            }
+        }
+
+        drop(fetch_lock);
+
+        if let Some(token) = lock
+            && let Err(e) = cache.release_store_lock(&path, &token).await
+        {
+            warn!("Failed to release store lock for {}: {}", path, e);
+        }
+    });
+
+    true
+}
+
+// Unconditional GET: no validator headers are sent, so upstream always answers with a full body
+// A 401 with a refreshable auth token fetches a new token and retries once
+async fn fetch_from_upstream(
+    client: &HttpClient,
+    config: &UpstreamConfig,
+    auth: &UpstreamAuth,
+    path: &str,
+    timeout: Option<Duration>,
+) -> Result<(reqwest::StatusCode, Bytes, Option<String>)> {
+    let response = open_upstream(client, config, auth, path, None, timeout).await?;
+    read_fetched(response, path, config.max_body_bytes).await
+}
+
+// Send the upstream request and wait for the response headers only, leaving the body unread.
+// Connection errors and 5xx answers are retried with exponential backoff and jitter while the
+// time budget allows; whatever the last attempt returned is handled as before. `range` is
+// sent as the `Range` header.
+async fn open_upstream(
+    client: &HttpClient,
+    config: &UpstreamConfig,
+    auth: &UpstreamAuth,
+    path: &str,
+    range: Option<&str>,
+    timeout: Option<Duration>,
+) -> Result<reqwest::Response> {
+    let url = format!("{}{}", config.host_for(path), path);
+    let first_byte_timeout = (config.first_byte_timeout_ms > 0)
+        .then(|| Duration::from_millis(config.first_byte_timeout_ms));
+
+    let started = Instant::now();
+    let mut refreshed = false;
+    let mut retries = 0;
+    loop {
+        let mut request = client
+            .get(&url)
+            .header("Referer", &config.referer)
+            .header("User-Agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36");
+        if let Some((name, value)) = auth.header().await {
+            request = request.header(name, value);
+        }
+        if let Some(range) = range {
+            request = request.header(header::RANGE, range);
+        }
+
+        let remaining = timeout.map(|timeout| timeout.saturating_sub(started.elapsed()));
+        let result = send_fetch(client, request, remaining, first_byte_timeout).await;
+        let failure = match &result {
+            Ok(response) if response.status().is_server_error() => Some(format!("status {}", response.status().as_u16())),
+            Ok(_) => None,
+            Err(e) => is_connection_error(e).then(|| e.to_string()),
+        };
+        if let Some(failure) = failure
+            && retries < config.max_retries
+        {
+            let delay = retry_delay(config.retry_base_ms, retries);
+            if timeout.is_none_or(|timeout| started.elapsed() + delay < timeout) {
+                retries += 1;
+                warn!(
+                    "Upstream fetch of {} failed ({}), retrying in {}ms (retry {} of {})",
+                    path, failure, delay.as_millis(), retries, config.max_retries
+                );
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+        }
+
+        let response = result?;
+        if !refreshed
+            && auth.refreshable()
+            && response.status() == reqwest::StatusCode::UNAUTHORIZED
+        {
+            warn!("Upstream rejected the auth token for {}, refreshing it", path);
+            if let Err(e) = auth.refresh().await {
+                warn!("Failed to refresh upstream auth token: {}", e);
+                return Ok(response);
+            }
+            refreshed = true;
+            continue;
+        }
+        return Ok(response);
+    }
+}
+
+// Why an upstream fetch failed, as logged and reported in `X-Upstream-Error`
+fn upstream_error_kind(error: &anyhow::Error) -> &'static str {
+    if error.downcast_ref::<TruncatedBody>().is_some() {
+        return "incomplete";
+    }
+    if error.downcast_ref::<BodyTooLarge>().is_some() {
+        return "too_large";
+    }
+    if error.downcast_ref::<FirstByteTimeout>().is_some() {
+        return "timeout";
+    }
+    match error.downcast_ref::<reqwest::Error>() {
+        Some(e) if e.is_timeout() => "timeout",
+        Some(e) if e.is_connect() => "connect",
+        _ => "other",
+    }
+}
+
+// Resets and refused connections are worth another try; our own timeouts are not
+fn is_connection_error(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| !e.is_timeout() && (e.is_connect() || e.is_request()))
+}
+
+// `base * 2^retry`, with up to half of it taken off at random so retries from many
+// requests failing at once do not arrive together
+fn retry_delay(base_ms: u64, retry: u32) -> Duration {
+    let delay = base_ms.saturating_mul(1 << retry.min(16)) as f64;
+    Duration::from_millis((delay * (1.0 - rand::random::<f64>() / 2.0)) as u64)
+}
+
+// Ask the parent proxy instance for the object, counting the hop so misconfigured
+// hierarchies cannot loop forever
+async fn fetch_from_parent(
+    client: &HttpClient,
+    config: &Config,
+    parent_url: &str,
+    path: &str,
+    hops: u32,
+    timeout: Option<Duration>,
+) -> Result<(reqwest::StatusCode, Bytes, Option<String>)> {
+    let url = format!("{}{}", parent_url.trim_end_matches('/'), path);
+
+    let mut request = client
+        .get(&url)
+        .header("Referer", &config.upstream.referer)
+        .header(HOP_COUNT_HEADER, (hops + 1).to_string());
+    if let Some(token) = config.auth.token.as_deref() {
+        request = request.bearer_auth(token);
+    }
+
+    // The parent may itself be fetching from upstream, so no first-byte limit applies
+    execute_fetch(client, request, path, timeout, None, config.upstream.max_body_bytes).await
+}
+
+async fn execute_fetch(
+    client: &HttpClient,
+    request: reqwest::RequestBuilder,
+    path: &str,
+    timeout: Option<Duration>,
+    first_byte_timeout: Option<Duration>,
+    max_bytes: u64,
+) -> Result<(reqwest::StatusCode, Bytes, Option<String>)> {
+    let response = send_fetch(client, request, timeout, first_byte_timeout).await?;
+    read_fetched(response, path, max_bytes).await
+}
+
+// `first_byte_timeout` only bounds the wait for the response headers, so a hung upstream
+// fails fast while a large body that keeps arriving is bounded by `timeout` alone.
+async fn send_fetch(
+    client: &HttpClient,
+    mut request: reqwest::RequestBuilder,
+    timeout: Option<Duration>,
+    first_byte_timeout: Option<Duration>,
+) -> Result<reqwest::Response> {
+    // Bound the fetch by whatever is left of the request deadline
+    if let Some(timeout) = timeout {
+        request = request.timeout(timeout);
+    }
+
+    let request = request.build()?;
+    debug!("Upstream request: {} {} headers={:?}", request.method(), request.url(), request.headers());
+
+    let url = request.url().to_string();
+    let response = match first_byte_timeout {
+        Some(limit) => tokio::time::timeout(limit, client.execute(request))
+            .await
+            .map_err(|_| FirstByteTimeout { limit })??,
+        None => client.execute(request).await?,
+    };
+
+    let status = response.status();
+    debug!(
+        "Upstream response for {}: status={} headers={:?}",
+        url,
+        status,
+        LOGGED_UPSTREAM_HEADERS.iter()
+            .filter_map(|name| response.headers().get(*name).map(|value| (*name, value)))
+            .collect::<Vec<_>>()
+    );
+
+    Ok(response)
+}
+
+// A nonzero `max_bytes` is enforced as the body arrives, so chunked responses without
+// a Content-Length are cut off as soon as they exceed it.
+async fn read_fetched(
+    response: reqwest::Response,
+    path: &str,
+    max_bytes: u64,
+) -> Result<(reqwest::StatusCode, Bytes, Option<String>)> {
+    let status = response.status();
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .and_then(|ct| ct.to_str().ok())
+        .map(|s| s.to_string());
+    let expected_length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    
+    let data = if max_bytes > 0 {
+        read_bounded_body(response, path, expected_length, max_bytes).await?
+    } else {
+        response.bytes().await?
+    };
+
+    // Never hand a truncated body to the caller, it would be cached as a complete image
+    if let Some(expected) = expected_length
+        && expected != data.len() as u64
+    {
+        error!("Upstream body for {} has {} bytes but Content-Length was {}", path, data.len(), expected);
+        return Err(TruncatedBody { expected, received: data.len() as u64 }.into());
+    }
+    
+    Ok((status, data, content_type))
+}
+
+// Read the body chunk by chunk, giving up once it grows past `max_bytes`. A declared
+// Content-Length over the limit is refused before anything is read.
+async fn read_bounded_body(
+    mut response: reqwest::Response,
+    path: &str,
+    expected_length: Option<u64>,
+    max_bytes: u64,
+) -> Result<Bytes> {
+    if expected_length.is_some_and(|expected| expected > max_bytes) {
+        warn!("Upstream body for {} declares {:?} bytes, over the {} byte limit", path, expected_length, max_bytes);
+        return Err(BodyTooLarge { limit: max_bytes }.into());
+    }
+
+    let mut data = BytesMut::with_capacity(expected_length.unwrap_or_default() as usize);
+    while let Some(chunk) = response.chunk().await? {
+        if (data.len() + chunk.len()) as u64 > max_bytes {
+            warn!("Upstream body for {} exceeded the {} byte limit while streaming", path, max_bytes);
+            return Err(BodyTooLarge { limit: max_bytes }.into());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data.freeze())
+}
+
+// An upstream body over MAX_UPSTREAM_BYTES is refused as unprocessable, not as an upstream failure
+fn body_too_large() -> (StatusCode, String) {
+    (StatusCode::UNPROCESSABLE_ENTITY, "Upstream image exceeds the size limit".to_string())
+}
+
+#[derive(Debug)]
+struct BodyTooLarge {
+    limit: u64,
+}
+
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Upstream body exceeds the {} byte limit", self.limit)
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+#[derive(Debug)]
+struct TruncatedBody {
+    expected: u64,
+    received: u64,
+}
+
+impl std::fmt::Display for TruncatedBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Upstream body length {} does not match Content-Length {}", self.received, self.expected)
+    }
+}
+
+impl std::error::Error for TruncatedBody {}
+
+#[derive(Debug)]
+struct FirstByteTimeout {
+    limit: Duration,
+}
+
+impl std::fmt::Display for FirstByteTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Upstream sent no response within {}ms", self.limit.as_millis())
+    }
+}
+
+impl std::error::Error for FirstByteTimeout {}
+
+// Strong ETag of a response body: the hash of exactly the bytes served, so a variant, a
+// thumbnail and a still-compressed body each get their own, and a changed object a new one.
+// Bodies streamed from S3 use the same hash as recorded at store time (see `ObjectHead`).
+fn content_etag(config: &Config, data: &[u8]) -> String {
+    format!("\"{}\"", config.cache.content_hash_algo.digest(data))
+}
+
+// `declared` is the content type upstream sent along with `data`, when serving it unchanged
+fn create_image_response(
+    data: Bytes,
+    path: &str,
+    etag: String,
+    attachment: Option<&str>,
+    config: &Config,
+    declared: Option<&str>,
+) -> Response<Body> {
+    let content_type = resolve_content_type(declared, &data, path, &config.transform);
+    let length = data.len() as u64;
+    image_response(Body::from(data), Some(length), content_type, Some(etag), attachment, config)
+}
+
+fn create_streamed_image_response(
+    object: StreamedObject,
+    path: &str,
+    etag: Option<String>,
+    attachment: Option<&str>,
+    config: &Config,
+) -> Response<Body> {
+    let content_type = resolve_content_type(object.content_type.as_deref(), &object.head, path, &config.transform);
+    let length = object.content_length;
+    image_response(Body::from_stream(object.into_stream()), length, content_type, etag, attachment, config)
+}
+
+// A range read from S3 is only sniffed when it starts at the beginning of the image
+fn create_partial_image_response(
+    object: RangedObject,
+    start: u64,
+    size: u64,
+    path: &str,
+    etag: Option<String>,
+    attachment: Option<&str>,
+    config: &Config,
+) -> Response<Body> {
+    let head: &[u8] = if start == 0 { &object.data } else { &[] };
+    let content_type = resolve_content_type(object.content_type.as_deref(), head, path, &config.transform);
+    let length = object.data.len() as u64;
+    let content_range = format!("bytes {}-{}/{}", start, start + length.max(1) - 1, size);
+
+    let mut response = image_response(Body::from(object.data), Some(length), content_type, etag, attachment, config);
+    *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+    if let Ok(value) = HeaderValue::from_str(&content_range) {
+        response.headers_mut().insert(header::CONTENT_RANGE, value);
+    }
+    response
+}
+
+fn image_response(
+    body: Body,
+    length: Option<u64>,
+    content_type: String,
+    etag: Option<String>,
+    attachment: Option<&str>,
+    config: &Config,
+) -> Response<Body> {
+    // Lets downstream caches keep serving their copy while we return errors
+    let cache_control = match config.cache.stale_if_error_secs {
+        0 => config.cache.cache_control_for(&content_type).to_string(),
+        secs => format!("{}, stale-if-error={}", config.cache.cache_control_for(&content_type), secs),
+    };
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::AGE, 0) // Stored copies override this with their real age
+        .header("X-Cache-Status", "HIT");
+
+    if let Some(etag) = etag {
+        response = response.header(header::ETAG, etag);
+    }
+
+    response = response.header(header::CONTENT_TYPE, content_type);
+
+    if let Some(filename) = attachment {
+        response = response.header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename));
+    }
+
+    if let Some(length) = length {
+        response = response.header(header::CONTENT_LENGTH, length);
+    }
+
+    if config.server.range_requests {
+        response = response.header(header::ACCEPT_RANGES, "bytes");
+    }
+
+    response
+        .body(body)
+        .unwrap_or_else(|_| {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("Failed to create response"))
+                .unwrap()
+        })
+}
+
+// Content type of a response body, in order of preference:
+// 1. the content type upstream declared, unless it is missing or application/octet-stream
+// 2. the content type sniffed from magic bytes, or the CONTENT_TYPE_FAMILY_OVERRIDES entry
+//    for the sniffed family (so WebP stored under a .jpg path, see STORE_AS_WEBP, is labelled as such)
+// 3. the file extension
+// 4. application/octet-stream
+fn resolve_content_type(declared: Option<&str>, data: &[u8], path: &str, config: &TransformConfig) -> String {
+    if let Some(declared) = declared.filter(|ct| !ct.is_empty() && !ct.starts_with("application/octet-stream")) {
+        return declared.to_string();
+    }
+
+    if let Some((family, precise)) = transform::sniff(data) {
+        if let Some((_, content_type)) = config.content_type_overrides.iter().find(|(f, _)| f == family) {
+            return content_type.clone();
+        }
+        if let Some(precise) = precise {
+            return precise.to_string();
+        }
+    }
+
+    content_type_for_path(path).to_string()
+}
+
+// Content type based on the file extension
+fn content_type_for_path(path: &str) -> &'static str {
+    let extension = path.split('.').next_back().unwrap_or_default().to_lowercase();
+    match extension.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "apng" => "image/apng",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "zip" => "application/zip",
+        "7z" => "application/x-7z-compressed",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, http::Method, routing::get};
+    use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+    use tower::ServiceExt;
+
+    use crate::{cache::testing::FakeRedis, storage::testing::FakeS3};
+
+    const IMAGE_PATH: &str = "/img-original/img/2024/01/01/00/00/00/123_p0.png";
+
+    // A proxy wired to in-process S3, Redis and upstream stand-ins
+    struct Harness {
+        state: ProxyState,
+        s3: FakeS3,
+        redis: FakeRedis,
+        upstream_hits: Arc<AtomicUsize>,
+    }
+
+    impl Harness {
+        async fn start(settings: &[(&str, &str)], upstream: Router) -> Self {
+            let upstream_hits = Arc::new(AtomicUsize::new(0));
+            let hits = upstream_hits.clone();
+            let upstream = upstream.layer(axum::middleware::from_fn(move |request: Request, next: Next| {
+                hits.fetch_add(1, Ordering::SeqCst);
+                next.run(request)
+            }));
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let upstream_host = format!("http://{}", listener.local_addr().unwrap());
+            spawn(async move {
+                let _ = axum::serve(listener, upstream).await;
+            });
+
+            let s3 = FakeS3::start().await;
+            let redis = FakeRedis::start().await;
+            let mut all = vec![("UPSTREAM_HOST", upstream_host.as_str()), ("REDIS_URL", redis.url())];
+            all.extend_from_slice(settings);
+            let config = Config::for_tests(&s3.settings(&all));
+            let state = Self::state(config).await;
+
+            Self { state, s3, redis, upstream_hits }
+        }
+
+        async fn state(config: Config) -> ProxyState {
+            let storage = S3Storage::new(&config.storage).await.unwrap();
+            let cache = KVStore::new(&config.cache).await.unwrap();
+            let http_client = HttpClient::new();
+            ProxyState {
+                health: HealthChecker::new(http_client.clone(), &config.upstream, config.health.clone(), cache.clone(), config.cache.clone(), storage.clone()),
+                stats: StatsCollector::new(config.stats.clone(), storage.clone(), cache.clone()),
+                cold_tier: config.storage.cold_tier.as_ref().map(|cold| ColdTier::new(&storage, cold).unwrap()),
+                recent_writes: RecentWrites::new(config.storage.consistency_grace_ms),
+                uploads: BackgroundUploads::new(config.storage.max_background_uploads),
+                in_flight: InFlightFetches::default(),
+                memory_cache: MemoryCache::new(config.cache.memory_cache_mb),
+                upstream_auth: UpstreamAuth::new(http_client.clone(), &config.upstream).unwrap(),
+                rewriter: PathRewriter::new(&config.upstream.rewrite_rules, config.cache.hex_segment_pattern.as_deref()).unwrap(),
+                path_templates: PathTemplates::new(config.upstream.path_templates_enabled, &config.upstream.path_templates, &config.upstream.path_patterns).unwrap(),
+                hot_paths: HotPaths::new(config.stats.hot_paths_top_n, config.stats.hot_paths_window),
+                metrics: None,
+                config,
+                storage,
+                cache,
+                http_client,
+            }
+        }
+
+        // Another proxy instance sharing the same S3, Redis and upstream
+        async fn another_instance(&self) -> Self {
+            Self {
+                state: Self::state(self.state.config.clone()).await,
+                s3: self.s3.clone(),
+                redis: self.redis.clone(),
+                upstream_hits: self.upstream_hits.clone(),
+            }
+        }
+
+        async fn request(&self, method: Method, path: &str, headers: &[(&str, &str)]) -> Response<Body> {
+            let app = Router::new()
+                .route("/{*path}", get(proxy_handler).options(options_handler))
+                .with_state(self.state.clone());
+            let mut request = Request::builder().method(method).uri(path);
+            for (name, value) in headers {
+                request = request.header(*name, *value);
+            }
+            app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap()
+        }
+
+        async fn get(&self, path: &str, headers: &[(&str, &str)]) -> Response<Body> {
+            self.request(Method::GET, path, headers).await
+        }
+
+        // Serve this instance over TCP behind its auth guard, for other instances to reach
+        async fn listen(&self) -> String {
+            let app = Router::new()
+                .route("/{*path}", get(proxy_handler))
+                .layer(axum::middleware::from_fn_with_state(self.state.clone(), auth_guard))
+                .with_state(self.state.clone());
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = format!("http://{}", listener.local_addr().unwrap());
+            spawn(async move {
+                let _ = axum::serve(listener, app).await;
+            });
+            address
+        }
+
+        fn upstream_hits(&self) -> usize {
+            self.upstream_hits.load(Ordering::SeqCst)
+        }
+
+        // Background stores finish after the response; wait for the object to show up
+        async fn stored(&self, key: &str) -> crate::storage::testing::StoredEntry {
+            eventually(|| self.s3.object(key).is_some()).await;
+            self.s3.object(key).unwrap()
+        }
+    }
+
+    // Wait for background work to reach a state the response cannot tell about
+    async fn eventually(condition: impl Fn() -> bool) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition never became true");
+    }
+
+    async fn body_bytes(response: Response<Body>) -> Bytes {
+        axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()
+    }
+
+    // A small real image, so validation and transcoding accept it
+    fn png() -> Vec<u8> {
+        let image = image::RgbaImage::from_pixel(4, 4, image::Rgba([200, 30, 30, 255]));
+        let mut out = Cursor::new(Vec::new());
+        image.write_to(&mut out, ImageFormat::Png).unwrap();
+        out.into_inner()
+    }
+
+    // Upstream answering every path with `body` as `content_type`
+    fn serving(body: Vec<u8>, content_type: &'static str) -> Router {
+        Router::new().fallback(move || {
+            let body = body.clone();
+            async move { ([(header::CONTENT_TYPE, content_type)], body) }
+        })
+    }
+
+    // Answer one request with `body` sent in chunks of `chunk_len`, without a Content-Length
+    async fn chunked_upstream(body: &'static [u8], chunk_len: usize) -> reqwest::Response {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            socket.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n").await.unwrap();
+            for chunk in body.chunks(chunk_len) {
+                socket.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await.unwrap();
+                socket.write_all(chunk).await.unwrap();
+                socket.write_all(b"\r\n").await.unwrap();
+            }
+            socket.write_all(b"0\r\n\r\n").await.unwrap();
+        });
+
+        let response = HttpClient::new().get(format!("http://{}/img.png", address)).send().await.unwrap();
+        assert_eq!(response.content_length(), None);
+        response
+    }
+
+    #[test]
+    fn allowed_hosts_match_exactly_or_on_any_port() {
+        let allowed = ["img.example.com".to_string(), "cdn.example.com:8443".to_string()];
+        let cases = [
+            ("img.example.com", true),
+            ("IMG.Example.com", true),
+            // An entry without a port matches the host on any port
+            ("img.example.com:8080", true),
+            ("cdn.example.com:8443", true),
+            // An entry with a port only matches that port
+            ("cdn.example.com", false),
+            ("cdn.example.com:443", false),
+            ("other.example.com", false),
+            ("img.example.com.evil.test", false),
+            ("", false),
+        ];
+        for (host, expected) in cases {
+            assert_eq!(is_allowed_host(&allowed, host), expected, "{}", host);
+        }
+    }
+
+    #[test]
+    fn paths_without_a_file_name_are_not_images() {
+        for path in ["/", "/foo/", "/favicon", "/img-original/img/2024", "/robots.", "/a.b/c"] {
+            assert!(is_non_image_path(path), "{}", path);
+        }
+        for path in [IMAGE_PATH, "/favicon.ico", "/a/b.c"] {
+            assert!(!is_non_image_path(path), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn non_image_paths_never_reach_upstream() {
+        let harness = Harness::start(&[("LANDING_RESPONSE", "pixiv image proxy")], serving(png(), "image/png")).await;
+        for path in ["/foo/", "/img-original/img/123_p0"] {
+            let response = harness.get(path, &[]).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+            assert_eq!(body_bytes(response).await, "pixiv image proxy");
+        }
+        assert_eq!(harness.upstream_hits(), 0);
+    }
+
+    #[test]
+    fn ambiguous_bytes_get_the_content_type_configured_for_their_family() {
+        let config = Config::for_tests(&[("CONTENT_TYPE_FAMILY_OVERRIDES", "riff=image/webp,png=image/png")]).transform;
+        let riff: &[u8] = b"RIFF\0\0\0\0WAVEfmt ";
+        let png_signature: &[u8] = b"\x89PNG\r\n\x1a\n";
+        let isobmff: &[u8] = b"\0\0\0\x18ftypmp42\0\0\0\0";
+
+        // Upstream's header wins, then the family override, then the extension
+        assert_eq!(resolve_content_type(Some("image/jpeg"), riff, "/a.bin", &config), "image/jpeg");
+        assert_eq!(resolve_content_type(Some("application/octet-stream"), riff, "/a.bin", &config), "image/webp");
+        assert_eq!(resolve_content_type(None, png_signature, "/a", &config), "image/png");
+        assert_eq!(resolve_content_type(None, isobmff, "/a.gif", &config), "image/gif");
+        assert_eq!(resolve_content_type(None, isobmff, "/a", &config), "application/octet-stream");
+
+        // Without an override an ambiguous family falls through to the extension
+        let plain = Config::for_tests(&[]).transform;
+        assert_eq!(resolve_content_type(None, riff, "/a.png", &plain), "image/png");
+    }
+
+    #[test]
+    fn webp_quality_is_checked_against_the_configured_range() {
+        let query = |q: &str| ProxyQuery { q: Some(q.to_string()), ..ProxyQuery::default() };
+        let range = Some((30, 90));
+
+        assert_eq!(query("50").webp_quality(range), Ok(Some(50)));
+        assert_eq!(query("30").webp_quality(range), Ok(Some(30)));
+        assert_eq!(query("90").webp_quality(range), Ok(Some(90)));
+        for q in ["29", "91", "300", "-1", "fifty", "50.5", ""] {
+            assert_eq!(query(q).webp_quality(range).unwrap_err().0, StatusCode::BAD_REQUEST, "{}", q);
+        }
+        // Without a range, `q` is ignored whatever it holds
+        assert_eq!(query("fifty").webp_quality(None), Ok(None));
+        assert_eq!(ProxyQuery::default().webp_quality(range), Ok(None));
+
+        let requested = |quality| transform::VariantRequest { variant: transform::Variant::Webp, quality };
+        assert_eq!(requested(Some(50)).token(), "webp-q50");
+        assert_eq!(requested(None).token(), "webp");
+        assert_eq!(requested(Some(50)).key(IMAGE_PATH), format!("{}@webp-q50", IMAGE_PATH));
+    }
+
+    #[tokio::test]
+    async fn invalid_webp_quality_is_refused_with_400() {
+        let harness = Harness::start(&[("WEBP_QUERY_QUALITY_RANGE", "30-90")], serving(png(), "image/png")).await;
+        for q in ["10", "abc"] {
+            let response = harness.get(&format!("{}?q={}", IMAGE_PATH, q), &[]).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", q);
+        }
+        assert_eq!(harness.upstream_hits(), 0);
+
+        let response = harness.get(&format!("{}?q=50", IMAGE_PATH), &[]).await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        harness.stored(&format!("{}@webp-q50", IMAGE_PATH)).await;
+    }
+
+    #[tokio::test]
+    async fn chunked_body_within_limit_is_read_whole() {
+        let body: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+        let response = chunked_upstream(body, 8).await;
+        let data = read_bounded_body(response, "/img.png", None, body.len() as u64).await.unwrap();
+        assert_eq!(data, body);
+    }
+
+    #[tokio::test]
+    async fn chunked_body_over_limit_is_cut_off() {
+        let response = chunked_upstream(b"0123456789abcdefghijklmnopqrstuvwxyz", 8).await;
+        let error = read_bounded_body(response, "/img.png", None, 20).await.unwrap_err();
+        assert!(error.downcast_ref::<BodyTooLarge>().is_some());
+        assert_eq!(upstream_error_kind(&error), "too_large");
+    }
+
+    #[tokio::test]
+    async fn failed_store_is_retried_without_refetching() {
+        let image = png();
+        let harness = Harness::start(&[("STORE_RETRIES", "2")], serving(image.clone(), "image/png")).await;
+        harness.s3.fail_next(Method::PUT, StatusCode::SERVICE_UNAVAILABLE);
+
+        let response = harness.get(IMAGE_PATH, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_bytes(response).await, image);
+
+        assert_eq!(harness.stored(IMAGE_PATH).await.data, image);
+        assert_eq!(harness.s3.count(Method::PUT), 2);
+        assert_eq!(harness.upstream_hits(), 1);
+        eventually(|| harness.redis.keys("lock:store:").is_empty()).await;
+    }
+
+    #[tokio::test]
+    async fn purges_need_only_the_admin_token_when_the_tokens_differ() {
+        let harness = Harness::start(
+            &[("PROXY_AUTH_TOKEN", "proxy-token"), ("ADMIN_TOKEN", "admin-token")],
+            serving(png(), "image/png"),
+        ).await;
+        // Routed and guarded as in main
+        let app = Router::new()
+            .route("/admin/purge/{*path}", axum::routing::delete(crate::admin::purge_handler))
+            .route("/{*path}", get(proxy_handler))
+            .layer(axum::middleware::from_fn_with_state(harness.state.clone(), auth_guard))
+            .with_state(harness.state.clone());
+        let send = |method: Method, uri: String, token: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {}", token));
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(send(Method::GET, IMAGE_PATH.to_string(), "proxy-token").await.unwrap().status(), StatusCode::OK);
+        harness.stored(IMAGE_PATH).await;
+
+        let purge = format!("/admin/purge{}", IMAGE_PATH);
+        assert_eq!(send(Method::DELETE, purge.clone(), "proxy-token").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert!(harness.s3.object(IMAGE_PATH).is_some());
+
+        let response = send(Method::DELETE, purge, "admin-token").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(report["deleted"], serde_json::json!([IMAGE_PATH]));
+        assert!(harness.s3.object(IMAGE_PATH).is_none());
+    }
+
+    #[tokio::test]
+    async fn artwork_purges_clear_the_burst_cache() {
+        let settings = [("ADMIN_TOKEN", "admin-token"), ("ARTWORK_ID_PATTERN", r"/(\d+)_p\d+"), ("BURST_CACHE_TTL", "60")];
+        let harness = Harness::start(&settings, serving(png(), "image/png")).await;
+        assert_eq!(harness.get(IMAGE_PATH, &[]).await.status(), StatusCode::OK);
+        harness.stored(IMAGE_PATH).await;
+        eventually(|| !harness.redis.keys("burst:").is_empty()).await;
+
+        let app = Router::new()
+            .route("/admin/purge-artwork/{id}", axum::routing::post(crate::admin::purge_artwork_handler))
+            .with_state(harness.state.clone());
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/admin/purge-artwork/123")
+            .header(header::AUTHORIZATION, "Bearer admin-token");
+        let response = app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(report["deleted"], serde_json::json!([IMAGE_PATH]));
+        assert_eq!(report["burst_cleared"], serde_json::json!([IMAGE_PATH]));
+        assert!(harness.redis.keys("burst:").is_empty());
+
+        // Served again from upstream, not from a stale burst copy
+        let response = harness.get(IMAGE_PATH, &[]).await;
+        assert_eq!(response.headers()["X-Cache-Status"], "MISS");
+        assert_eq!(harness.upstream_hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn signatures_cover_the_decoded_path_at_both_checks() {
+        let secret = "signing-secret";
+        let harness = Harness::start(
+            &[("PROXY_AUTH_TOKEN", "token"), ("URL_SIGNING_SECRET", secret)],
+            serving(png(), "image/png"),
+        ).await;
+        let app = Router::new()
+            .route("/{*path}", get(proxy_handler))
+            .layer(axum::middleware::from_fn_with_state(harness.state.clone(), auth_guard))
+            .with_state(harness.state.clone());
+        let send = |uri: String, bearer: bool| {
+            let mut request = Request::builder().uri(uri);
+            if bearer {
+                request = request.header(header::AUTHORIZATION, "Bearer token");
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        // An encoded `_` reaches the handler decoded; both checks must see the same path
+        let encoded = IMAGE_PATH.replace("123_p0", "123%5Fp0");
+        let signed = signing::signed_url(secret, &encoded, unix_now() + 60);
+        assert_eq!(send(signed, false).await.unwrap().status(), StatusCode::OK);
+
+        let expired = signing::signed_url(secret, IMAGE_PATH, unix_now() - 60);
+        assert_eq!(send(expired.clone(), false).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(send(expired, true).await.unwrap().status(), StatusCode::FORBIDDEN);
+
+        let tampered = signing::signed_url(secret, IMAGE_PATH, unix_now() + 60).replace("123_p0", "124_p0");
+        assert_eq!(send(tampered.clone(), false).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(send(tampered, true).await.unwrap().status(), StatusCode::FORBIDDEN);
+        assert_eq!(harness.upstream_hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn parent_proxy_is_asked_with_the_shared_token() {
+        let image = png();
+        let parent = Harness::start(&[("PROXY_AUTH_TOKEN", "shared")], serving(image.clone(), "image/png")).await;
+        let parent_url = parent.listen().await;
+
+        let child = Harness::start(
+            &[("PARENT_PROXY_URL", &parent_url), ("PROXY_AUTH_TOKEN", "shared")],
+            serving(image.clone(), "image/png"),
+        ).await;
+        let response = child.get(IMAGE_PATH, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_bytes(response).await, image);
+        assert_eq!((parent.upstream_hits(), child.upstream_hits()), (1, 0));
+
+        // A parent refusing the token is a failure of the parent, not a miss
+        let mismatched = Harness::start(
+            &[("PARENT_PROXY_URL", &parent_url), ("PROXY_AUTH_TOKEN", "other")],
+            serving(image.clone(), "image/png"),
+        ).await;
+        let response = mismatched.get(IMAGE_PATH, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_bytes(response).await, image);
+        assert_eq!((parent.upstream_hits(), mismatched.upstream_hits()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn upstream_gets_the_full_query_whatever_the_key_keeps() {
+        let modes = [
+            ("strip", IMAGE_PATH.to_string()),
+            ("allowlist", format!("{}?size=m", IMAGE_PATH)),
+            ("include", format!("{}?a=1&b=2&size=m", IMAGE_PATH)),
+        ];
+        for (mode, key) in modes {
+            let requested = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let upstream = Router::new().fallback({
+                let requested = requested.clone();
+                let image = png();
+                move |uri: axum::http::Uri| {
+                    requested.lock().unwrap().push(uri.to_string());
+                    let image = image.clone();
+                    async move { ([(header::CONTENT_TYPE, "image/png")], image) }
+                }
+            });
+            let harness = Harness::start(&[("QUERY_KEY_MODE", mode), ("QUERY_KEY_ALLOWLIST", "size")], upstream).await;
+
+            let response = harness.get(&format!("{}?b=2&a=1&size=m&download=1", IMAGE_PATH), &[]).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", mode);
+            assert_eq!(*requested.lock().unwrap(), [format!("{}?b=2&a=1&size=m", IMAGE_PATH)], "{}", mode);
+            harness.stored(&key).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn redis_outage_policies_fail_open_or_closed() {
+        for policy in ["open", "closed"] {
+            let closed = policy == "closed";
+            let image = png();
+
+            let harness = Harness::start(&[("REDIS_OUTAGE_NEGATIVE_CACHE", policy)], serving(image.clone(), "image/png")).await;
+            harness.redis.stop();
+            let expected = if closed { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::OK };
+            assert_eq!(harness.get(IMAGE_PATH, &[]).await.status(), expected, "negative cache {}", policy);
+
+            // The image is served either way; only the open policy stores it without the lock
+            let harness = Harness::start(&[("REDIS_OUTAGE_STORE_LOCK", policy)], serving(image.clone(), "image/png")).await;
+            harness.redis.stop();
+            let response = harness.get(IMAGE_PATH, &[]).await;
+            assert_eq!(response.status(), StatusCode::OK, "store lock {}", policy);
+            assert_eq!(body_bytes(response).await, image);
+            if closed {
+                // The upload slot is freed once the store gave up
+                eventually(|| harness.state.uploads.try_start(IMAGE_PATH).is_ok()).await;
+                assert_eq!(harness.s3.count(Method::PUT), 0);
+            } else {
+                harness.stored(IMAGE_PATH).await;
+            }
+
+            let harness = Harness::start(
+                &[("REDIS_OUTAGE_RATE_LIMIT", policy), ("RATE_LIMIT_PER_MINUTE", "100")],
+                serving(image.clone(), "image/png"),
+            ).await;
+            harness.redis.stop();
+            let app = Router::new()
+                .route("/{*path}", get(proxy_handler))
+                .layer(axum::middleware::from_fn_with_state(harness.state.clone(), rate_limit_guard))
+                .with_state(harness.state.clone());
+            let mut request = Request::builder().uri(IMAGE_PATH).body(Body::empty()).unwrap();
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+            let expected = if closed { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::OK };
+            assert_eq!(app.oneshot(request).await.unwrap().status(), expected, "rate limit {}", policy);
+        }
+    }
+
+    #[tokio::test]
+    async fn upstream_slow_to_respond_times_out_with_504() {
+        let image = png();
+        let hung_upstream = Router::new().fallback(move || {
+            let image = image.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                ([(header::CONTENT_TYPE, "image/png")], image)
+            }
+        });
+        let harness = Harness::start(&[("UPSTREAM_FIRST_BYTE_TIMEOUT_MS", "200")], hung_upstream).await;
+
+        let started = Instant::now();
+        let response = harness.get(IMAGE_PATH, &[]).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // A timeout says nothing about the path, so it is not negatively cached
+        assert!(harness.redis.keys("cache:").is_empty());
+    }
+
+    #[tokio::test]
+    async fn path_prefixes_are_fetched_from_their_upstream_host() {
+        let thumbnail = png();
+        let thumb_hits = Arc::new(AtomicUsize::new(0));
+        let thumb_upstream = Router::new().fallback({
+            let (thumbnail, thumb_hits) = (thumbnail.clone(), thumb_hits.clone());
+            move || {
+                thumb_hits.fetch_add(1, Ordering::SeqCst);
+                let thumbnail = thumbnail.clone();
+                async move { ([(header::CONTENT_TYPE, "image/png")], thumbnail) }
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rule = format!("/c/=http://{}", listener.local_addr().unwrap());
+        spawn(async move {
+            let _ = axum::serve(listener, thumb_upstream).await;
+        });
+        let harness = Harness::start(&[("UPSTREAM_HOST_RULES", &rule)], serving(png(), "image/png")).await;
+
+        let thumb_path = "/c/250x250_80_a2/img-master/img/2024/01/01/00/00/00/123_p0_square1200.jpg";
+        assert_eq!(harness.get(thumb_path, &[]).await.status(), StatusCode::OK);
+        assert_eq!((thumb_hits.load(Ordering::SeqCst), harness.upstream_hits()), (1, 0));
+
+        // Paths matching no rule go to the default host
+        assert_eq!(harness.get(IMAGE_PATH, &[]).await.status(), StatusCode::OK);
+        assert_eq!((thumb_hits.load(Ordering::SeqCst), harness.upstream_hits()), (1, 1));
+        assert_eq!(harness.state.config.upstream.all_hosts().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn uncacheable_content_types_pass_through_without_being_stored() {
+        let image = png();
+        let settings = [("NO_CACHE_CONTENT_TYPES", "image/*"), ("BURST_CACHE_TTL", "60")];
+        let harness = Harness::start(&settings, serving(image.clone(), "image/png")).await;
+
+        for _ in 0..2 {
+            let response = harness.get(IMAGE_PATH, &[]).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["X-Cache-Status"], "BYPASS");
+            assert_eq!(body_bytes(response).await, image);
+        }
+        assert_eq!(harness.upstream_hits(), 2);
+        // Give a store the time it would take before checking that none happened
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(harness.s3.count(Method::PUT), 0);
+        assert!(harness.redis.keys("burst:").is_empty());
+    }
+
+    #[tokio::test]
+    async fn fresh_writes_are_served_while_s3_does_not_show_them_yet() {
+        for (grace, upstream_hits) in [("5000", 1), ("0", 2)] {
+            let image = png();
+            let harness = Harness::start(&[("S3_CONSISTENCY_GRACE_MS", grace)], serving(image.clone(), "image/png")).await;
+            assert_eq!(harness.get(IMAGE_PATH, &[]).await.status(), StatusCode::OK);
+            harness.stored(IMAGE_PATH).await;
+
+            // An eventually consistent S3 that has not caught up with the write yet
+            harness.s3.fail_next(Method::HEAD, StatusCode::NOT_FOUND);
+            harness.s3.fail_next(Method::GET, StatusCode::NOT_FOUND);
+            let response = harness.get(IMAGE_PATH, &[]).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body_bytes(response).await, image);
+            assert_eq!(harness.upstream_hits(), upstream_hits, "grace {}", grace);
+        }
+    }
+
+    #[tokio::test]
+    async fn instances_share_one_upstream_fetch_through_redis() {
+        let image = png();
+        let slow_upstream = Router::new().fallback({
+            let image = image.clone();
+            move || {
+                let image = image.clone();
+                async move {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    ([(header::CONTENT_TYPE, "image/png")], image)
+                }
+            }
+        });
+        let first = Harness::start(&[("COALESCE_WINDOW_MS", "5000")], slow_upstream).await;
+        let second = first.another_instance().await;
+
+        let (a, b) = tokio::join!(first.get(IMAGE_PATH, &[]), async {
+            // Let the first instance take the fetch lock
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            second.get(IMAGE_PATH, &[]).await
+        });
+
+        assert_eq!((a.status(), b.status()), (StatusCode::OK, StatusCode::OK));
+        assert_eq!(body_bytes(a).await, image);
+        assert_eq!(body_bytes(b).await, image);
+        assert_eq!(first.upstream_hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn corrupt_stored_object_is_replaced_from_upstream() {
+        let image = png();
+        let key = crate::crypto::generate_encryption_key();
+        let settings = [
+            ("S3_ENCRYPTION_ENABLED", "true"),
+            ("S3_ENCRYPTION_KEY", key.as_str()),
+            ("SELF_HEAL_ON_CORRUPTION", "true"),
+            // Without the delete, write-once would keep the corrupt copy forever
+            ("STORAGE_WRITE_ONCE", "true"),
+        ];
+        let harness = Harness::start(&settings, serving(image.clone(), "image/png")).await;
+        let corrupt = [b"PXIP\x01\x00\x01\x00".as_slice(), &[0u8; 64]].concat();
+        harness.s3.insert(IMAGE_PATH, corrupt.clone(), Some("image/png"));
+
+        let response = harness.get(IMAGE_PATH, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_bytes(response).await, image);
+        assert_eq!(harness.upstream_hits(), 1);
+        assert_eq!(harness.state.stats.snapshot().await.corrupt_objects, 1);
+
+        eventually(|| harness.s3.object(IMAGE_PATH).is_some_and(|object| object.data != corrupt)).await;
+        let healed = harness.state.storage.get_stored_object(IMAGE_PATH).await.unwrap().unwrap();
+        assert_eq!(healed.data, image);
+    }
+
+    #[tokio::test]
+    async fn plaintext_object_is_served_and_reencrypted_on_read() {
+        let image = png();
+        let key = crate::crypto::generate_encryption_key();
+        let settings = [("S3_ENCRYPTION_ENABLED", "true"), ("S3_ENCRYPTION_KEY", key.as_str()), ("ENCRYPT_ON_READ_MIGRATION", "true")];
+        let harness = Harness::start(&settings, serving(Vec::new(), "image/png")).await;
+        // Stored before encryption was enabled
+        harness.s3.insert(IMAGE_PATH, image.clone(), Some("image/png"));
+
+        let response = harness.get(IMAGE_PATH, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_bytes(response).await, image);
+        assert_eq!(harness.upstream_hits(), 0);
+
+        eventually(|| harness.s3.object(IMAGE_PATH).is_some_and(|object| object.data.starts_with(b"PXIP"))).await;
+        let migrated = harness.state.storage.get_stored_object(IMAGE_PATH).await.unwrap().unwrap();
+        assert!(migrated.encrypted);
+        assert_eq!(migrated.data, image);
+    }
+
+    #[tokio::test]
+    async fn gzip_object_is_recompressed_with_zstd_on_read() {
+        use crate::{config::{CompressionConfig, EncryptionConfig}, crypto::CryptoProcessor};
+
+        let image = png();
+        let gzip = CompressionConfig { enabled: true, content_types: vec!["*".to_string()], ..CompressionConfig::default() };
+        let gzipped = CryptoProcessor::new(EncryptionConfig::default(), gzip, true, false, 1).unwrap()
+            .process_for_storage(Bytes::from(image.clone()), Some("image/png")).await.unwrap();
+        assert_eq!(gzipped[5], 1);
+
+        let settings = [
+            ("S3_COMPRESSION_ENABLED", "true"),
+            ("S3_COMPRESSION_ALGORITHM", "zstd"),
+            ("S3_COMPRESSION_CONTENT_TYPES", "*"),
+            ("RECOMPRESS_ON_READ", "true"),
+        ];
+        let harness = Harness::start(&settings, serving(Vec::new(), "image/png")).await;
+        harness.s3.insert(IMAGE_PATH, gzipped, Some("image/png"));
+
+        // Reads stay correct before and after the migration
+        let response = harness.get(IMAGE_PATH, &[]).await;
+        assert_eq!(body_bytes(response).await, image);
+        eventually(|| harness.s3.object(IMAGE_PATH).is_some_and(|object| object.data[5] == 3)).await;
+        let response = harness.get(IMAGE_PATH, &[]).await;
+        assert_eq!(body_bytes(response).await, image);
+        assert_eq!(harness.upstream_hits(), 0);
+
+        // Already current, so nothing is stored again
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(harness.s3.count(Method::PUT), 1);
+    }
+
+    #[tokio::test]
+    async fn brotli_svg_is_served_as_stored_only_to_clients_accepting_br() {
+        use std::io::Read;
+
+        const SVG_PATH: &str = "/img-original/img/2024/01/01/00/00/00/123_p0.svg";
+        let svg = b"<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"4\" height=\"4\"><rect width=\"4\" height=\"4\"/></svg>".to_vec();
+        let settings = [("SVG_BROTLI_ENABLED", "true")];
+        let harness = Harness::start(&settings, serving(svg.clone(), "image/svg+xml")).await;
+
+        let response = harness.get(SVG_PATH, &[]).await;
+        assert_eq!(body_bytes(response).await, svg);
+        assert_eq!(harness.stored(SVG_PATH).await.data[5], 2);
+
+        let response = harness.get(SVG_PATH, &[("Accept-Encoding", "gzip, br")]).await;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "br");
+        assert!(response.headers()[header::VARY].to_str().unwrap().contains("Accept-Encoding"));
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(&body_bytes(response).await[..], 4096).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, svg);
+
+        let response = harness.get(SVG_PATH, &[("Accept-Encoding", "gzip")]).await;
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(body_bytes(response).await, svg);
+        assert_eq!(harness.upstream_hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn gzip_object_is_passed_through_only_to_clients_accepting_gzip() {
+        use std::io::Read;
+
+        let settings = [
+            ("S3_COMPRESSION_ENABLED", "true"),
+            ("S3_COMPRESSION_CONTENT_TYPES", "image/png"),
+            ("S3_GZIP_PASSTHROUGH", "true"),
+        ];
+        let harness = Harness::start(&settings, serving(png(), "image/png")).await;
+
+        let response = harness.get(IMAGE_PATH, &[]).await;
+        assert_eq!(body_bytes(response).await, png());
+        assert_eq!(harness.stored(IMAGE_PATH).await.data[5], 1);
+
+        let response = harness.get(IMAGE_PATH, &[("Accept-Encoding", "gzip")]).await;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert!(response.headers()[header::VARY].to_str().unwrap().contains("Accept-Encoding"));
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&body_bytes(response).await[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, png());
+
+        for accept_encoding in ["identity", "gzip;q=0"] {
+            let response = harness.get(IMAGE_PATH, &[("Accept-Encoding", accept_encoding)]).await;
+            assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+            assert_eq!(body_bytes(response).await, png());
+        }
+        assert_eq!(harness.upstream_hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn variants_past_the_cap_are_served_but_not_stored() {
+        let settings = [("WEBP_QUERY_QUALITY_RANGE", "10-90"), ("MAX_VARIANTS_PER_ORIGINAL", "2")];
+        let harness = Harness::start(&settings, serving(png(), "image/png")).await;
+
+        for quality in [50, 60] {
+            let response = harness.get(&format!("{}?q={}", IMAGE_PATH, quality), &[]).await;
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+            harness.stored(&format!("{}@webp-q{}", IMAGE_PATH, quality)).await;
+        }
+        assert_eq!(harness.s3.count(Method::PUT), 3);
+
+        let response = harness.get(&format!("{}?q=70", IMAGE_PATH), &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        assert!(!body_bytes(response).await.is_empty());
+        // Nothing signals the skipped store; give it the time a store takes
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(harness.s3.object(&format!("{}@webp-q70", IMAGE_PATH)).is_none());
+        assert_eq!(harness.s3.count(Method::PUT), 3);
+        assert_eq!(harness.upstream_hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn if_none_match_only_matches_the_negotiated_variant() {
+        let harness = Harness::start(&[("FORMAT_PREFERENCES", "webp")], serving(png(), "image/png")).await;
+        let etag = |response: &Response<Body>| response.headers()[header::ETAG].to_str().unwrap().to_string();
+
+        let webp = harness.get(IMAGE_PATH, &[("Accept", "image/webp")]).await;
+        assert_eq!(webp.headers()[header::CONTENT_TYPE], "image/webp");
+        let webp_etag = etag(&webp);
+        let png_response = harness.get(IMAGE_PATH, &[("Accept", "image/png")]).await;
+        let png_etag = etag(&png_response);
+        assert_ne!(webp_etag, png_etag);
+        eventually(|| harness.s3.count(Method::PUT) == 2).await;
+
+        // The same validator from a client negotiating the other variant gets the full response
+        let response = harness.get(IMAGE_PATH, &[("Accept", "image/png"), ("If-None-Match", &webp_etag)]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        assert_eq!(body_bytes(response).await, png());
+        let response = harness.get(IMAGE_PATH, &[("Accept", "image/webp"), ("If-None-Match", &png_etag)]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+
+        for (accept, validator) in [("image/webp", &webp_etag), ("image/png", &png_etag)] {
+            let response = harness.get(IMAGE_PATH, &[("Accept", accept), ("If-None-Match", validator)]).await;
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{}", accept);
+            assert!(response.headers()[header::VARY].to_str().unwrap().contains("Accept"));
+        }
+    }
+
+    #[tokio::test]
+    async fn objects_are_read_from_the_tier_recorded_for_them() {
+        let settings = [("COLD_TIER_BUCKET", crate::storage::testing::BUCKET), ("COLD_TIER_PREFIX", "cold/")];
+        let harness = Harness::start(&settings, serving(png(), "image/png")).await;
+
+        harness.get(IMAGE_PATH, &[]).await;
+        let stored = harness.stored(IMAGE_PATH).await;
+        let response = harness.get(IMAGE_PATH, &[]).await;
+        assert_eq!(body_bytes(response).await, png());
+
+        // Move the object the way the migration does
+        harness.s3.insert(&format!("cold{}", IMAGE_PATH), stored.data, stored.content_type.as_deref());
+        harness.state.cache.mark_cold(IMAGE_PATH).await.unwrap();
+        harness.state.storage.delete_object(IMAGE_PATH).await.unwrap();
+
+        let response = harness.get(IMAGE_PATH, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_bytes(response).await, png());
+        assert!(harness.s3.object(IMAGE_PATH).is_none());
+        assert_eq!(harness.upstream_hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn every_stored_response_path_carries_the_same_etag() {
+        let harness = Harness::start(&[("STREAM_THRESHOLD_BYTES", "1")], serving(png(), "image/png")).await;
+        let etag = |response: &Response<Body>| response.headers()[header::ETAG].clone();
+
+        // A miss streamed from upstream is hashed only once it has gone out
+        let streamed_miss = harness.get(IMAGE_PATH, &[]).await;
+        assert_eq!(streamed_miss.headers()["X-Cache-Status"], "MISS");
+        assert!(!streamed_miss.headers().contains_key(header::ETAG));
+        assert_eq!(body_bytes(streamed_miss).await, png());
+        harness.stored(IMAGE_PATH).await;
+        let expected = HeaderValue::from_str(&content_etag(&harness.state.config, &png())).unwrap();
+
+        let streamed_hit = harness.get(IMAGE_PATH, &[]).await;
+        assert_eq!(etag(&streamed_hit), expected);
+        let ranged = harness.get(IMAGE_PATH, &[("Range", "bytes=0-3")]).await;
+        assert_eq!(ranged.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(etag(&ranged), expected);
+
+        let mut config = harness.state.config.clone();
+        config.server.stream_threshold_bytes = 0;
+        let buffered = Harness {
+            state: Harness::state(config).await,
+            s3: harness.s3.clone(),
+            redis: harness.redis.clone(),
+            upstream_hits: harness.upstream_hits.clone(),
+        };
+        let buffered_hit = buffered.get(IMAGE_PATH, &[]).await;
+        assert_eq!(etag(&buffered_hit), expected);
+
+        // The validator from a streamed hit is honoured by a buffered hit
+        let expected = expected.to_str().unwrap();
+        let response = buffered.get(IMAGE_PATH, &[("If-None-Match", expected)]).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(harness.upstream_hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn key_space_samples_are_exported_as_gauges_on_metrics() {
+        // The recorder is process-wide; no other test samples the key space
+        let handle = crate::telemetry::install().unwrap();
+        let harness = Harness::start(&[], serving(png(), "image/png")).await;
+        harness.s3.insert("/a.png", vec![0u8; 10], None);
+        harness.s3.insert("/b.png", vec![0u8; 32], None);
+        harness.state.cache.cache_not_found("/missing.png").await.unwrap();
+
+        let mut state = harness.state.clone();
+        state.metrics = Some(handle);
+        let app = Router::new().route("/metrics", get(crate::telemetry::metrics_handler)).with_state(state);
+        let metrics = || async {
+            let response = app.clone().oneshot(Request::get("/metrics").body(Body::empty()).unwrap()).await.unwrap();
+            String::from_utf8(body_bytes(response).await.to_vec()).unwrap()
+        };
+        let gauge = |metrics: &str, name: &str| metrics.lines().find_map(|line| line.strip_prefix(&format!("{} ", name))).map(str::to_string);
+
+        harness.state.stats.refresh().await;
+        let sampled = metrics().await;
+        assert_eq!(gauge(&sampled, "keyspace_stored_objects").as_deref(), Some("2"));
+        assert_eq!(gauge(&sampled, "keyspace_stored_bytes").as_deref(), Some("42"));
+        assert_eq!(gauge(&sampled, "keyspace_negative_entries").as_deref(), Some("1"));
+        assert_eq!(gauge(&sampled, "keyspace_redis_keys"), None);
+
+        // DBSIZE counts every Redis key instead of the negative entries
+        let mut config = harness.state.config.clone();
+        config.stats.redis_method = "dbsize".to_string();
+        let dbsize = Harness::state(config).await;
+        harness.s3.insert("/c.png", vec![0u8; 8], None);
+        dbsize.stats.refresh().await;
+        let resampled = metrics().await;
+        assert_eq!(gauge(&resampled, "keyspace_stored_objects").as_deref(), Some("3"));
+        assert_eq!(gauge(&resampled, "keyspace_stored_bytes").as_deref(), Some("50"));
+        assert_eq!(gauge(&resampled, "keyspace_redis_keys").as_deref(), Some("1"));
+        // A count the sample did not take keeps its last value
+        assert_eq!(gauge(&resampled, "keyspace_negative_entries").as_deref(), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn thumbnails_are_served_after_the_original_was_cached() {
+        let harness = Harness::start(
+            &[("GENERATE_THUMBNAIL_ON_STORE", "true"), ("MEMORY_CACHE_MB", "16"), ("BURST_CACHE_TTL", "60")],
+            serving(png(), "image/png"),
+        ).await;
+        assert_eq!(body_bytes(harness.get(IMAGE_PATH, &[]).await).await, png());
+        harness.stored(&transform::thumbnail_key(IMAGE_PATH)).await;
+        // The original now sits in both the memory and the burst cache
+        assert!(harness.state.memory_cache.get(IMAGE_PATH).is_some());
+        eventually(|| !harness.redis.keys("burst:").is_empty()).await;
+
+        let response = harness.get(&format!("{}?preset=thumb", IMAGE_PATH), &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        assert_eq!(image::guess_format(&body_bytes(response).await).unwrap(), ImageFormat::WebP);
+        assert_eq!(harness.upstream_hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_changed_body_under_the_same_key_gets_a_new_etag() {
+        let harness = Harness::start(&[], serving(png(), "image/png")).await;
+        let original = harness.get(IMAGE_PATH, &[]).await.headers()[header::ETAG].to_str().unwrap().to_string();
+        harness.stored(IMAGE_PATH).await;
+
+        let mut repainted = Cursor::new(Vec::new());
+        image::RgbaImage::from_pixel(4, 4, image::Rgba([30, 30, 200, 255])).write_to(&mut repainted, ImageFormat::Png).unwrap();
+        let repainted = repainted.into_inner();
+        harness.s3.insert(IMAGE_PATH, repainted.clone(), Some("image/png"));
+
+        // The old validator no longer matches, so the client gets the new body
+        let response = harness.get(IMAGE_PATH, &[("If-None-Match", &original)]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG].to_str().unwrap().to_string();
+        assert_ne!(etag, original);
+        assert_eq!(etag, content_etag(&harness.state.config, &repainted));
+        assert_eq!(body_bytes(response).await, repainted);
+        assert_eq!(harness.upstream_hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn range_requests_get_206_416_or_the_whole_body() {
+        let image = png();
+        let length = image.len();
+        // Read from S3 with a ranged GET, and sliced after a compressed object is read whole
+        let plain: &[(&str, &str)] = &[];
+        let compressed: &[(&str, &str)] = &[("S3_COMPRESSION_ENABLED", "true"), ("S3_COMPRESSION_CONTENT_TYPES", "*")];
+        for settings in [plain, compressed] {
+            let harness = Harness::start(settings, serving(image.clone(), "image/png")).await;
+            let etag = harness.get(IMAGE_PATH, &[]).await.headers()[header::ETAG].to_str().unwrap().to_string();
+            harness.stored(IMAGE_PATH).await;
+
+            for (range, expected) in [("bytes=0-3", 0..4), ("bytes=-4", length - 4..length), ("bytes=8-", 8..length)] {
+                let response = harness.get(IMAGE_PATH, &[("Range", range)]).await;
+                assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT, "{}", range);
+                let content_range = format!("bytes {}-{}/{}", expected.start, expected.end - 1, length);
+                assert_eq!(response.headers()[header::CONTENT_RANGE], content_range.as_str());
+                assert_eq!(body_bytes(response).await, image[expected]);
+            }
+
+            let response = harness.get(IMAGE_PATH, &[("Range", &format!("bytes={}-", length))]).await;
+            assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+            assert_eq!(response.headers()[header::CONTENT_RANGE], format!("bytes */{}", length).as_str());
+
+            // Multi-range requests and stale If-Range validators get the whole image
+            for headers in [&[("Range", "bytes=0-1,4-5")][..], &[("Range", "bytes=0-3"), ("If-Range", "\"stale\"")][..]] {
+                let response = harness.get(IMAGE_PATH, headers).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(body_bytes(response).await, image);
+            }
+            let response = harness.get(IMAGE_PATH, &[("Range", "bytes=0-3"), ("If-Range", &etag)]).await;
+            assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(body_bytes(response).await, image[..4]);
+            assert_eq!(harness.upstream_hits(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_range_missing_from_s3_is_forwarded_upstream_and_the_object_stored_whole() {
+        let image = png();
+        let ranges = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let upstream = Router::new().fallback({
+            let (image, ranges) = (image.clone(), ranges.clone());
+            move |headers: HeaderMap| {
+                let (image, ranges) = (image.clone(), ranges.clone());
+                async move {
+                    let range = headers.get(header::RANGE).map(|value| value.to_str().unwrap().to_string());
+                    ranges.lock().unwrap().push(range.clone());
+                    let Some((start, end)) = ByteRange::parse(&headers).and_then(|range| range.resolve(image.len() as u64)) else {
+                        return ([(header::CONTENT_TYPE, "image/png")], image).into_response();
+                    };
+                    let content_range = format!("bytes {}-{}/{}", start, end, image.len());
+                    let body = image[start as usize..=end as usize].to_vec();
+                    (StatusCode::PARTIAL_CONTENT, [(header::CONTENT_TYPE, "image/png".to_string()), (header::CONTENT_RANGE, content_range)], body).into_response()
+                }
+            }
+        });
+        let harness = Harness::start(&[], upstream).await;
+
+        let response = harness.get(IMAGE_PATH, &[("Range", "bytes=2-5")]).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], format!("bytes 2-5/{}", image.len()).as_str());
+        assert_eq!(response.headers()["X-Cache-Status"], "MISS");
+        assert_eq!(body_bytes(response).await, image[2..6]);
+
+        assert_eq!(harness.stored(IMAGE_PATH).await.data, image);
+        assert_eq!(*ranges.lock().unwrap(), [Some("bytes=2-5".to_string()), None]);
+
+        let response = harness.get(IMAGE_PATH, &[("Range", "bytes=2-5")]).await;
+        assert_eq!(body_bytes(response).await, image[2..6]);
+        assert_eq!(harness.upstream_hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn streamed_bodies_are_sliced_as_they_arrive() {
+        let chunks = ["0123", "4567", "89ab", "cdef"].map(|chunk| Ok::<_, std::io::Error>(Bytes::from(chunk)));
+        let body = Body::from_stream(futures::stream::iter(chunks));
+        let sliced = axum::body::to_bytes(slice_body(body, 3, 9), usize::MAX).await.unwrap();
+        assert_eq!(sliced, "3456789");
+
+        // Reading stops once the range is complete, so a body failing after it does not matter
+        let failing = futures::stream::iter([Ok(Bytes::from("0123")), Err(std::io::Error::other("reset"))]);
+        let sliced = axum::body::to_bytes(slice_body(Body::from_stream(failing), 1, 2), usize::MAX).await.unwrap();
+        assert_eq!(sliced, "12");
+    }
+
+    #[tokio::test]
+    async fn options_lists_the_allowed_methods_and_leaves_preflights_to_cors() {
+        let harness = Harness::start(&[("RANGE_REQUESTS_ENABLED", "false")], serving(png(), "image/png")).await;
+
+        let response = harness.request(Method::OPTIONS, IMAGE_PATH, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let allow: Vec<&str> = response.headers()[header::ALLOW].to_str().unwrap().split(", ").collect();
+        assert_eq!(allow, ["GET", "HEAD", "OPTIONS"]);
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "none");
+        let capabilities: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(capabilities["methods"], serde_json::json!(["GET", "HEAD", "OPTIONS"]));
+        assert_eq!(capabilities["byte_ranges"], false);
+        assert_eq!(harness.upstream_hits(), 0);
+
+        // A preflight is answered by the CORS layer before reaching the handler
+        let app = Router::new()
+            .route("/{*path}", get(proxy_handler).options(options_handler))
+            .layer(tower_http::cors::CorsLayer::permissive())
+            .with_state(harness.state.clone());
+        let preflight = Request::builder()
+            .method(Method::OPTIONS)
+            .uri(IMAGE_PATH)
+            .header(header::ORIGIN, "https://example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(preflight).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
+        assert!(!response.headers().contains_key(header::ALLOW));
+    }
+
+    #[tokio::test]
+    async fn fleet_counters_sum_the_flushes_of_every_instance() {
+        let first = Harness::start(&[("FLEET_STATS_ENABLED", "true")], serving(png(), "image/png")).await;
+        let second = first.another_instance().await;
+
+        assert_eq!(first.get(IMAGE_PATH, &[]).await.headers()["X-Cache-Status"], "MISS");
+        first.stored(IMAGE_PATH).await;
+        for instance in [&first, &second, &second] {
+            assert_eq!(instance.get(IMAGE_PATH, &[]).await.status(), StatusCode::OK);
+        }
+
+        let (own_first, own_second) = (first.state.stats.snapshot().await, second.state.stats.snapshot().await);
+        assert_eq!((own_first.hits, own_first.misses), (1, 1));
+        assert_eq!((own_second.hits, own_second.misses), (2, 0));
+
+        // Flushing again sends nothing new, so nothing is counted twice
+        for instance in [&first, &second, &first] {
+            instance.state.stats.flush().await;
+        }
+        for instance in [&first, &second] {
+            let fleet = instance.state.stats.snapshot().await.fleet.expect("fleet counters are read");
+            assert_eq!((fleet.hits, fleet.misses, fleet.corrupt_objects), (3, 1, 0));
+        }
+
+        second.get(IMAGE_PATH, &[]).await;
+        second.state.stats.flush().await;
+        let fleet = first.state.stats.snapshot().await.fleet.unwrap();
+        assert_eq!((fleet.hits, fleet.misses), (4, 1));
+        assert_eq!(first.state.stats.snapshot().await.hits, 1);
+    }
+
+    #[tokio::test]
+    async fn a_broad_outage_opens_the_circuit_instead_of_caching_every_path() {
+        let failing = Router::new().fallback(|| async { StatusCode::INTERNAL_SERVER_ERROR });
+        let harness = Harness::start(&[("ERROR_CIRCUIT_THRESHOLD", "3"), ("UPSTREAM_MAX_RETRIES", "0")], failing).await;
+        let path = |artwork: usize| format!("/img-original/img/2024/01/01/00/00/00/{}_p0.png", artwork);
+
+        for artwork in 0..20 {
+            let status = harness.get(&path(artwork), &[]).await.status();
+            assert!(status.is_server_error(), "{}: {}", artwork, status);
+        }
+
+        // Errors below the threshold are cached per path; the one reaching it opens the circuit
+        // and every later path is turned away without asking upstream
+        assert_eq!(harness.upstream_hits(), 3);
+        let mut cached = harness.redis.keys("cache:");
+        cached.sort();
+        assert_eq!(cached, [format!("cache:{}", path(0)), format!("cache:{}", path(1))]);
+        assert_eq!(harness.redis.keys("circuit:upstream").len(), 1);
+        assert_eq!(harness.get(&path(2), &[]).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(harness.upstream_hits(), 3);
+
+        // Other instances of the fleet see the same circuit
+        let other = harness.another_instance().await;
+        assert_eq!(other.get(&path(99), &[]).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(harness.upstream_hits(), 3);
+    }
+
+    #[tokio::test]
+    async fn revalidation_stores_the_image_once_upstream_recovers() {
+        let healthy = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let image = png();
+        let upstream = Router::new().fallback({
+            let (healthy, image) = (healthy.clone(), image.clone());
+            move || {
+                let (healthy, image) = (healthy.clone(), image.clone());
+                async move {
+                    if !healthy.load(Ordering::SeqCst) {
+                        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                    }
+                    ([(header::CONTENT_TYPE, "image/png")], image).into_response()
+                }
+            }
+        });
+        let harness = Harness::start(&[("SERVER_ERROR_REVALIDATE_SECS", "60"), ("UPSTREAM_MAX_RETRIES", "0")], upstream).await;
+
+        assert_eq!(harness.get(IMAGE_PATH, &[]).await.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(harness.redis.keys("cache:").len(), 1);
+
+        // The cached error is still served while the retry stores the image behind it
+        healthy.store(true, Ordering::SeqCst);
+        let response = harness.get(IMAGE_PATH, &[]).await;
+        assert_eq!(response.headers()["X-Cache-Reason"], "upstream-error");
+        assert_eq!(harness.stored(IMAGE_PATH).await.data, image);
+        eventually(|| harness.redis.keys("cache:").is_empty()).await;
+        assert_eq!(harness.upstream_hits(), 2);
+
+        let response = harness.get(IMAGE_PATH, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_bytes(response).await, image);
+        assert_eq!(harness.upstream_hits(), 2);
+    }
+}
@@ -0,0 +1,57 @@
+use axum::http::{HeaderMap, header};
+
+/// A single `bytes=` range from a `Range` header, before it is resolved against a length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    From(u64, Option<u64>), // `start-` or `start-end`
+    Suffix(u64),            // `-length`: the last `length` bytes
+}
+
+impl ByteRange {
+    /// The range a request asks for. Only single ranges are honoured: multi-range requests
+    /// and malformed headers get `None`, and with it the whole body, as RFC 9110 allows.
+    pub fn parse(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get(header::RANGE)?.to_str().ok()?;
+        let spec = value.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+
+        let (start, end) = spec.split_once('-')?;
+        match (start.trim(), end.trim()) {
+            ("", "") => None,
+            ("", suffix) => suffix.parse().ok().map(ByteRange::Suffix),
+            (start, "") => start.parse().ok().map(|start| ByteRange::From(start, None)),
+            (start, end) => {
+                let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                (start <= end).then_some(ByteRange::From(start, Some(end)))
+            },
+        }
+    }
+
+    /// Inclusive bounds within a body of `length` bytes, or `None` when the range is unsatisfiable.
+    pub fn resolve(self, length: u64) -> Option<(u64, u64)> {
+        match self {
+            ByteRange::From(start, end) if start < length => {
+                Some((start, end.map_or(length - 1, |end| end.min(length - 1))))
+            },
+            ByteRange::Suffix(suffix) if suffix > 0 && length > 0 => {
+                Some((length.saturating_sub(suffix), length - 1))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Whether a ranged response may be served: without `If-Range`, or when it names `etag`, the
+/// `ETag` of the representation. Dates are never honoured: `Last-Modified` is the store time
+/// of our copy, which differs between instances and copies, so it is too weak a validator.
+pub fn if_range_matches(request: &HeaderMap, etag: Option<&str>) -> bool {
+    let Some(if_range) = request.get(header::IF_RANGE).and_then(|value| value.to_str().ok()) else {
+        return true;
+    };
+
+    // Strong comparison: weak validators never match
+    let if_range = if_range.trim();
+    !if_range.starts_with("W/") && etag.is_some_and(|etag| etag == if_range)
+}
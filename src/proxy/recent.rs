@@ -0,0 +1,72 @@
+use bytes::Bytes;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Stored bytes plus `None` while the store is still in flight, then the time it completed
+type Entry = (Bytes, Option<Instant>);
+
+/// Objects this instance fetched and stored recently, kept for a short grace window.
+///
+/// Some S3-compatible stores are only eventually consistent, so a read right after
+/// `put_object` may still 404. Serving from here during that window avoids a
+/// redundant upstream fetch.
+#[derive(Clone)]
+pub struct RecentWrites {
+    window: Duration,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl RecentWrites {
+    pub fn new(window_ms: u64) -> Self {
+        Self {
+            window: Duration::from_millis(window_ms),
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    /// Remember freshly fetched bytes while their store is in flight.
+    pub fn insert(&self, path: &str, data: Bytes) {
+        if !self.enabled() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|_, (_, stored_at)| self.is_live(*stored_at));
+        entries.insert(path.to_string(), (data, None));
+    }
+
+    /// Start the grace window once the store has completed.
+    pub fn mark_stored(&self, path: &str) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((_, stored_at)) = entries.get_mut(path) {
+            *stored_at = Some(Instant::now());
+        }
+    }
+
+    /// Forget an entry whose store failed.
+    pub fn remove(&self, path: &str) {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).remove(path);
+    }
+
+    pub fn get(&self, path: &str) -> Option<Bytes> {
+        if !self.enabled() {
+            return None;
+        }
+
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.get(path)
+            .filter(|(_, stored_at)| self.is_live(*stored_at))
+            .map(|(data, _)| data.clone())
+    }
+
+    fn is_live(&self, stored_at: Option<Instant>) -> bool {
+        stored_at.is_none_or(|at| at.elapsed() < self.window)
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{Result, anyhow};
+use regex::{Captures, Regex};
+use std::{borrow::Cow, sync::Arc};
+use tracing::debug;
+
+use crate::config::RewriteRule;
+
+/// Compiled legacy path rewrites, applied before any cache or upstream lookup so
+/// that every key uses the canonical path.
+#[derive(Clone)]
+pub struct PathRewriter {
+    rules: Arc<Vec<(Regex, String)>>,
+    hex_segments: Option<Regex>,
+}
+
+impl PathRewriter {
+    pub fn new(rules: &[RewriteRule], hex_segment_pattern: Option<&str>) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let regex = Regex::new(&rule.pattern)
+                    .map_err(|e| anyhow!("Invalid path rewrite pattern '{}': {}", rule.pattern, e))?;
+                Ok((regex, rule.replacement.clone()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let hex_segments = hex_segment_pattern
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|e| anyhow!("Invalid hex segment pattern '{}': {}", pattern, e))
+            })
+            .transpose()?;
+
+        Ok(Self { rules: Arc::new(rules), hex_segments })
+    }
+
+    /// Lowercase the parts of `path` matching the hex segment pattern, leaving the rest as is.
+    /// Pixiv hashes are always lowercase, so this only folds clients' uppercased copies together.
+    pub fn normalize_hex_segments<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        match &self.hex_segments {
+            Some(regex) => regex.replace_all(path, |captures: &Captures| captures[0].to_lowercase()),
+            None => Cow::Borrowed(path),
+        }
+    }
+
+    /// Apply the first matching rule; paths matching no rule are returned unchanged.
+    pub fn rewrite(&self, path: &str) -> String {
+        for (regex, replacement) in self.rules.iter() {
+            if regex.is_match(path) {
+                let rewritten = regex.replace(path, replacement.as_str()).into_owned();
+                debug!("Rewrote path {} to {}", path, rewritten);
+                return rewritten;
+            }
+        }
+        path.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str, replacement: &str) -> RewriteRule {
+        RewriteRule { pattern: pattern.to_string(), replacement: replacement.to_string() }
+    }
+
+    #[test]
+    fn first_matching_rule_rewrites_with_captures() {
+        let rewriter = PathRewriter::new(
+            &[
+                rule(r"^/img/(\d+)\.jpg$", "/img-original/img/${1}_p0.jpg"),
+                rule(r"^/img/(.*)$", "/unreachable/$1"),
+            ],
+            None,
+        ).unwrap();
+
+        assert_eq!(rewriter.rewrite("/img/12345.jpg"), "/img-original/img/12345_p0.jpg");
+        assert_eq!(rewriter.rewrite("/img/other.png"), "/unreachable/other.png");
+    }
+
+    #[test]
+    fn unmatched_paths_are_unchanged() {
+        let rewriter = PathRewriter::new(&[rule(r"^/legacy/(.*)$", "/$1")], None).unwrap();
+        assert_eq!(rewriter.rewrite("/img-original/img/1_p0.png"), "/img-original/img/1_p0.png");
+    }
+
+    #[test]
+    fn invalid_patterns_fail_construction() {
+        let error = PathRewriter::new(&[rule("(unclosed", "/")], None).err().unwrap();
+        assert!(error.to_string().contains("Invalid path rewrite pattern '(unclosed'"));
+        assert!(PathRewriter::new(&[], Some("[")).is_err());
+    }
+
+    #[test]
+    fn hex_segments_are_lowercased() {
+        let rewriter = PathRewriter::new(&[], Some("[0-9A-Fa-f]{32}")).unwrap();
+        assert_eq!(
+            rewriter.normalize_hex_segments("/c/ABCDEF0123456789ABCDEF0123456789/Img.png"),
+            "/c/abcdef0123456789abcdef0123456789/Img.png"
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use anyhow::{Result, anyhow};
+use regex::Regex;
+use std::sync::Arc;
+
+/// URL shapes served by i.pximg.net, used when no templates are configured.
+const BUILTIN_TEMPLATES: &[&str] = &[
+    "/img-original/img/{date}/{id}_p{n}.{ext}",
+    "/img-master/img/{date}/{id}_p{n}_{suffix}.{ext}",
+    "/c/{size}/img-master/img/{date}/{id}_p{n}_{suffix}.{ext}",
+    "/c/{size}/custom-thumb/img/{date}/{id}_p{n}_{suffix}.{ext}",
+    "/img-zip-ugoira/img/{date}/{id}_ugoira{size}.{ext}",
+    "/user-profile/img/{date}/{name}.{ext}",
+];
+
+// What each placeholder matches; paths never contain anything else between the literals
+fn segment_pattern(placeholder: &str) -> Option<&'static str> {
+    match placeholder {
+        "date" => Some(r"\d{4}/\d{2}/\d{2}/\d{2}/\d{2}/\d{2}"),
+        "id" | "n" => Some(r"\d+"),
+        "ext" => Some(r"[A-Za-z0-9]+"),
+        "size" => Some(r"\d+x\d+(?:_[A-Za-z0-9]+)*"),
+        "suffix" => Some(r"[a-z]+\d*"),
+        "name" => Some(r"[A-Za-z0-9_-]+"),
+        _ => None,
+    }
+}
+
+/// Request paths the proxy accepts, compiled from templates such as
+/// `/img-original/img/{date}/{id}_p{n}.{ext}` with typed placeholders. Stricter than a
+/// prefix allowlist: anything but the exact shapes listed is refused before any work.
+#[derive(Clone)]
+pub struct PathTemplates {
+    templates: Arc<Vec<Regex>>,
+}
+
+impl PathTemplates {
+    /// Compile `templates`, or the built-in Pixiv templates when it is empty, plus raw
+    /// `patterns` for shapes the placeholders cannot express. Patterns must match the whole
+    /// path. With `enabled` unset every path is accepted.
+    pub fn new(enabled: bool, templates: &[String], patterns: &[String]) -> Result<Self> {
+        if !enabled {
+            return Ok(Self { templates: Arc::new(Vec::new()) });
+        }
+
+        let mut compiled = if templates.is_empty() {
+            BUILTIN_TEMPLATES.iter().map(|template| compile(template)).collect::<Result<Vec<_>>>()?
+        } else {
+            templates.iter().map(|template| compile(template)).collect::<Result<Vec<_>>>()?
+        };
+        for pattern in patterns {
+            let regex = Regex::new(&format!("^(?:{})$", pattern))
+                .map_err(|e| anyhow!("Invalid path pattern '{}': {}", pattern, e))?;
+            compiled.push(regex);
+        }
+        Ok(Self { templates: Arc::new(compiled) })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.templates.is_empty()
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        !self.enabled() || self.templates.iter().any(|regex| regex.is_match(path))
+    }
+}
+
+// Literal parts are matched exactly; `{placeholder}` parts by their type
+fn compile(template: &str) -> Result<Regex> {
+    if !template.starts_with('/') {
+        return Err(anyhow!("Invalid path template '{}': must start with '/'", template));
+    }
+
+    let mut pattern = String::from("^");
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        pattern.push_str(&regex::escape(&rest[..start]));
+        let end = rest[start..].find('}')
+            .ok_or_else(|| anyhow!("Invalid path template '{}': unclosed placeholder", template))?;
+        let placeholder = &rest[start + 1..start + end];
+        let segment = segment_pattern(placeholder)
+            .ok_or_else(|| anyhow!("Invalid path template '{}': unknown placeholder {{{}}}", template, placeholder))?;
+        pattern.push_str(&format!("(?:{})", segment));
+        rest = &rest[start + end + 1..];
+    }
+    if rest.contains('}') {
+        return Err(anyhow!("Invalid path template '{}': unmatched '}}'", template));
+    }
+    pattern.push_str(&regex::escape(rest));
+    pattern.push('$');
+
+    Regex::new(&pattern).map_err(|e| anyhow!("Invalid path template '{}': {}", template, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builtin() -> PathTemplates {
+        PathTemplates::new(true, &[], &[]).unwrap()
+    }
+
+    #[test]
+    fn builtin_templates_accept_pixiv_paths() {
+        let templates = builtin();
+        for path in [
+            "/img-original/img/2024/01/01/00/00/00/123_p0.png",
+            "/img-master/img/2024/01/01/00/00/00/123_p12_master1200.jpg",
+            "/c/250x250_80_a2/img-master/img/2024/01/01/00/00/00/123_p0_square1200.jpg",
+            "/c/360x360_70/custom-thumb/img/2024/01/01/00/00/00/123_p0_custom1200.jpg",
+            "/img-zip-ugoira/img/2024/01/01/00/00/00/123_ugoira600x600.zip",
+            "/user-profile/img/2024/01/01/00/00/00/some-user_170.png",
+        ] {
+            assert!(templates.matches(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn builtin_templates_refuse_other_paths() {
+        let templates = builtin();
+        for path in [
+            "/",
+            "/img-original/img/2024/01/01/00/00/123_p0.png",
+            "/img-original/img/2024/01/01/00/00/00/abc_p0.png",
+            "/img-original/img/2024/01/01/00/00/00/123_p0.png/extra",
+            "/img-original/img/2024/01/01/00/00/00/../../123_p0.png",
+            "/img-master/img/2024/01/01/00/00/00/123_p0.jpg",
+            "/prefix/img-original/img/2024/01/01/00/00/00/123_p0.png",
+            "/admin/stats",
+        ] {
+            assert!(!templates.matches(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn configured_templates_and_patterns_replace_the_builtin_ones() {
+        let templates = PathTemplates::new(
+            true,
+            &["/art/{id}.{ext}".to_string()],
+            &[r"/img-original/img/\d+_v2\.png".to_string()],
+        ).unwrap();
+        assert!(templates.matches("/art/42.png"));
+        assert!(templates.matches("/img-original/img/42_v2.png"));
+        assert!(!templates.matches("/img-original/img/2024/01/01/00/00/00/123_p0.png"));
+
+        // Disabled templates accept anything
+        assert!(PathTemplates::new(false, &[], &[]).unwrap().matches("/anything"));
+    }
+
+    #[test]
+    fn invalid_templates_fail_construction() {
+        for template in ["art/{id}.png", "/art/{id.png", "/art/{color}.png", "/art/id}.png"] {
+            assert!(PathTemplates::new(true, &[template.to_string()], &[]).is_err(), "{}", template);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Slots for background S3 stores: at most a fixed number run at once, and only one per key.
+///
+/// Stores are fire-and-forget, so without a bound a traffic spike would open as many
+/// concurrent S3 writes as there are misses. A store that finds no free slot is dropped;
+/// the object is fetched and stored again on a later miss.
+#[derive(Clone)]
+pub struct BackgroundUploads {
+    permits: Option<Arc<Semaphore>>, // None when unlimited
+    in_flight: Arc<Mutex<HashSet<String>>>,
+}
+
+/// A claimed slot, released when the store finishes.
+pub struct UploadSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+    key: String,
+    in_flight: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Drop for UploadSlot {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.key);
+    }
+}
+
+/// Why no slot was claimed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadRejected {
+    InFlight, // The same key is already being stored
+    Busy,     // Every slot is taken
+}
+
+impl BackgroundUploads {
+    pub fn new(max: usize) -> Self {
+        Self {
+            permits: (max > 0).then(|| Arc::new(Semaphore::new(max))),
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn try_start(&self, key: &str) -> Result<UploadSlot, UploadRejected> {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        if in_flight.contains(key) {
+            return Err(UploadRejected::InFlight);
+        }
+
+        let permit = match &self.permits {
+            Some(permits) => Some(permits.clone().try_acquire_owned().map_err(|_| UploadRejected::Busy)?),
+            None => None,
+        };
+        in_flight.insert(key.to_string());
+
+        Ok(UploadSlot {
+            _permit: permit,
+            key: key.to_string(),
+            in_flight: self.in_flight.clone(),
+        })
+    }
+}
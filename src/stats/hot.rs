@@ -0,0 +1,116 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::health::unix_now;
+
+// Counters kept per reported path; the extra slots absorb the long tail
+const TRACKED_PER_REPORTED: usize = 10;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HotPath {
+    pub path: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HotPathsReport {
+    pub since: u64, // Unix time the counts started from
+    pub paths: Vec<HotPath>,
+}
+
+struct Counters {
+    counts: HashMap<String, u64>,
+    started: Instant,
+    since: u64,
+}
+
+impl Counters {
+    fn new() -> Self {
+        Self {
+            counts: HashMap::new(),
+            started: Instant::now(),
+            since: unix_now(),
+        }
+    }
+}
+
+/// Approximate request counts of the most requested paths, in bounded memory.
+///
+/// Uses the space-saving algorithm: a fixed number of counters, where a new path
+/// replaces the least counted one and inherits its count. Hot paths are always
+/// kept, but their counts may be slightly overestimated.
+#[derive(Clone)]
+pub struct HotPaths {
+    top_n: usize,
+    window: Option<Duration>,
+    counters: Arc<Mutex<Counters>>,
+}
+
+impl HotPaths {
+    pub fn new(top_n: usize, window_secs: u64) -> Self {
+        Self {
+            top_n,
+            window: (window_secs > 0).then(|| Duration::from_secs(window_secs)),
+            counters: Arc::new(Mutex::new(Counters::new())),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.top_n > 0
+    }
+
+    pub fn record(&self, path: &str) {
+        if !self.enabled() {
+            return;
+        }
+
+        let mut counters = self.lock();
+        if let Some(count) = counters.counts.get_mut(path) {
+            *count += 1;
+            return;
+        }
+
+        let mut count = 1;
+        if counters.counts.len() >= self.top_n * TRACKED_PER_REPORTED {
+            let coldest = counters.counts
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(path, count)| (path.clone(), *count));
+            if let Some((coldest, coldest_count)) = coldest {
+                counters.counts.remove(&coldest);
+                count += coldest_count;
+            }
+        }
+        counters.counts.insert(path.to_string(), count);
+    }
+
+    pub fn snapshot(&self) -> HotPathsReport {
+        let counters = self.lock();
+
+        let mut paths: Vec<HotPath> = counters.counts
+            .iter()
+            .map(|(path, count)| HotPath { path: path.clone(), count: *count })
+            .collect();
+        paths.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.path.cmp(&b.path)));
+        paths.truncate(self.top_n);
+
+        HotPathsReport { since: counters.since, paths }
+    }
+
+    pub fn reset(&self) {
+        *self.counters.lock().unwrap_or_else(|e| e.into_inner()) = Counters::new();
+    }
+
+    // Counts start over once the window has passed
+    fn lock(&self) -> std::sync::MutexGuard<'_, Counters> {
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        if self.window.is_some_and(|window| counters.started.elapsed() >= window) {
+            *counters = Counters::new();
+        }
+        counters
+    }
+}
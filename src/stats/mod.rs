@@ -0,0 +1,259 @@
+mod hot;
+
+pub use hot::{HotPaths, HotPathsReport};
+
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, atomic::{AtomicU64, Ordering}},
+    time::Duration,
+};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::{
+    cache::KVStore,
+    config::StatsConfig,
+    health::unix_now,
+    storage::S3Storage,
+};
+
+/// Approximate size of the cache key space, refreshed in the background.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeySpaceStats {
+    pub stored_objects: Option<u64>,
+    pub stored_bytes: Option<u64>,
+    pub stored_truncated: bool,
+    pub negative_entries: Option<u64>,
+    pub negative_truncated: bool,
+    pub redis_keys: Option<u64>,
+    pub sampled_at: Option<u64>,
+    pub corrupt_objects: u64, // Stored objects found corrupt since startup
+    pub hits: u64,            // Requests served without an upstream fetch since startup
+    pub misses: u64,          // Requests fetched from upstream since startup
+    pub fleet: Option<FleetCounters>, // Totals across all instances, when fleet stats are enabled
+}
+
+/// Counters summed across every instance in Redis.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FleetCounters {
+    pub hits: u64,
+    pub misses: u64,
+    pub corrupt_objects: u64,
+}
+
+// Redis counter names, in the order of `Counter::load`
+const COUNTER_NAMES: [&str; 3] = ["hits", "misses", "corrupt_objects"];
+
+// A per-instance total plus how much of it has already been added to the fleet counters
+#[derive(Default)]
+struct Counter {
+    total: AtomicU64,
+    flushed: AtomicU64,
+}
+
+impl Counter {
+    fn increment(&self) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone)]
+pub struct StatsCollector {
+    config: StatsConfig,
+    storage: S3Storage,
+    cache: KVStore,
+    latest: Arc<RwLock<KeySpaceStats>>,
+    counters: Arc<[Counter; 3]>, // Indexed like COUNTER_NAMES
+    flush_lock: Arc<Mutex<()>>,  // Keeps the shutdown flush from racing a periodic one
+}
+
+impl StatsCollector {
+    pub fn new(config: StatsConfig, storage: S3Storage, cache: KVStore) -> Self {
+        Self {
+            config,
+            storage,
+            cache,
+            latest: Arc::new(RwLock::new(KeySpaceStats::default())),
+            counters: Arc::new(Default::default()),
+            flush_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Add this instance's counts to the fleet-wide Redis counters on the configured interval.
+    pub fn spawn_flush(&self) {
+        if !self.config.fleet_stats {
+            return;
+        }
+
+        let collector = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(collector.config.flush_interval.max(1)));
+            loop {
+                interval.tick().await;
+                collector.flush().await;
+            }
+        });
+    }
+
+    /// Add whatever was counted since the last flush to the fleet counters. Only deltas are
+    /// sent, so a failed flush is simply retried by the next one and nothing is counted twice.
+    pub async fn flush(&self) {
+        if !self.config.fleet_stats {
+            return;
+        }
+
+        let _guard = self.flush_lock.lock().await;
+        let deltas: Vec<(&str, u64)> = COUNTER_NAMES
+            .iter()
+            .zip(self.counters.iter())
+            .map(|(name, counter)| (*name, counter.load() - counter.flushed.load(Ordering::Relaxed)))
+            .collect();
+        if deltas.iter().all(|(_, delta)| *delta == 0) {
+            return;
+        }
+
+        match self.cache.increment_counters(&deltas).await {
+            Ok(()) => {
+                for (counter, (_, delta)) in self.counters.iter().zip(&deltas) {
+                    counter.flushed.fetch_add(*delta, Ordering::Relaxed);
+                }
+                debug!("Flushed stats counters to Redis: {:?}", deltas);
+            },
+            Err(e) => warn!("Failed to flush stats counters to Redis: {}", e),
+        }
+    }
+
+    /// Sample the key space on the configured interval; a zero interval disables sampling.
+    pub fn spawn(&self) {
+        if self.config.interval == 0 {
+            info!("Key space sampling disabled");
+            return;
+        }
+
+        let collector = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(collector.config.interval));
+            loop {
+                interval.tick().await;
+                collector.refresh().await;
+            }
+        });
+    }
+
+    /// Take a new sample, unless another instance shared one recently enough to reuse.
+    pub async fn refresh(&self) {
+        let shared = self.config.shared_sample_secs;
+        if shared > 0 {
+            match self.cache.get_shared_sample().await {
+                Ok(Some(sample)) => match serde_json::from_str::<KeySpaceStats>(&sample) {
+                    Ok(stats) => {
+                        debug!("Reusing the shared key space sample");
+                        self.publish(stats).await;
+                        return;
+                    },
+                    Err(e) => warn!("Ignoring unreadable shared key space sample: {}", e),
+                },
+                Ok(None) => {},
+                Err(e) => warn!("{}", e),
+            }
+        }
+
+        let mut stats = KeySpaceStats {
+            sampled_at: Some(unix_now()),
+            ..Default::default()
+        };
+
+        match self.storage.count_objects(self.config.s3_max_pages).await {
+            Ok((objects, bytes, truncated)) => {
+                stats.stored_objects = Some(objects);
+                stats.stored_bytes = Some(bytes);
+                stats.stored_truncated = truncated;
+            },
+            Err(e) => warn!("Failed to sample S3 object count: {}", e),
+        }
+
+        // SCAN counts only negative-cache entries but costs more than DBSIZE on large databases
+        if self.config.redis_method == "dbsize" {
+            match self.cache.db_size().await {
+                Ok(keys) => stats.redis_keys = Some(keys),
+                Err(e) => warn!("Failed to sample Redis key count: {}", e),
+            }
+        } else {
+            match self.cache.count_keys("cache:*", self.config.redis_scan_limit).await {
+                Ok((entries, truncated)) => {
+                    stats.negative_entries = Some(entries);
+                    stats.negative_truncated = truncated;
+                },
+                Err(e) => warn!("Failed to sample negative cache entries: {}", e),
+            }
+        }
+
+        if shared > 0 {
+            match serde_json::to_string(&stats) {
+                Ok(sample) => {
+                    if let Err(e) = self.cache.share_sample(&sample, shared).await {
+                        warn!("{}", e);
+                    }
+                },
+                Err(e) => warn!("Failed to serialize key space sample: {}", e),
+            }
+        }
+
+        self.publish(stats).await;
+    }
+
+    // Make a sample the latest one, for `/admin/stats` and as gauges on `/metrics`. Counts a
+    // sample could not take keep their previous gauge value.
+    async fn publish(&self, stats: KeySpaceStats) {
+        let gauges = [
+            ("keyspace_stored_objects", stats.stored_objects),
+            ("keyspace_stored_bytes", stats.stored_bytes),
+            ("keyspace_negative_entries", stats.negative_entries),
+            ("keyspace_redis_keys", stats.redis_keys),
+        ];
+        for (name, value) in gauges {
+            if let Some(value) = value {
+                metrics::gauge!(name).set(value as f64);
+            }
+        }
+        *self.latest.write().await = stats;
+    }
+
+    pub async fn snapshot(&self) -> KeySpaceStats {
+        let mut stats = self.latest.read().await.clone();
+        let [hits, misses, corrupt_objects] = &*self.counters;
+        stats.hits = hits.load();
+        stats.misses = misses.load();
+        stats.corrupt_objects = corrupt_objects.load();
+
+        if self.config.fleet_stats {
+            match self.cache.get_counters(&COUNTER_NAMES).await {
+                Ok(values) => {
+                    stats.fleet = Some(FleetCounters {
+                        hits: values[0],
+                        misses: values[1],
+                        corrupt_objects: values[2],
+                    });
+                },
+                Err(e) => warn!("Failed to read fleet stats counters: {}", e),
+            }
+        }
+        stats
+    }
+
+    pub fn record_hit(&self) {
+        self.counters[0].increment();
+    }
+
+    pub fn record_miss(&self) {
+        self.counters[1].increment();
+    }
+
+    pub fn record_corrupt_object(&self) {
+        self.counters[2].increment();
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::{Result, anyhow};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use super::S3Storage;
+use crate::cache::KVStore;
+use crate::config::ColdTierConfig;
+
+/// Secondary storage for objects that have not been stored or read for a while.
+///
+/// Every store and read of the hot bucket leaves a Redis access record that expires after
+/// the migration age. A background task walks the hot bucket one listing page per run and
+/// moves originals without an access record to the cold tier, recording the move so reads
+/// go straight to the cold tier afterwards.
+#[derive(Clone)]
+pub struct ColdTier {
+    storage: S3Storage,
+    config: ColdTierConfig,
+    shares_bucket: bool, // Cold objects live under a prefix of the hot bucket
+}
+
+impl ColdTier {
+    pub fn new(hot: &S3Storage, config: &ColdTierConfig) -> Result<Self> {
+        let storage = hot.for_cold_tier(config)?;
+        let shares_bucket = storage.bucket_name() == hot.bucket_name();
+        if shares_bucket && config.prefix.trim_matches('/').is_empty() {
+            return Err(anyhow!("COLD_TIER_PREFIX is required when the cold tier uses the main bucket"));
+        }
+
+        Ok(Self { storage, config: config.clone(), shares_bucket })
+    }
+
+    pub fn storage(&self) -> &S3Storage {
+        &self.storage
+    }
+
+    /// Key of the cold copy of the object stored under `key` in the hot bucket.
+    pub fn key(&self, key: &str) -> String {
+        format!("{}{}", self.config.prefix, key.strip_prefix('/').unwrap_or(key))
+    }
+
+    /// How long an access keeps an object in the hot bucket.
+    pub fn access_ttl(&self) -> u64 {
+        self.config.migration_age_secs
+    }
+
+    /// Move aged-out objects to the cold tier on the configured interval until the process exits.
+    pub fn spawn_migration(&self, hot: S3Storage, cache: KVStore) {
+        let tier = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(tier.config.migration_interval_secs));
+            let mut continuation = None;
+            loop {
+                interval.tick().await;
+                match tier.migrate_page(&hot, &cache, continuation.as_deref()).await {
+                    Ok((next, moved)) => {
+                        if moved > 0 {
+                            info!("Moved {} objects to the cold tier", moved);
+                        }
+                        continuation = next;
+                    },
+                    Err(e) => warn!("Cold tier migration failed: {}", e),
+                }
+            }
+        });
+    }
+
+    // Move the aged-out objects of one hot bucket listing page, returning the next page's token
+    async fn migrate_page(&self, hot: &S3Storage, cache: &KVStore, continuation: Option<&str>) -> Result<(Option<String>, u64)> {
+        let (objects, next) = hot.list_objects_page(continuation).await?;
+
+        // Variants and thumbnails are cheap to derive again, so only originals are moved
+        let candidates: Vec<String> = objects
+            .into_iter()
+            .filter(|object| !object.key.contains('@'))
+            .filter(|object| !(self.shares_bucket && object.key.starts_with(&self.config.prefix)))
+            .map(|object| format!("/{}", object.key))
+            .collect();
+        let accessed = cache.recently_accessed(&candidates).await?;
+
+        let mut moved = 0;
+        for (key, _) in candidates.iter().zip(accessed).filter(|(_, accessed)| !accessed) {
+            match self.migrate(hot, cache, key).await {
+                Ok(()) => moved += 1,
+                Err(e) => warn!("Failed to move {} to the cold tier: {}", key, e),
+            }
+        }
+        Ok((next, moved))
+    }
+
+    // Copied as stored, so no decrypt/re-encrypt round trip is needed. The tier is recorded
+    // before the hot copy is deleted, so concurrent reads find the object in one of them.
+    async fn migrate(&self, hot: &S3Storage, cache: &KVStore, key: &str) -> Result<()> {
+        let Some(data) = hot.get_raw_object(key).await? else {
+            return Ok(());
+        };
+
+        self.storage.put_raw_object(&self.key(key), data, None, None).await?;
+        cache.mark_cold(key).await?;
+        hot.delete_object(key).await?;
+        debug!("Moved {} to the cold tier", key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testing::FakeRedis;
+    use crate::config::Config;
+    use crate::storage::testing::FakeS3;
+
+    #[tokio::test]
+    async fn migration_moves_only_originals_nobody_read() {
+        let (s3, redis) = (FakeS3::start().await, FakeRedis::start().await);
+        let config = Config::for_tests(&s3.settings(&[("REDIS_URL", redis.url()), ("COLD_TIER_BUCKET", "pixiv"), ("COLD_TIER_PREFIX", "cold/")]));
+        let hot = S3Storage::new(&config.storage).await.unwrap();
+        let cache = KVStore::new(&config.cache).await.unwrap();
+        let tier = ColdTier::new(&hot, config.storage.cold_tier.as_ref().unwrap()).unwrap();
+
+        s3.insert("/stale.png", "stale", None);
+        s3.insert("/read.png", "read", None);
+        s3.insert("/stale.png@webp", "variant", None);
+        cache.record_access("/read.png", tier.access_ttl()).await.unwrap();
+
+        let (next, moved) = tier.migrate_page(&hot, &cache, None).await.unwrap();
+        assert_eq!((next, moved), (None, 1));
+        assert_eq!(s3.object("cold/stale.png").unwrap().data, "stale");
+        assert!(s3.object("stale.png").is_none());
+        assert!(cache.is_cold("/stale.png").await.unwrap());
+
+        assert!(s3.object("read.png").is_some());
+        assert!(!cache.is_cold("/read.png").await.unwrap());
+        assert!(s3.object("stale.png@webp").is_some());
+
+        // Cold objects under the shared bucket's prefix are not moved again
+        assert_eq!(tier.migrate_page(&hot, &cache, None).await.unwrap().1, 0);
+    }
+}
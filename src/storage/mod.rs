@@ -1,51 +1,180 @@
 use anyhow::{Result, anyhow};
 use bytes::Bytes;
 use reqwest::Client as HttpClient;
-use rusty_s3::{Bucket, Credentials, S3Action};
-use std::time::Duration;
-use tracing::{info, error};
+use rusty_s3::{Bucket, Credentials, S3Action, actions::ListObjectsV2};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, warn, error};
 
-use crate::config::StorageConfig;
-use crate::crypto::CryptoProcessor;
+mod cold;
+mod tags;
+#[cfg(test)]
+pub mod testing;
+
+pub use cold::ColdTier;
+
+use crate::config::{ColdTierConfig, ManifestSourceConfig, StorageConfig, load_client_identity};
+use crate::crypto::{CryptoProcessor, HEADER_LEN, KeepEncoded, ObjectHeader};
+use tags::ObjectTagger;
+
+/// Object body together with the time S3 last stored it.
+#[derive(Debug, Clone)]
+pub struct StoredObject {
+    pub data: Bytes,
+    pub last_modified: Option<SystemTime>,
+    pub content_type: Option<String>, // As given when the object was stored
+    pub content_encoding: Option<&'static str>, // `data` is still compressed, see `get_stored_object_encoded`
+    pub encrypted: bool,    // Whether the object was stored encrypted
+    pub stale_key: bool,    // Encrypted with an algorithm or key other than the current ones
+    pub compression: u8,    // Compression id from the object's crypto header
+}
+
+/// An object whose body is still arriving from S3, so a large plain object can be passed
+/// through to the client without buffering it.
+pub struct StreamedObject {
+    pub head: Bytes, // Start of the body, already read to check for a crypto header
+    pub content_length: Option<u64>,
+    pub last_modified: Option<SystemTime>,
+    pub content_type: Option<String>,
+    response: reqwest::Response,
+}
+
+impl StreamedObject {
+    /// The whole body, starting with `head`.
+    pub fn into_stream(self) -> impl futures::Stream<Item = reqwest::Result<Bytes>> {
+        let head = futures::stream::once(async move { Ok(self.head) });
+        let rest = futures::stream::unfold(Some(self.response), |response| async move {
+            let mut response = response?;
+            match response.chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk), Some(response))),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        });
+        futures::StreamExt::chain(head, rest)
+    }
+}
+
+/// A slice of a stored object read directly from S3, see `get_object_range`.
+pub struct RangedObject {
+    pub data: Bytes,
+    pub last_modified: Option<SystemTime>,
+    pub content_type: Option<String>,
+}
+
+/// A stored object read either completely or as a stream, see `get_stored_body`.
+pub enum StoredBody {
+    Buffered(StoredObject),
+    Streamed(StreamedObject),
+}
+
+/// A stored object that was read but failed decryption or decompression.
+#[derive(Debug)]
+pub struct CorruptObject {
+    pub key: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for CorruptObject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Stored object {} is corrupt: {}", self.key, self.reason)
+    }
+}
+
+impl std::error::Error for CorruptObject {}
+
+/// What a HEAD request reports about a stored object.
+#[derive(Debug, Clone)]
+pub struct ObjectHead {
+    pub size: u64,
+    pub etag: Option<String>, // Quoted hash of the decoded body, see `CONTENT_HASH_HEADER`, else S3's own ETag
+}
+
+/// User metadata holding the content hash of the decoded body, recorded when the proxy stores
+/// an object so the ETag of a response is known before its body is read.
+pub const CONTENT_HASH_HEADER: &str = "x-amz-meta-content-hash";
+
+/// One stored object as reported by a bucket listing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectSummary {
+    pub key: String,
+    pub size: u64,
+    #[serde(default)]
+    pub etag: String,
+    #[serde(default)]
+    pub last_modified: String,
+}
 
 #[derive(Clone)]
 pub struct S3Storage {
     client: HttpClient,
     bucket: Bucket,
     credentials: Credentials,
-    crypto_processor: Option<CryptoProcessor>,
+    crypto_processor: CryptoProcessor,
+    write_once: bool,
+    tagger: ObjectTagger,
+    storage_class: Option<String>, // x-amz-storage-class of uploads, unset for the bucket default
+    max_retries: u32,
+    retry_backoff: Duration,
 }
 
 impl S3Storage {
     pub async fn new(config: &StorageConfig) -> Result<Self> {
-        let client = HttpClient::builder()
-            .timeout(Duration::from_secs(30))
+        let mut builder = HttpClient::builder().timeout(Duration::from_secs(30));
+        if let Some(identity) = load_client_identity(config.client_cert.as_deref(), config.client_key.as_deref())? {
+            info!("Using TLS client certificate for S3");
+            builder = builder.identity(identity);
+        }
+        let client = builder
             .build()
             .map_err(|e| anyhow!("Failed to create HTTP client: {}", e))?;
 
-        let bucket = Bucket::new(
-            config.endpoint.parse().map_err(|e| anyhow!("Invalid S3 endpoint: {}", e))?,
-            rusty_s3::UrlStyle::Path,
-            config.bucket.clone(),
-            config.region.clone(),
-        ).map_err(|e| anyhow!("Failed to create S3 bucket: {}", e))?;
+        let bucket = build_bucket(&config.endpoint, &config.bucket, &config.region)?;
 
         let credentials = Credentials::new(&config.access_key, &config.secret_key);
 
-        // Initialize crypto processor if encryption or compression is enabled
-        let crypto_processor = if config.encryption.enabled || config.compression.enabled {
-            Some(CryptoProcessor::new(config.encryption.clone(), config.compression.clone())?)
-        } else {
-            None
-        };
+        // The crypto processor is always present so objects carrying a crypto header
+        // can be read back even after encryption/compression is disabled
+        let crypto_processor = CryptoProcessor::new(
+            config.encryption.clone(),
+            config.compression.clone(),
+            config.crypto_legacy_fallback,
+            config.encrypt_on_read_migration && config.encryption.enabled,
+            config.crypto_read_concurrency,
+        )?;
+
+        let tagger = ObjectTagger::new(config)?;
 
-        let storage = Self {
+        let mut storage = Self {
             client,
             bucket,
             credentials,
             crypto_processor,
+            write_once: config.write_once,
+            tagger,
+            storage_class: None,
+            max_retries: config.max_retries,
+            retry_backoff: Duration::from_millis(config.retry_backoff_ms),
         };
 
+        // Catch a wrong S3_REGION before it surfaces as a confusing bucket or upload failure
+        match storage.detect_bucket_region().await {
+            Ok(Some(region)) if region != config.region => {
+                if config.auto_region {
+                    warn!("S3 bucket '{}' is in region '{}', not '{}'; switching region automatically", config.bucket, region, config.region);
+                    storage.bucket = build_bucket(&config.endpoint, &config.bucket, &region)?;
+                } else {
+                    error!("S3 bucket '{}' is in region '{}' but S3_REGION is '{}'", config.bucket, region, config.region);
+                    return Err(anyhow!(
+                        "S3 region mismatch: set S3_REGION={} (or S3_AUTO_REGION=true to correct this automatically)",
+                        region
+                    ));
+                }
+            },
+            Ok(_) => {},
+            Err(e) => warn!("Could not determine S3 bucket region: {}", e),
+        }
+
         // Check if bucket exists and create if necessary
         info!("Checking S3 bucket: {}", config.bucket);
         match storage.ensure_bucket_exists().await {
@@ -64,6 +193,45 @@ impl S3Storage {
         Ok(storage)
     }
 
+    /// Ask S3 which region the bucket lives in, if the provider reports it.
+    ///
+    /// AWS returns the region in the `x-amz-bucket-region` header; some providers only
+    /// mention it in the XML error body of a redirect or malformed-authorization response.
+    pub async fn detect_bucket_region(&self) -> Result<Option<String>> {
+        let action = self.bucket.head_bucket(Some(&self.credentials));
+        let url = action.sign(Duration::from_secs(300));
+
+        let response = self.send("head_bucket", self.client.head(url)).await
+            .map_err(|e| anyhow!("Failed to connect to S3 endpoint: {}", e))?;
+
+        // A successful request proves the configured region works, whatever the provider reports
+        if response.status().is_success() {
+            return Ok(None);
+        }
+
+        if let Some(region) = bucket_region_header(response.headers()) {
+            return Ok(Some(region));
+        }
+
+        // HEAD responses carry no body, so repeat as a minimal listing to read the error message
+        if matches!(response.status().as_u16(), 301 | 307 | 400) {
+            let mut action = self.bucket.list_objects_v2(Some(&self.credentials));
+            action.with_max_keys(1);
+            let url = action.sign(Duration::from_secs(300));
+
+            let response = self.send("list", self.client.get(url)).await
+                .map_err(|e| anyhow!("Failed to connect to S3 endpoint: {}", e))?;
+            if let Some(region) = bucket_region_header(response.headers()) {
+                return Ok(Some(region));
+            }
+
+            let body = response.text().await.unwrap_or_default();
+            return Ok(parse_region_from_error(&body));
+        }
+
+        Ok(None)
+    }
+
     pub async fn ensure_bucket_exists(&self) -> Result<()> {
         // First, try to check if bucket exists by doing a HEAD request
         match self.check_bucket_exists().await {
@@ -87,7 +255,7 @@ impl S3Storage {
         let action = self.bucket.head_bucket(Some(&self.credentials));
         let url = action.sign(Duration::from_secs(300));
 
-        match self.client.head(url).send().await {
+        match self.send("head_bucket", self.client.head(url)).await {
             Ok(response) => {
                 match response.status().as_u16() {
                     200 => Ok(true),
@@ -104,7 +272,7 @@ impl S3Storage {
         let action = self.bucket.create_bucket(&self.credentials);
         let url = action.sign(Duration::from_secs(300));
 
-        match self.client.put(url).send().await {
+        match self.send("create_bucket", self.client.put(url)).await {
             Ok(response) => {
                 match response.status().as_u16() {
                     200 | 201 => {
@@ -126,26 +294,272 @@ impl S3Storage {
         }
     }
 
-    pub async fn get_object(&self, key: &str) -> Result<Option<Bytes>> {
+    /// Handle on another bucket sharing this client and crypto settings, without startup checks.
+    pub fn for_source(&self, source: &ManifestSourceConfig) -> Result<Self> {
+        Ok(Self {
+            client: self.client.clone(),
+            bucket: build_bucket(&source.endpoint, &source.bucket, &source.region)?,
+            credentials: Credentials::new(&source.access_key, &source.secret_key),
+            crypto_processor: self.crypto_processor.clone(),
+            write_once: self.write_once,
+            tagger: self.tagger.clone(),
+            storage_class: None,
+            max_retries: self.max_retries,
+            retry_backoff: self.retry_backoff,
+        })
+    }
+
+    /// Handle on the cold tier bucket, sharing this client and crypto settings.
+    pub fn for_cold_tier(&self, config: &ColdTierConfig) -> Result<Self> {
+        Ok(Self {
+            client: self.client.clone(),
+            bucket: build_bucket(&config.endpoint, &config.bucket, &config.region)?,
+            credentials: Credentials::new(&config.access_key, &config.secret_key),
+            crypto_processor: self.crypto_processor.clone(),
+            write_once: false,
+            tagger: self.tagger.clone(),
+            storage_class: config.storage_class.clone(),
+            max_retries: self.max_retries,
+            retry_backoff: self.retry_backoff,
+        })
+    }
+
+    pub fn bucket_name(&self) -> &str {
+        self.bucket.name()
+    }
+
+    pub fn crypto_processor(&self) -> &CryptoProcessor {
+        &self.crypto_processor
+    }
+
+    /// Fetch and decode an object along with its storage metadata.
+    pub async fn get_stored_object(&self, key: &str) -> Result<Option<StoredObject>> {
+        self.get_stored_object_encoded(key, KeepEncoded::default()).await
+    }
+
+    /// Fetch and decode an object, leaving it compressed when its at-rest compression is in `keep`.
+    pub async fn get_stored_object_encoded(&self, key: &str, keep: KeepEncoded) -> Result<Option<StoredObject>> {
+        match self.get_raw_stored_object(key).await? {
+            // Decrypt and/or decompress according to the object's crypto header
+            Some(object) => {
+                let retrieved = self.crypto_processor.process_for_retrieval_encoded(object.data, keep).await
+                    .map_err(|e| CorruptObject { key: key.to_string(), reason: e.to_string() })?;
+                Ok(Some(StoredObject {
+                    data: retrieved.data,
+                    content_encoding: retrieved.content_encoding,
+                    encrypted: retrieved.encrypted,
+                    stale_key: retrieved.stale_key,
+                    compression: retrieved.compression,
+                    ..object
+                }))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Fetch an object as a stream when it can be served as stored: the crypto pipeline is
+    /// disabled and the object carries no crypto header. Anything else is read completely
+    /// and decoded, like `get_stored_object_encoded`.
+    pub async fn get_stored_body(&self, key: &str, keep: KeepEncoded) -> Result<Option<StoredBody>> {
+        if self.crypto_processor.is_enabled() {
+            return Ok(self.get_stored_object_encoded(key, keep).await?.map(StoredBody::Buffered));
+        }
+
+        let normalized_key = key.strip_prefix('/').unwrap_or(key);
+        let action = self.bucket.get_object(Some(&self.credentials), normalized_key);
+        let url = action.sign(Duration::from_secs(3600));
+
+        let mut response = self.send("get", self.client.get(url)).await
+            .map_err(|e| anyhow!("Failed to get object: {}", e))?;
+        match response.status().as_u16() {
+            200 => {},
+            404 => return Ok(None),
+            status => {
+                error!("S3 GET request failed with status {}", status);
+                return Err(anyhow!("S3 GET request failed with status {}", status));
+            }
+        }
+
+        let last_modified = response.headers()
+            .get(reqwest::header::LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok());
+        let content_type = stored_content_type(response.headers());
+        let content_length = response.content_length();
+
+        // Objects written while the pipeline was enabled still need decoding
+        let mut head = Vec::new();
+        let mut finished = false;
+        while head.len() < HEADER_LEN {
+            match response.chunk().await.map_err(|e| anyhow!("Failed to read response body: {}", e))? {
+                Some(chunk) => head.extend_from_slice(&chunk),
+                None => {
+                    finished = true;
+                    break;
+                }
+            }
+        }
+        if finished || !matches!(ObjectHeader::parse(&head), Ok(None)) {
+            while let Some(chunk) = response.chunk().await.map_err(|e| anyhow!("Failed to read response body: {}", e))? {
+                head.extend_from_slice(&chunk);
+            }
+            let retrieved = self.crypto_processor.process_for_retrieval_encoded(Bytes::from(head), keep).await
+                .map_err(|e| CorruptObject { key: key.to_string(), reason: e.to_string() })?;
+            return Ok(Some(StoredBody::Buffered(StoredObject {
+                data: retrieved.data,
+                last_modified,
+                content_type,
+                content_encoding: retrieved.content_encoding,
+                encrypted: retrieved.encrypted,
+                stale_key: retrieved.stale_key,
+                compression: retrieved.compression,
+            })));
+        }
+
+        Ok(Some(StoredBody::Streamed(StreamedObject {
+            head: Bytes::from(head),
+            content_length,
+            last_modified,
+            content_type,
+            response,
+        })))
+    }
+
+    /// Read bytes `start..=end` of an object without fetching the rest. Only objects served as
+    /// stored can be sliced like this, so `None` is returned when the crypto pipeline is enabled
+    /// or the object carries a crypto header, and the caller reads the whole object instead.
+    pub async fn get_object_range(&self, key: &str, start: u64, end: u64) -> Result<Option<RangedObject>> {
+        if self.crypto_processor.is_enabled() {
+            return Ok(None);
+        }
+
+        // The first bytes tell whether the object carries a crypto header: a range starting
+        // within them is read from the beginning, any other range needs a separate probe
+        let header_len = HEADER_LEN as u64;
+        let from = if start < header_len {
+            0
+        } else {
+            match self.get_range(key, 0, header_len - 1).await? {
+                Some(probe) if probe.data.len() == HEADER_LEN && matches!(ObjectHeader::parse(&probe.data), Ok(None)) => start,
+                _ => return Ok(None),
+            }
+        };
+
+        let Some(mut object) = self.get_range(key, from, end.max(header_len - 1)).await? else {
+            return Ok(None);
+        };
+        if from == 0 {
+            if object.data.len() < HEADER_LEN || !matches!(ObjectHeader::parse(&object.data), Ok(None)) {
+                return Ok(None);
+            }
+            let end = (end as usize + 1).min(object.data.len());
+            object.data = object.data.slice(start as usize..end);
+        }
+        Ok(Some(object))
+    }
+
+    // A plain ranged GET; the body is exactly the range, also when S3 ignored the Range header
+    async fn get_range(&self, key: &str, start: u64, end: u64) -> Result<Option<RangedObject>> {
+        let normalized_key = key.strip_prefix('/').unwrap_or(key);
+        let action = self.bucket.get_object(Some(&self.credentials), normalized_key);
+        let url = action.sign(Duration::from_secs(3600));
+
+        let request = self.client.get(url).header(reqwest::header::RANGE, format!("bytes={}-{}", start, end));
+        let response = self.send("get_range", request).await
+            .map_err(|e| anyhow!("Failed to get object range: {}", e))?;
+        let status = response.status().as_u16();
+        match status {
+            200 | 206 => {},
+            // Unsatisfiable against the stored size; the caller decides on the decoded length instead
+            404 | 416 => return Ok(None),
+            status => {
+                error!("S3 ranged GET request failed with status {}", status);
+                return Err(anyhow!("S3 ranged GET request failed with status {}", status));
+            }
+        }
+
+        let last_modified = response.headers()
+            .get(reqwest::header::LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok());
+        let content_type = stored_content_type(response.headers());
+        let mut data = response.bytes().await
+            .map_err(|e| anyhow!("Failed to read response body: {}", e))?;
+        if status == 200 {
+            let end = (end as usize + 1).min(data.len());
+            data = data.slice((start as usize).min(end)..end);
+        }
+
+        Ok(Some(RangedObject { data, last_modified, content_type }))
+    }
+
+    pub async fn delete_object(&self, key: &str) -> Result<()> {
+        // Normalize the key by removing leading slash
+        let normalized_key = key.strip_prefix('/').unwrap_or(key);
+
+        let action = self.bucket.delete_object(Some(&self.credentials), normalized_key);
+        let url = action.sign(Duration::from_secs(3600));
+
+        match self.send("delete", self.client.delete(url)).await {
+            Ok(response) => {
+                match response.status().as_u16() {
+                    200 | 204 => {
+                        info!("Deleted object: {}", key);
+                        Ok(())
+                    },
+                    404 => {
+                        debug!("Object {} was already absent", key);
+                        Ok(())
+                    },
+                    403 => {
+                        error!("Access denied. Check S3 credentials and delete permissions for '{}'.", self.bucket.name());
+                        Err(anyhow!("Failed to delete object {}: access denied (HTTP 403)", key))
+                    },
+                    status => {
+                        error!("S3 DELETE request failed with status {}", status);
+                        Err(anyhow!("S3 DELETE request failed with status {}", status))
+                    }
+                }
+            },
+            Err(e) => {
+                error!("Failed to delete object {}: {}", key, e);
+                Err(anyhow!("Failed to delete object: {}", e))
+            }
+        }
+    }
+
+    /// Fetch the object exactly as stored, without running the crypto pipeline.
+    pub async fn get_raw_object(&self, key: &str) -> Result<Option<Bytes>> {
+        Ok(self.get_raw_stored_object(key).await?.map(|object| object.data))
+    }
+
+    async fn get_raw_stored_object(&self, key: &str) -> Result<Option<StoredObject>> {
         // Normalize the key by removing leading slash
         let normalized_key = key.strip_prefix('/').unwrap_or(key);
         
         let action = self.bucket.get_object(Some(&self.credentials), normalized_key);
         let url = action.sign(Duration::from_secs(3600));
 
-        match self.client.get(url).send().await {
+        match self.send("get", self.client.get(url)).await {
             Ok(response) => {
                 match response.status().as_u16() {
                     200 => {
-                        let mut data = response.bytes().await
+                        let last_modified = response.headers()
+                            .get(reqwest::header::LAST_MODIFIED)
+                            .and_then(|value| value.to_str().ok())
+                            .and_then(|value| httpdate::parse_http_date(value).ok());
+                        let content_type = stored_content_type(response.headers());
+                        let data = response.bytes().await
                             .map_err(|e| anyhow!("Failed to read response body: {}", e))?;
-                        
-                        // Decrypt and/or decompress if crypto processor is available
-                        if let Some(ref processor) = self.crypto_processor {
-                            data = processor.process_for_retrieval(data).await?;
-                        }
-                        
-                        Ok(Some(data))
+                        Ok(Some(StoredObject {
+                            data,
+                            last_modified,
+                            content_type,
+                            content_encoding: None,
+                            encrypted: false,
+                            stale_key: false,
+                            compression: 0,
+                        })) // Not decoded yet
                     },
                     404 => Ok(None),
                     status => {
@@ -161,15 +575,29 @@ impl S3Storage {
         }
     }
 
-    pub async fn put_object(&self, key: &str, mut data: Bytes, content_type: Option<&str>) -> Result<()> {
+    pub async fn put_object(&self, key: &str, data: Bytes, content_type: Option<&str>, content_hash: Option<&str>) -> Result<()> {
+        // In write-once mode the first stored copy wins and is never overwritten
+        if self.write_once && self.head_object(key).await? {
+            info!("Skipping store of {}: object already exists (write-once)", key);
+            return Ok(());
+        }
+
+        self.write_object(key, data, content_type, content_hash).await
+    }
+
+    /// Process and upload an object, replacing any existing copy even in write-once mode.
+    /// `content_hash` is the hash of `data` as given, before compression and encryption.
+    pub async fn write_object(&self, key: &str, data: Bytes, content_type: Option<&str>, content_hash: Option<&str>) -> Result<()> {
+        // Compress and/or encrypt if enabled
+        let data = self.crypto_processor.process_for_storage(data, content_type).await?;
+        self.put_raw_object(key, data, content_type, content_hash).await
+    }
+
+    /// Upload already-processed bytes as-is, bypassing the crypto pipeline and write-once checks.
+    pub async fn put_raw_object(&self, key: &str, data: Bytes, content_type: Option<&str>, content_hash: Option<&str>) -> Result<()> {
         // Normalize the key by removing leading slash
         let normalized_key = key.strip_prefix('/').unwrap_or(key);
         
-        // Compress and/or encrypt if crypto processor is available
-        if let Some(ref processor) = self.crypto_processor {
-            data = processor.process_for_storage(data).await?;
-        }
-        
         let action = self.bucket.put_object(Some(&self.credentials), normalized_key);
         let url = action.sign(Duration::from_secs(3600));
 
@@ -181,7 +609,20 @@ impl S3Storage {
             request = request.header("Content-Type", ct);
         }
 
-        match request.send().await {
+        if let Some(storage_class) = &self.storage_class {
+            request = request.header("x-amz-storage-class", storage_class);
+        }
+
+        if let Some(content_hash) = content_hash {
+            request = request.header(CONTENT_HASH_HEADER, content_hash);
+        }
+
+        // Tags for bucket lifecycle rules
+        if let Some(tagging) = self.tagger.tagging_for(key) {
+            request = request.header("x-amz-tagging", tagging);
+        }
+
+        match self.send("put", request).await {
             Ok(response) => {
                 if response.status().is_success() {
                     info!("Successfully stored object: {}", key);
@@ -209,18 +650,100 @@ impl S3Storage {
         }
     }
 
+    /// List one page of stored objects, returning the next continuation token if there are more.
+    pub async fn list_objects_page(&self, continuation: Option<&str>) -> Result<(Vec<ObjectSummary>, Option<String>)> {
+        self.list_objects("", continuation).await
+    }
+
+    /// List one page of the stored objects whose keys start with `prefix`, e.g. every derived
+    /// copy of an original with `<key>@`. Keys are reported without a leading slash.
+    pub async fn list_objects(&self, prefix: &str, continuation: Option<&str>) -> Result<(Vec<ObjectSummary>, Option<String>)> {
+        let mut action = self.bucket.list_objects_v2(Some(&self.credentials));
+        let prefix = prefix.strip_prefix('/').unwrap_or(prefix);
+        if !prefix.is_empty() {
+            action.with_prefix(prefix);
+        }
+        if let Some(token) = continuation {
+            action.with_continuation_token(token);
+        }
+        let url = action.sign(Duration::from_secs(300));
+
+        let response = self.send("list", self.client.get(url)).await
+            .map_err(|e| anyhow!("Failed to list objects: {}", e))?;
+        if !response.status().is_success() {
+            return Err(anyhow!("S3 list request failed with status {}", response.status()));
+        }
+
+        let body = response.bytes().await
+            .map_err(|e| anyhow!("Failed to read list response: {}", e))?;
+        let page = ListObjectsV2::parse_response(&body)
+            .map_err(|e| anyhow!("Failed to parse list response: {}", e))?;
+
+        let objects = page.contents
+            .into_iter()
+            .map(|object| ObjectSummary {
+                key: object.key,
+                size: object.size,
+                etag: object.etag,
+                last_modified: object.last_modified,
+            })
+            .collect();
+
+        Ok((objects, page.next_continuation_token))
+    }
+
+    /// Count stored objects and their total size, reading at most `max_pages` listing pages.
+    /// Returns `(objects, bytes, truncated)`.
+    pub async fn count_objects(&self, max_pages: u32) -> Result<(u64, u64, bool)> {
+        let mut objects: u64 = 0;
+        let mut bytes: u64 = 0;
+        let mut continuation: Option<String> = None;
+
+        for _ in 0..max_pages {
+            let (page, next) = self.list_objects_page(continuation.as_deref()).await?;
+
+            objects += page.len() as u64;
+            bytes += page.iter().map(|object| object.size).sum::<u64>();
+
+            match next {
+                Some(token) => continuation = Some(token),
+                None => return Ok((objects, bytes, false)),
+            }
+        }
+
+        Ok((objects, bytes, true))
+    }
+
     pub async fn head_object(&self, key: &str) -> Result<bool> {
+        Ok(self.object_head(key).await?.is_some())
+    }
+
+    /// Size and ETag of the stored object as S3 reports them, or `None` when there is no such object.
+    pub async fn object_head(&self, key: &str) -> Result<Option<ObjectHead>> {
         // Normalize the key by removing leading slash
         let normalized_key = key.strip_prefix('/').unwrap_or(key);
         
         let action = self.bucket.head_object(Some(&self.credentials), normalized_key);
         let url = action.sign(Duration::from_secs(3600));
 
-        match self.client.head(url).send().await {
+        match self.send("head", self.client.head(url)).await {
             Ok(response) => {
                 match response.status().as_u16() {
-                    200 => Ok(true),
-                    404 => Ok(false),
+                    200 => {
+                        let headers = response.headers();
+                        let size = headers
+                            .get(reqwest::header::CONTENT_LENGTH)
+                            .and_then(|value| value.to_str().ok())
+                            .and_then(|value| value.parse().ok())
+                            .unwrap_or(0);
+                        let text = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+                        // Objects copied as stored or written before hashes were recorded carry only S3's ETag
+                        let etag = text(CONTENT_HASH_HEADER)
+                            .map(|hash| format!("\"{}\"", hash))
+                            .or_else(|| text("etag").map(str::to_string));
+                        Ok(Some(ObjectHead { size, etag }))
+                    },
+                    404 => Ok(None),
                     status => {
                         error!("S3 HEAD request failed with status {}", status);
                         Err(anyhow!("S3 HEAD request failed with status {}", status))
@@ -233,4 +756,136 @@ impl S3Storage {
             }
         }
     }
-}
\ No newline at end of file
+
+    // Send an S3 request, retrying connection errors and 5xx answers with a doubling backoff.
+    // Request bodies are always in memory, already encrypted/compressed, so a retry resends
+    // the same bytes; a request that cannot be cloned is sent once. 4xx answers such as 403
+    // and 404 are returned as they are.
+    async fn send(&self, operation: &'static str, mut request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            let retry = if attempt < self.max_retries { request.try_clone() } else { None };
+            let result = self.send_once(operation, request).await;
+            let failure = match &result {
+                Ok(response) if response.status().is_server_error() => Some(format!("status {}", response.status().as_u16())),
+                Ok(_) => None,
+                Err(e) if e.is_connect() || e.is_request() => Some(e.to_string()),
+                Err(_) => None,
+            };
+
+            match (retry, failure) {
+                (Some(next), Some(failure)) => {
+                    attempt += 1;
+                    warn!(
+                        "S3 {} failed ({}), retrying in {:?} ({}/{})",
+                        operation, failure, backoff, attempt, self.max_retries
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    request = next;
+                },
+                _ => return result,
+            }
+        }
+    }
+
+    // Send one S3 request, recording its count by status and its latency per operation.
+    // Labels are limited to the operation and status, never the key.
+    async fn send_once(&self, operation: &'static str, request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+        let started = Instant::now();
+        let result = request.send().await;
+
+        let status = match &result {
+            Ok(response) => response.status().as_str().to_string(),
+            Err(_) => "error".to_string(),
+        };
+        metrics::counter!("s3_requests_total", "operation" => operation, "status" => status).increment(1);
+        metrics::histogram!("s3_request_duration_seconds", "operation" => operation)
+            .record(started.elapsed().as_secs_f64());
+
+        result
+    }
+}
+
+fn build_bucket(endpoint: &str, bucket: &str, region: &str) -> Result<Bucket> {
+    Bucket::new(
+        endpoint.parse().map_err(|e| anyhow!("Invalid S3 endpoint: {}", e))?,
+        rusty_s3::UrlStyle::Path,
+        bucket.to_string(),
+        region.to_string(),
+    ).map_err(|e| anyhow!("Failed to create S3 bucket: {}", e))
+}
+
+// Content type the object was stored with. S3 answers with a generic default for objects
+// stored without one, which says nothing about the body and is left out.
+fn stored_content_type(headers: &reqwest::header::HeaderMap) -> Option<String> {
+    headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !matches!(*value, "binary/octet-stream" | "application/octet-stream"))
+        .map(str::to_string)
+}
+
+fn bucket_region_header(headers: &reqwest::header::HeaderMap) -> Option<String> {
+    headers
+        .get("x-amz-bucket-region")
+        .and_then(|value| value.to_str().ok())
+        .map(|region| region.trim().to_string())
+        .filter(|region| !region.is_empty())
+}
+
+// Extract the expected region from an S3 error body, e.g. `<Region>eu-west-1</Region>`
+// or "the region 'us-east-1' is wrong; expecting 'eu-west-1'"
+fn parse_region_from_error(body: &str) -> Option<String> {
+    if let Some(start) = body.find("<Region>") {
+        let rest = &body[start + "<Region>".len()..];
+        if let Some(end) = rest.find("</Region>") {
+            return Some(rest[..end].trim().to_string()).filter(|region| !region.is_empty());
+        }
+    }
+
+    let rest = &body[body.find("expecting '")? + "expecting '".len()..];
+    let end = rest.find('\'')?;
+    Some(rest[..end].to_string()).filter(|region| !region.is_empty())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+    use crate::config::Config;
+    use testing::FakeS3;
+
+    #[tokio::test]
+    async fn bucket_region_is_read_from_the_header_or_the_error_body() {
+        let s3 = FakeS3::start().await;
+        let config = Config::for_tests(&s3.settings(&[]));
+        let storage = S3Storage::new(&config.storage).await.unwrap();
+        assert_eq!(storage.detect_bucket_region().await.unwrap(), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-amz-bucket-region", HeaderValue::from_static("eu-west-1"));
+        s3.fail_next_with(Method::HEAD, StatusCode::MOVED_PERMANENTLY, headers, "");
+        assert_eq!(storage.detect_bucket_region().await.unwrap().as_deref(), Some("eu-west-1"));
+
+        // Without the header, the listing's error body names the region
+        s3.fail_next(Method::HEAD, StatusCode::BAD_REQUEST);
+        s3.fail_next_with(Method::GET, StatusCode::BAD_REQUEST, HeaderMap::new(), "<Error><Region>ap-northeast-1</Region></Error>");
+        assert_eq!(storage.detect_bucket_region().await.unwrap().as_deref(), Some("ap-northeast-1"));
+    }
+
+    #[test]
+    fn region_is_parsed_from_error_bodies() {
+        let cases = [
+            ("<Error><Code>PermanentRedirect</Code><Region> eu-central-1 </Region></Error>", Some("eu-central-1")),
+            ("the region 'us-east-1' is wrong; expecting 'eu-west-2'", Some("eu-west-2")),
+            ("<Error><Region></Region></Error>", None),
+            ("expecting ''", None),
+            ("<Error><Code>AccessDenied</Code></Error>", None),
+            ("", None),
+        ];
+        for (body, region) in cases {
+            assert_eq!(parse_region_from_error(body).as_deref(), region, "{}", body);
+        }
+    }
+}
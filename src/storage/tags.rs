@@ -0,0 +1,103 @@
+use anyhow::{Result, anyhow};
+use regex::Regex;
+use std::sync::Arc;
+
+use crate::config::StorageConfig;
+
+// S3 allows at most 10 tags per object
+const MAX_TAGS: usize = 10;
+
+/// Tags attached to stored objects so bucket lifecycle rules can target them:
+/// fixed tags on every object plus tags from rules matching the object key.
+#[derive(Clone)]
+pub struct ObjectTagger {
+    fixed: Arc<Vec<(String, String)>>,
+    rules: Arc<Vec<(Regex, String, String)>>,
+}
+
+impl ObjectTagger {
+    pub fn new(config: &StorageConfig) -> Result<Self> {
+        for (key, value) in &config.object_tags {
+            validate_tag(key, value)?;
+        }
+
+        let rules = config.tag_rules
+            .iter()
+            .map(|rule| {
+                validate_tag(&rule.key, &rule.value)?;
+                let regex = Regex::new(&rule.pattern)
+                    .map_err(|e| anyhow!("Invalid S3 tag rule pattern '{}': {}", rule.pattern, e))?;
+                Ok((regex, rule.key.clone(), rule.value.clone()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut keys: Vec<&str> = config.object_tags.iter().map(|(key, _)| key.as_str()).collect();
+        keys.extend(config.tag_rules.iter().map(|rule| rule.key.as_str()));
+        keys.sort_unstable();
+        keys.dedup();
+        if keys.len() > MAX_TAGS {
+            return Err(anyhow!("S3 objects can carry at most {} tags, {} tag keys are configured", MAX_TAGS, keys.len()));
+        }
+
+        Ok(Self {
+            fixed: Arc::new(config.object_tags.clone()),
+            rules: Arc::new(rules),
+        })
+    }
+
+    /// Value of the `x-amz-tagging` header for `key`, or `None` when it gets no tags.
+    /// Matching rules override fixed tags with the same tag key.
+    pub fn tagging_for(&self, key: &str) -> Option<String> {
+        let mut tags: Vec<(&str, &str)> = Vec::new();
+        let matching = self.rules
+            .iter()
+            .filter(|(regex, _, _)| regex.is_match(key))
+            .map(|(_, tag, value)| (tag.as_str(), value.as_str()));
+
+        for (tag, value) in self.fixed.iter().map(|(tag, value)| (tag.as_str(), value.as_str())).chain(matching) {
+            match tags.iter_mut().find(|(existing, _)| *existing == tag) {
+                Some(entry) => entry.1 = value,
+                None => tags.push((tag, value)),
+            }
+        }
+
+        if tags.is_empty() {
+            return None;
+        }
+
+        Some(
+            tags.iter()
+                .map(|(tag, value)| format!("{}={}", percent_encode(tag), percent_encode(value)))
+                .collect::<Vec<_>>()
+                .join("&"),
+        )
+    }
+}
+
+// S3 tag keys are 1-128 and values 0-256 characters of letters, digits, spaces and + - = . _ : / @
+fn validate_tag(key: &str, value: &str) -> Result<()> {
+    let allowed = |c: char| c.is_alphanumeric() || " +-=._:/@".contains(c);
+
+    if key.is_empty() || key.chars().count() > 128 || !key.chars().all(allowed) {
+        return Err(anyhow!("Invalid S3 tag key '{}'", key));
+    }
+    if value.chars().count() > 256 || !value.chars().all(allowed) {
+        return Err(anyhow!("Invalid S3 tag value '{}' for key '{}'", value, key));
+    }
+    if key.starts_with("aws:") {
+        return Err(anyhow!("S3 tag key '{}' uses the reserved aws: prefix", key));
+    }
+    Ok(())
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
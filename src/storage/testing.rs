@@ -0,0 +1,163 @@
+//! In-process stand-in for an S3 endpoint with path-style buckets. Objects live in memory;
+//! failures can be injected per method to exercise retries and error handling.
+
+use axum::{
+    Router,
+    body::{Body, Bytes},
+    extract::State,
+    http::{HeaderMap, Method, StatusCode, Uri, header},
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+
+pub const BUCKET: &str = "pixiv";
+
+#[derive(Clone)]
+pub struct StoredEntry {
+    pub data: Bytes,
+    pub content_type: Option<String>,
+    pub content_hash: Option<String>, // The object's `x-amz-meta-content-hash`
+}
+
+#[derive(Default)]
+struct Inner {
+    objects: BTreeMap<String, StoredEntry>,
+    faults: HashMap<Method, VecDeque<(StatusCode, HeaderMap, String)>>,
+    requests: Vec<(Method, String)>,
+}
+
+#[derive(Clone)]
+pub struct FakeS3 {
+    endpoint: String,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl FakeS3 {
+    pub async fn start() -> Self {
+        let inner = Arc::new(Mutex::new(Inner::default()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+
+        let app = Router::new().fallback(handle).with_state(inner.clone());
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+
+        Self { endpoint, inner }
+    }
+
+    /// Settings pointing at this endpoint with S3-level retries off, then `settings`.
+    pub fn settings<'a>(&'a self, settings: &[(&'a str, &'a str)]) -> Vec<(&'a str, &'a str)> {
+        let mut all = vec![("S3_ENDPOINT", self.endpoint.as_str()), ("S3_BUCKET", BUCKET), ("S3_MAX_RETRIES", "0")];
+        all.extend_from_slice(settings);
+        all
+    }
+
+    /// Answer the next request with `method` with `status` instead of handling it.
+    pub fn fail_next(&self, method: Method, status: StatusCode) {
+        self.fail_next_with(method, status, HeaderMap::new(), "");
+    }
+
+    pub fn fail_next_with(&self, method: Method, status: StatusCode, headers: HeaderMap, body: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.faults.entry(method).or_default().push_back((status, headers, body.to_string()));
+    }
+
+    pub fn insert(&self, key: &str, data: impl Into<Bytes>, content_type: Option<&str>) {
+        let entry = StoredEntry { data: data.into(), content_type: content_type.map(str::to_string), content_hash: None };
+        self.inner.lock().unwrap().objects.insert(key.trim_start_matches('/').to_string(), entry);
+    }
+
+    /// The object as stored, i.e. still encrypted and/or compressed.
+    pub fn object(&self, key: &str) -> Option<StoredEntry> {
+        self.inner.lock().unwrap().objects.get(key.trim_start_matches('/')).cloned()
+    }
+
+    /// Number of requests with `method` made so far, against the bucket or any object.
+    pub fn count(&self, method: Method) -> usize {
+        self.inner.lock().unwrap().requests.iter().filter(|(m, _)| *m == method).count()
+    }
+}
+
+async fn handle(State(inner): State<Arc<Mutex<Inner>>>, method: Method, uri: Uri, headers: HeaderMap, body: Bytes) -> Response {
+    let mut inner = inner.lock().unwrap();
+    inner.requests.push((method.clone(), uri.path().to_string()));
+    if let Some((status, headers, body)) = inner.faults.get_mut(&method).and_then(VecDeque::pop_front) {
+        return (status, headers, body).into_response();
+    }
+
+    // Keys arrive percent-encoded, as S3 decodes them
+    let path = percent_encoding::percent_decode_str(uri.path().trim_start_matches('/')).decode_utf8_lossy();
+    let key = match path.split_once('/') {
+        Some((_, key)) if !key.is_empty() => key.to_string(),
+        // Bucket-level requests: HEAD and PUT succeed, GET lists
+        _ if method == Method::GET => return list(&inner.objects, uri.query().unwrap_or_default()),
+        _ => return StatusCode::OK.into_response(),
+    };
+
+    match method {
+        Method::PUT => {
+            let text = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+            let entry = StoredEntry { data: body, content_type: text(header::CONTENT_TYPE.as_str()), content_hash: text(super::CONTENT_HASH_HEADER) };
+            inner.objects.insert(key, entry);
+            StatusCode::OK.into_response()
+        },
+        Method::DELETE => {
+            inner.objects.remove(&key);
+            StatusCode::NO_CONTENT.into_response()
+        },
+        Method::GET | Method::HEAD => {
+            let Some(object) = inner.objects.get(&key) else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let mut response = Response::builder()
+                .header(header::LAST_MODIFIED, "Mon, 01 Jan 2024 00:00:00 GMT")
+                .header(header::ETAG, format!("\"{}\"", object.data.len()));
+            if let Some(content_type) = &object.content_type {
+                response = response.header(header::CONTENT_TYPE, content_type);
+            }
+            if let Some(content_hash) = &object.content_hash {
+                response = response.header(super::CONTENT_HASH_HEADER, content_hash);
+            }
+            let range = headers.get(header::RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("bytes="))
+                .and_then(|v| v.split_once('-'))
+                .and_then(|(start, end)| Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?)));
+            let body = match range {
+                Some((start, _)) if start >= object.data.len() => return StatusCode::RANGE_NOT_SATISFIABLE.into_response(),
+                Some((start, end)) => {
+                    response = response.status(StatusCode::PARTIAL_CONTENT);
+                    object.data.slice(start..object.data.len().min(end + 1))
+                },
+                None => object.data.clone(),
+            };
+            if method == Method::HEAD {
+                return response.header(header::CONTENT_LENGTH, body.len()).body(Body::empty()).unwrap();
+            }
+            response.body(Body::from(body)).unwrap()
+        },
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+fn list(objects: &BTreeMap<String, StoredEntry>, query: &str) -> Response {
+    let prefix = query.split('&')
+        .find_map(|pair| pair.strip_prefix("prefix="))
+        .map(|prefix| prefix.replace("%2F", "/"))
+        .unwrap_or_default();
+    let contents: String = objects
+        .iter()
+        .filter(|(key, _)| key.starts_with(&prefix))
+        .map(|(key, object)| format!(
+            "<Contents><Key>{}</Key><LastModified>2024-01-01T00:00:00.000Z</LastModified><ETag>\"{}\"</ETag><Size>{}</Size></Contents>",
+            key, object.data.len(), object.data.len()
+        ))
+        .collect();
+    let body = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult><Name>{}</Name><IsTruncated>false</IsTruncated>{}</ListBucketResult>", BUCKET, contents);
+    ([(header::CONTENT_TYPE, "application/xml")], body).into_response()
+}
@@ -0,0 +1,53 @@
+use anyhow::{Result, anyhow};
+use axum::{
+    extract::State,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use axum::body::Body;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use std::time::Duration;
+
+use crate::proxy::ProxyState;
+
+// Latency buckets in seconds, from a warm S3 read to a slow upstream download
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Install the global Prometheus recorder. Until this is called every metric is a no-op.
+pub fn install() -> Result<PrometheusHandle> {
+    PrometheusBuilder::new()
+        .set_buckets(LATENCY_BUCKETS)
+        .map_err(|e| anyhow!("Invalid metrics buckets: {}", e))?
+        .install_recorder()
+        .map_err(|e| anyhow!("Failed to install metrics recorder: {}", e))
+}
+
+/// Count an image request by the `X-Cache-Status` it was answered with (`none` for plain
+/// errors) and its status class, so hit ratios on dashboards match what clients see.
+pub fn record_request(response: &Response<Body>, elapsed: Duration) {
+    let cache_status = response.headers()
+        .get("X-Cache-Status")
+        .and_then(|value| value.to_str().ok())
+        .map_or_else(|| "none".to_string(), str::to_lowercase);
+    let status = match response.status().as_u16() {
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 if response.status() == StatusCode::NOT_FOUND => "404",
+        400..=499 => "4xx",
+        _ => "5xx",
+    };
+
+    metrics::counter!("proxy_requests_total", "cache_status" => cache_status.clone(), "status" => status).increment(1);
+    metrics::histogram!("proxy_request_duration_seconds", "cache_status" => cache_status).record(elapsed.as_secs_f64());
+}
+
+/// Metrics in the Prometheus text format; 404 when metrics are disabled.
+pub async fn metrics_handler(State(state): State<ProxyState>) -> Response {
+    match state.metrics.as_ref() {
+        Some(handle) => (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            handle.render(),
+        ).into_response(),
+        None => (StatusCode::NOT_FOUND, "Not found").into_response(),
+    }
+}
@@ -0,0 +1,318 @@
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use image::{ImageFormat, ImageReader};
+use std::io::Cursor;
+
+use crate::config::TransformConfig;
+
+/// Canonical output variants. Clients' `Accept` headers are reduced to one of
+/// these so the variant key space stays bounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Avif,
+    Webp,
+    Original,
+}
+
+impl Variant {
+    pub fn token(&self) -> &'static str {
+        match self {
+            Variant::Avif => "avif",
+            Variant::Webp => "webp",
+            Variant::Original => "orig",
+        }
+    }
+
+    fn from_token(token: &str) -> Option<Self> {
+        match token {
+            "avif" => Some(Variant::Avif),
+            "webp" => Some(Variant::Webp),
+            _ => None,
+        }
+    }
+
+    /// Whether `encode_variant` can produce this variant. There is no AVIF encoder in the
+    /// build yet, so AVIF is never negotiated and clients get their next accepted format.
+    pub fn is_encodable(&self) -> bool {
+        !matches!(self, Variant::Avif)
+    }
+
+    fn media_type(&self) -> Option<&'static str> {
+        match self {
+            Variant::Avif => Some("image/avif"),
+            Variant::Webp => Some("image/webp"),
+            Variant::Original => None,
+        }
+    }
+}
+
+/// Map an `Accept` header to the most preferred encodable variant the client explicitly accepts.
+///
+/// Only explicit media types count: `image/*` and `*/*` are sent by clients that
+/// cannot decode modern formats, so they never select a variant.
+pub fn normalize_accept(accept: Option<&str>, preferences: &[String]) -> Variant {
+    let Some(accept) = accept else {
+        return Variant::Original;
+    };
+
+    let accepted: Vec<String> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let media_type = parts.next()?.trim().to_lowercase();
+            let refused = parts.any(|param| {
+                let param = param.trim();
+                param.strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+            (!refused).then_some(media_type)
+        })
+        .collect();
+
+    preferences
+        .iter()
+        .filter_map(|token| Variant::from_token(token))
+        .filter(Variant::is_encodable)
+        .find(|variant| {
+            variant.media_type().is_some_and(|media_type| accepted.iter().any(|a| a == media_type))
+        })
+        .unwrap_or(Variant::Original)
+}
+
+/// Format families reported by `sniff`.
+pub const FORMAT_FAMILIES: &[&str] = &["riff", "isobmff", "png", "jpeg", "gif", "zip", "7z"];
+
+/// Identify the format family from magic bytes, along with the precise content type
+/// when the bytes settle it. A RIFF container need not be WebP and a PNG is only known
+/// to be APNG or plain PNG once its chunks up to the image data are present, so those
+/// stay ambiguous unless the container says otherwise.
+pub fn sniff(data: &[u8]) -> Option<(&'static str, Option<&'static str>)> {
+    if data.len() >= 12 && &data[..4] == b"RIFF" {
+        let precise = (&data[8..12] == b"WEBP").then_some("image/webp");
+        return Some(("riff", precise));
+    }
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        let precise = matches!(&data[8..12], b"avif" | b"avis").then_some("image/avif");
+        return Some(("isobmff", precise));
+    }
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some(("png", png_content_type(data)));
+    }
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Some(("jpeg", Some("image/jpeg")));
+    }
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        return Some(("gif", Some("image/gif")));
+    }
+    // Ugoira frames are served as ZIP archives
+    if data.starts_with(b"PK\x03\x04") {
+        return Some(("zip", Some("application/zip")));
+    }
+    if data.starts_with(b"7z\xBC\xAF\x27\x1C") {
+        return Some(("7z", Some("application/x-7z-compressed")));
+    }
+    None
+}
+
+// An APNG declares its animation in an `acTL` chunk, which must come before the first `IDAT`
+fn png_content_type(data: &[u8]) -> Option<&'static str> {
+    let mut offset = 8;
+    while let Some(header) = data.get(offset..offset + 8) {
+        match &header[4..] {
+            b"acTL" => return Some("image/apng"),
+            b"IDAT" => return Some("image/png"),
+            _ => {},
+        }
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        // Chunk length, type and CRC around the chunk data
+        offset = offset.checked_add(length)?.checked_add(12)?;
+    }
+    None
+}
+
+/// Storage key of a copy derived from the original at `path`, e.g. `<path>@webp`.
+pub fn derived_key(path: &str, token: &str) -> String {
+    format!("{}@{}", path, token)
+}
+
+/// A variant together with the WebP quality the client asked for with `?q=`, if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VariantRequest {
+    pub variant: Variant,
+    pub quality: Option<u8>,
+}
+
+impl VariantRequest {
+    /// Storage token of the variant, e.g. `webp`, or `webp-q50` for an explicit quality.
+    pub fn token(&self) -> String {
+        match self.quality {
+            Some(quality) => format!("{}-q{}", self.variant.token(), quality),
+            None => self.variant.token().to_string(),
+        }
+    }
+
+    /// Storage key of this variant of the original at `path`.
+    pub fn key(&self, path: &str) -> String {
+        derived_key(path, &self.token())
+    }
+}
+
+/// Whether the bytes are a still image we can re-encode without losing anything but quality.
+/// Animated formats (GIF/APNG) and archives are left alone.
+pub fn is_transcodable(data: &[u8], path: &str) -> bool {
+    let extension = path.rsplit('.').next().map(|ext| ext.to_lowercase());
+    if extension.as_deref() == Some("apng") {
+        return false;
+    }
+
+    matches!(image::guess_format(data), Ok(ImageFormat::Jpeg) | Ok(ImageFormat::Png))
+}
+
+/// Re-encode an image as lossy WebP, refusing images above the configured pixel limit.
+pub fn transcode_to_webp(data: &[u8], config: &TransformConfig) -> Result<Bytes> {
+    let reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| anyhow!("Failed to read image: {}", e))?;
+
+    let (width, height) = reader.into_dimensions()
+        .map_err(|e| anyhow!("Failed to read image dimensions: {}", e))?;
+    if u64::from(width) * u64::from(height) > config.max_pixels {
+        return Err(anyhow!("Image of {}x{} exceeds the transcode pixel limit", width, height));
+    }
+
+    let image = image::load_from_memory(data)
+        .map_err(|e| anyhow!("Failed to decode image: {}", e))?
+        .to_rgba8();
+
+    let encoded = webp::Encoder::from_rgba(image.as_raw(), width, height)
+        .encode(config.webp_quality);
+
+    Ok(Bytes::copy_from_slice(&encoded))
+}
+
+pub const THUMBNAIL_TOKEN: &str = "thumb";
+
+/// Storage key of the thumbnail sidecar for the original at `path`.
+pub fn thumbnail_key(path: &str) -> String {
+    derived_key(path, THUMBNAIL_TOKEN)
+}
+
+/// Downscale an image to fit the configured thumbnail size and encode it as WebP.
+pub fn thumbnail(data: &[u8], config: &TransformConfig) -> Result<Bytes> {
+    let reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| anyhow!("Failed to read image: {}", e))?;
+
+    let (width, height) = reader.into_dimensions()
+        .map_err(|e| anyhow!("Failed to read image dimensions: {}", e))?;
+    if u64::from(width) * u64::from(height) > config.max_pixels {
+        return Err(anyhow!("Image of {}x{} exceeds the transcode pixel limit", width, height));
+    }
+
+    let image = image::load_from_memory(data)
+        .map_err(|e| anyhow!("Failed to decode image: {}", e))?
+        .thumbnail(config.thumbnail_size, config.thumbnail_size)
+        .to_rgba8();
+
+    let encoded = webp::Encoder::from_rgba(image.as_raw(), image.width(), image.height())
+        .encode(config.thumbnail_quality);
+
+    Ok(Bytes::copy_from_slice(&encoded))
+}
+
+pub async fn thumbnail_blocking(data: Bytes, config: TransformConfig) -> Result<Bytes> {
+    tokio::task::spawn_blocking(move || thumbnail(&data, &config))
+        .await
+        .map_err(|e| anyhow!("Thumbnail task failed: {}", e))?
+}
+
+/// Encode the original image as the requested variant.
+pub fn encode_variant(data: &[u8], variant: Variant, config: &TransformConfig) -> Result<Bytes> {
+    match variant {
+        Variant::Webp => transcode_to_webp(data, config),
+        Variant::Avif => Err(anyhow!("AVIF encoding is not available")),
+        Variant::Original => Ok(Bytes::copy_from_slice(data)),
+    }
+}
+
+/// Encode off the async runtime, at the requested quality when one was given.
+pub async fn encode_variant_blocking(data: Bytes, requested: VariantRequest, mut config: TransformConfig) -> Result<Bytes> {
+    if let Some(quality) = requested.quality {
+        config.webp_quality = f32::from(quality);
+    }
+    tokio::task::spawn_blocking(move || encode_variant(&data, requested.variant, &config))
+        .await
+        .map_err(|e| anyhow!("Transcode task failed: {}", e))?
+}
+
+/// Run the CPU-heavy transcode off the async runtime.
+pub async fn transcode_to_webp_blocking(data: Bytes, config: TransformConfig) -> Result<Bytes> {
+    tokio::task::spawn_blocking(move || transcode_to_webp(&data, &config))
+        .await
+        .map_err(|e| anyhow!("Transcode task failed: {}", e))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_headers_map_to_variant_tokens() {
+        let preferences = ["avif".to_string(), "webp".to_string()];
+        let cases = [
+            (None, "orig"),
+            (Some(""), "orig"),
+            (Some("image/webp,image/apng,image/*,*/*;q=0.8"), "webp"),
+            (Some("IMAGE/WEBP"), "webp"),
+            (Some("image/avif;q=0.9, image/webp"), "webp"),
+            // Wildcards never select a variant
+            (Some("image/*,*/*"), "orig"),
+            (Some("image/png,image/jpeg"), "orig"),
+            // An explicit q=0 refuses the type
+            (Some("image/webp;q=0"), "orig"),
+            (Some("image/webp; q=0.0, image/png"), "orig"),
+            (Some("image/webp;q=0.1"), "webp"),
+        ];
+        for (accept, token) in cases {
+            assert_eq!(normalize_accept(accept, &preferences).token(), token, "{:?}", accept);
+        }
+
+        // Only configured formats are negotiated
+        assert_eq!(normalize_accept(Some("image/webp"), &[]), Variant::Original);
+        assert_eq!(normalize_accept(Some("image/webp"), &["jxl".to_string()]), Variant::Original);
+    }
+
+    // A PNG signature followed by one chunk header of the given type
+    fn png_with_chunk(chunk: &[u8; 4]) -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(chunk);
+        data
+    }
+
+    #[test]
+    fn ambiguous_magic_bytes_report_only_the_family() {
+        let cases: [(&[u8], _); 6] = [
+            (b"RIFF\0\0\0\0WAVEfmt ", Some(("riff", None))),
+            (b"RIFF\0\0\0\0WEBPVP8 ", Some(("riff", Some("image/webp")))),
+            (b"\0\0\0\x18ftypmp42\0\0\0\0", Some(("isobmff", None))),
+            (b"\0\0\0\x18ftypavif\0\0\0\0", Some(("isobmff", Some("image/avif")))),
+            // Too short to tell the container's contents
+            (b"RIFF\0\0\0\0", None),
+            (b"not an image", None),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(sniff(data), expected, "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn png_is_only_settled_by_its_chunks() {
+        // Signature only: APNG or plain PNG cannot be told apart yet
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n"), Some(("png", None)));
+        assert_eq!(sniff(&png_with_chunk(b"IHDR")), Some(("png", None)));
+        assert_eq!(sniff(&png_with_chunk(b"acTL")), Some(("png", Some("image/apng"))));
+        assert_eq!(sniff(&png_with_chunk(b"IDAT")), Some(("png", Some("image/png"))));
+    }
+}